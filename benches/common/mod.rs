@@ -0,0 +1,22 @@
+//!Stores of the benchmarks' own, removed once they ran
+
+/// A store under a unique name, alphanumeric as the `db_name` setting takes it, removed with
+/// its audit log once dropped. Bound before the server using it, so that it is dropped last.
+pub struct BenchDb(String);
+
+impl BenchDb {
+    pub fn new(prefix: &str) -> Self {
+        BenchDb(format!("{}{}", prefix, uuid::Uuid::new_v4().to_simple()))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Drop for BenchDb {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(format!("./{}", self.0));
+        let _ = std::fs::remove_dir_all(format!("./{}_audit", self.0));
+    }
+}
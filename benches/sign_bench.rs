@@ -1,87 +1,38 @@
-use crate::keygen_bench::key_gen;
 use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use futures::executor::block_on;
 use two_party_ecdsa::kms::ecdsa::two_party::MasterKey2;
 use pprof::criterion::{Output, PProfProfiler};
 use rand::rngs::mock::StepRng;
 use rand::Rng;
-use rocket::{http::ContentType, http::Status, local::blocking::Client};
+use rocket::local::{asynchronous, blocking::Client};
 use std::collections::HashMap;
-use gotham_engine::sign::SignSecondMsgRequest;
-use two_party_ecdsa::{party_one, BigInt};
+use std::sync::Arc;
+use criterion::Throughput;
+use tokio::runtime::Handle;
+use two_party_ecdsa::BigInt;
+use public_server_lib::client::GothamClient;
 use public_server_lib::server::*;
 
-
-pub fn sign(
-    client: &Client,
-    message: &BigInt,
-    mk: &MasterKey2,
-    x_pos: &BigInt,
-    y_pos: &BigInt,
-    id: &str,
-) -> party_one::SignatureRecid {
-    let (eph_key_gen_first_message_party_two, eph_comm_witness, eph_ec_key_pair_party2) =
-        MasterKey2::sign_first_message();
-
-    let body = serde_json::to_string(&eph_key_gen_first_message_party_two).unwrap();
-
-    let response = client
-        .post(format!("/ecdsa/sign/{}/first", id))
-        .header(ContentType::JSON)
-        .body(body)
-        .dispatch();
-
-    assert_eq!(response.status(), Status::Ok);
-
-    let sign_party_one_first_message: party_one::EphKeyGenFirstMsg =
-        serde_json::from_str(&response.into_string().unwrap()).unwrap();
-
-    let child_party_two_master_key = mk.get_child(vec![x_pos.clone(), y_pos.clone()]);
-
-    let party_two_sign_message = child_party_two_master_key.sign_second_message(
-        &eph_ec_key_pair_party2,
-        eph_comm_witness,
-        &sign_party_one_first_message,
-        &message,
-    );
-
-    let request: SignSecondMsgRequest = SignSecondMsgRequest {
-        message: message.clone(),
-        party_two_sign_message,
-        x_pos_child_key: x_pos.clone(),
-        y_pos_child_key: y_pos.clone(),
-    };
-
-    let body = serde_json::to_string(&request).unwrap();
-
-    let response = client
-        .post(format!("/ecdsa/sign/{}/second", id))
-        .header(ContentType::JSON)
-        .body(body)
-        .dispatch();
-
-    assert_eq!(response.status(), Status::Ok);
-
-    let signature: party_one::SignatureRecid =
-        serde_json::from_str(&response.into_string().unwrap()).unwrap();
-
-    signature
-}
+mod common;
+use common::BenchDb;
 
 pub fn criterion_benchmark(c: &mut Criterion) {
+    let db = BenchDb::new("SignBench");
     let settings = HashMap::<String, String>::from([
         ("db".to_string(), "local".to_string()),
-        ("db_name".to_string(), "KeyGenAndSign".to_string()),
+        ("db_name".to_string(), db.name().to_string()),
     ]);
 
-    let server = get_server(settings);
+    let server = get_server(settings).expect("valid settings");
     let client = Client::tracked(server).expect("valid rocket instance");
 
-    let (id, mk) = crate::key_gen(&client);
+    let gotham_client = GothamClient::new(client);
+    let (id, mk) = block_on(gotham_client.key_gen()).expect("keygen");
 
     c.bench_with_input(
         BenchmarkId::new("sign_benchmark", 1),
-        &(client, id, mk),
-        |b, (client, id, mk)| {
+        &(gotham_client, id, mk),
+        |b, (gotham_client, id, mk)| {
             b.iter(|| {
                 let x_pos = BigInt::from(1);
                 let y_pos = BigInt::from(2);
@@ -90,17 +41,78 @@ pub fn criterion_benchmark(c: &mut Criterion) {
                 rng.fill(&mut msg_buf);
                 let msg: BigInt = BigInt::from(&msg_buf[..]);
 
-                sign(&client, &msg, mk, &x_pos, &y_pos, id);
+                block_on(gotham_client.sign(id, mk, &msg, &x_pos, &y_pos)).expect("signing");
             });
         },
     );
 }
 
+const PARALLEL_CLIENTS: [usize; 4] = [1, 2, 4, 8];
+
+/// Signing throughput with several clients, each holding its own key, signing concurrently
+pub fn parallel_sign_benchmark(c: &mut Criterion) {
+    let db = BenchDb::new("ParallelSign");
+    let settings = HashMap::<String, String>::from([
+        ("db".to_string(), "local".to_string()),
+        ("db_name".to_string(), db.name().to_string()),
+    ]);
+
+    // Keys are generated over the blocking client, whose server is shut down before the
+    // asynchronous client reopens the same store
+    let keys: Vec<(String, MasterKey2)> = {
+        let server = get_server(settings.clone()).expect("valid settings");
+        let client = Client::tracked(server).expect("valid rocket instance");
+        let gotham_client = GothamClient::new(client);
+        (0..PARALLEL_CLIENTS[PARALLEL_CLIENTS.len() - 1])
+            .map(|_| block_on(gotham_client.key_gen()).expect("keygen"))
+            .collect()
+    };
+    let keys = Arc::new(keys);
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let gotham_client = Arc::new(GothamClient::new(
+        runtime
+            .block_on(asynchronous::Client::untracked(get_server(settings).expect("valid settings")))
+            .expect("valid rocket instance"),
+    ));
+
+    let mut group = c.benchmark_group("parallel_sign_benchmark");
+    for clients in PARALLEL_CLIENTS {
+        group.throughput(Throughput::Elements(clients as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(clients), &clients, |b, &clients| {
+            b.iter(|| {
+                runtime.block_on(async {
+                    let handles: Vec<_> = (0..clients)
+                        .map(|i| {
+                            let gotham_client = gotham_client.clone();
+                            let keys = keys.clone();
+                            // The client's futures are not Send, each signer drives its own
+                            tokio::task::spawn_blocking(move || {
+                                let (id, mk) = &keys[i];
+                                let msg = BigInt::from(1234u32);
+                                let x_pos = BigInt::from(1);
+                                let y_pos = BigInt::from(2);
+                                Handle::current()
+                                    .block_on(gotham_client.sign(id, mk, &msg, &x_pos, &y_pos))
+                                    .expect("signing")
+                            })
+                        })
+                        .collect();
+                    for handle in handles {
+                        handle.await.unwrap();
+                    }
+                })
+            });
+        });
+    }
+    group.finish();
+}
+
 criterion_group! {
     name = benches;
     config = Criterion::default()
         .with_profiler(PProfProfiler::new(10, Output::Flamegraph(None)));
-    targets = criterion_benchmark
+    targets = criterion_benchmark, parallel_sign_benchmark
 }
 
 criterion_main!(benches);
@@ -0,0 +1,63 @@
+//!Authentication of the operator routes under /admin
+
+use std::collections::HashMap;
+use std::string::String;
+
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use rocket::{async_trait, Request};
+
+pub const ADMIN_TOKEN_HEADER: &str = "X-Admin-Token";
+
+/// Actor of the audit records of admin routes.
+pub const ADMIN_ACTOR: &str = "admin";
+
+/// The shared secret of the admin routes, from the `admin_token` setting.
+/// Admin routes refuse every request when it is empty.
+pub struct AdminAuth {
+    token: Option<String>,
+}
+
+impl AdminAuth {
+    pub fn new(settings: &HashMap<String, String>) -> Self {
+        let token = settings
+            .get("admin_token")
+            .map(|token| token.trim().to_string())
+            .filter(|token| !token.is_empty());
+        if token.is_none() {
+            log::info!("No admin_token configured, admin routes are disabled");
+        }
+        AdminAuth { token }
+    }
+
+    fn verify(&self, presented: Option<&str>) -> Result<(), Status> {
+        let token = self.token.as_ref().ok_or(Status::Forbidden)?;
+        match presented {
+            Some(presented) if constant_time_eq(token.as_bytes(), presented.as_bytes()) => Ok(()),
+            _ => Err(Status::Unauthorized),
+        }
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Request guard of the admin routes.
+pub struct AdminToken;
+
+#[async_trait]
+impl<'r> FromRequest<'r> for AdminToken {
+    type Error = String;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let admin = req.rocket().state::<AdminAuth>().expect("AdminAuth is not managed");
+        match admin.verify(req.headers().get_one(ADMIN_TOKEN_HEADER)) {
+            Ok(()) => Outcome::Success(AdminToken),
+            Err(status) => {
+                log::warn!("Rejected admin request to {}", req.uri());
+                Outcome::Failure((status, "Invalid admin token".to_string()))
+            }
+        }
+    }
+}
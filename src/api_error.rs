@@ -0,0 +1,275 @@
+//!JSON error envelope of every failed request, and the classification of engine step failures
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::string::String;
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Header, Status};
+use rocket::response::{self, Responder};
+use rocket::serde::json::{json, Json, Value};
+use rocket::{async_trait, Data, Request, Response};
+
+use crate::error::StorageError;
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Stable error codes of the error envelope. Clients should match on these, never on messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    BadRequest,
+    MalformedRequest,
+    Unauthorized,
+    Forbidden,
+    PolicyViolation,
+    KeyDeactivated,
+    KeyReplaced,
+    RouteNotFound,
+    UnknownId,
+    InvalidId,
+    StepOutOfOrder,
+    ProofFailed,
+    ProtocolError,
+    StorageError,
+    Unavailable,
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::BadRequest => "bad_request",
+            ErrorCode::MalformedRequest => "malformed_request",
+            ErrorCode::Unauthorized => "unauthorized",
+            ErrorCode::Forbidden => "forbidden",
+            ErrorCode::PolicyViolation => "policy_violation",
+            ErrorCode::KeyDeactivated => "key_deactivated",
+            ErrorCode::KeyReplaced => "key_replaced",
+            ErrorCode::RouteNotFound => "route_not_found",
+            ErrorCode::UnknownId => "unknown_id",
+            ErrorCode::InvalidId => "invalid_id",
+            ErrorCode::StepOutOfOrder => "step_out_of_order",
+            ErrorCode::ProofFailed => "proof_failed",
+            ErrorCode::ProtocolError => "protocol_error",
+            ErrorCode::StorageError => "storage_error",
+            ErrorCode::Unavailable => "unavailable",
+            ErrorCode::Internal => "internal_error",
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        match self {
+            ErrorCode::BadRequest
+            | ErrorCode::MalformedRequest
+            | ErrorCode::InvalidId
+            | ErrorCode::ProtocolError => Status::BadRequest,
+            ErrorCode::Unauthorized => Status::Unauthorized,
+            ErrorCode::Forbidden
+            | ErrorCode::PolicyViolation
+            | ErrorCode::KeyDeactivated
+            | ErrorCode::KeyReplaced => Status::Forbidden,
+            ErrorCode::RouteNotFound | ErrorCode::UnknownId => Status::NotFound,
+            ErrorCode::StepOutOfOrder => Status::Conflict,
+            ErrorCode::ProofFailed => Status::UnprocessableEntity,
+            ErrorCode::StorageError | ErrorCode::Internal => Status::InternalServerError,
+            ErrorCode::Unavailable => Status::ServiceUnavailable,
+        }
+    }
+}
+
+impl From<&StorageError> for ErrorCode {
+    fn from(err: &StorageError) -> Self {
+        match err {
+            StorageError::InvalidId(_) => ErrorCode::InvalidId,
+            _ => ErrorCode::StorageError,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    pub message: String,
+    /// The code's own status, unless answering a status no code stands for.
+    pub status: Status,
+}
+
+impl ApiError {
+    pub fn new<M: Into<String>>(code: ErrorCode, message: M) -> Self {
+        ApiError {
+            code,
+            message: message.into(),
+            status: code.status(),
+        }
+    }
+
+    pub fn with_status(mut self, status: Status) -> Self {
+        self.status = status;
+        self
+    }
+
+    /// `{"error": {"code", "message", "request_id"}}`
+    pub fn envelope(&self, request_id: &str) -> Value {
+        json!({
+            "error": {
+                "code": self.code.as_str(),
+                "message": self.message,
+                "request_id": request_id,
+            }
+        })
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let envelope = self.envelope(&RequestId::of(req).0);
+        Response::build_from(Json(envelope).respond_to(req)?)
+            .status(self.status)
+            .ok()
+    }
+}
+
+/// Identifier of a request, taken from its `X-Request-Id` header when the caller sent a
+/// usable one, generated otherwise. Echoed on every response.
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    pub fn of<'r>(req: &'r Request<'_>) -> &'r RequestId {
+        req.local_cache(|| {
+            let id = req
+                .headers()
+                .get_one(REQUEST_ID_HEADER)
+                .filter(|id| {
+                    !id.is_empty()
+                        && id.len() <= MAX_REQUEST_ID_LEN
+                        && id.chars().all(|e| e.is_ascii_graphic())
+                })
+                .map(str::to_string)
+                .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+            RequestId(id)
+        })
+    }
+}
+
+tokio::task_local! {
+    /// The Db calls of the engine step running on the current task, if any.
+    static LOOKUPS: RefCell<Lookups>;
+}
+
+/// What the Db calls an engine step made found, since the engine only reports failures as text.
+#[derive(Debug, Default, Clone)]
+pub struct Lookups {
+    hits: usize,
+    misses: Vec<String>,
+    failure: Option<ApiError>,
+    share_refused: bool,
+}
+
+impl Lookups {
+    fn record<F: FnOnce(&mut Lookups)>(update: F) {
+        // Calls made outside of an engine step, by our own routes, are not tracked
+        let _ = LOOKUPS.try_with(|lookups| update(&mut lookups.borrow_mut()));
+    }
+
+    pub fn record_read<T>(table: &str, read: &Result<Option<T>, StorageError>) {
+        match read {
+            Ok(Some(_)) => Lookups::record(|e| e.hits += 1),
+            Ok(None) => Lookups::record(|e| e.misses.push(table.to_string())),
+            Err(err) => Lookups::record_failure(err),
+        }
+    }
+
+    pub fn record_failure(err: &StorageError) {
+        let code = ErrorCode::from(err);
+        let message = match code {
+            ErrorCode::InvalidId => err.to_string(),
+            _ => "Storage backend failure".to_string(),
+        };
+        Lookups::record(|e| e.failure = Some(ApiError::new(code, message)));
+    }
+
+    /// The share policy refused a keygen because the customer already holds a share.
+    pub fn record_share_refusal() {
+        Lookups::record(|e| e.share_refused = true)
+    }
+}
+
+/// Runs an engine step, classifying its failure from the Db calls it made.
+pub async fn classified<T, F>(id: Option<&str>, step: F) -> Result<T, ApiError>
+where
+    F: Future<Output = Result<T, String>>,
+{
+    let (result, lookups) = LOOKUPS
+        .scope(RefCell::new(Lookups::default()), async {
+            let result = step.await;
+            (result, LOOKUPS.with(|lookups| lookups.take()))
+        })
+        .await;
+    result.map_err(|message| {
+        let error = classify(id, lookups, message);
+        log::warn!("Engine step on {:?} failed: {} {}", id, error.code.as_str(), error.message);
+        error
+    })
+}
+
+/// Runs storage work on the blocking pool rather than an async worker.
+pub(crate) async fn blocking<T, F>(work: F) -> Result<T, ApiError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    tokio::task::spawn_blocking(work).await.map_err(|e| {
+        log::error!("Storage task failed: {}", e);
+        ApiError::new(ErrorCode::Internal, "Internal server error")
+    })
+}
+
+/// Classifies a failed engine step from the Db calls it made: a storage failure is reported
+/// as is, nothing stored under the id is an unknown id, a missing record of an otherwise known
+/// id is a step out of order, and a failure once every record was found is a failed proof,
+/// verifying the counterparty's messages being all that is left to fail.
+pub fn classify(id: Option<&str>, lookups: Lookups, message: String) -> ApiError {
+    if let Some(failure) = lookups.failure {
+        return failure;
+    }
+    if lookups.share_refused {
+        return ApiError::new(
+            ErrorCode::Forbidden,
+            "The share policy refuses a new keygen while the customer holds an active share",
+        );
+    }
+    let id = id.unwrap_or_default();
+    match (lookups.hits, lookups.misses.first()) {
+        (0, Some(_)) => ApiError::new(ErrorCode::UnknownId, format!("Unknown id '{}'", id)),
+        (_, Some(table)) => ApiError::new(
+            ErrorCode::StepOutOfOrder,
+            format!("No {} for id '{}', a previous protocol step is missing", table, id),
+        ),
+        (0, None) => ApiError::new(ErrorCode::ProtocolError, message),
+        (_, None) => ApiError::new(ErrorCode::ProofFailed, message),
+    }
+}
+
+/// Assigns request ids and echoes them on every response.
+pub struct RequestIdFairing;
+
+#[async_trait]
+impl Fairing for RequestIdFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request ids",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        RequestId::of(req);
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        res.set_header(Header::new(REQUEST_ID_HEADER, RequestId::of(req).0.clone()));
+    }
+}
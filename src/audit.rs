@@ -0,0 +1,268 @@
+//!Append-only, hash-chained audit log of MPC operations and administrative actions
+
+use std::io::{self, BufRead};
+use std::path::Path;
+use std::string::String;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use rocket::http::{ContentType, Status};
+use rocket::serde::json::{json, Value};
+use rocket::{get, State};
+use rocksdb::checkpoint::Checkpoint;
+use rocksdb::{IteratorMode, Options, WriteOptions, DB};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::admin::AdminToken;
+use crate::api_error::{blocking, ApiError, ErrorCode};
+use crate::error::StorageError;
+use crate::public_gotham::PublicGotham;
+
+/// `prev_hash` of the first record.
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+const DEFAULT_EXPORT_LIMIT: usize = 1000;
+const MAX_EXPORT_LIMIT: usize = 10_000;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEvent {
+    pub at: DateTime<Utc>,
+    /// The customer id of the caller, or `admin` for the operator routes.
+    pub actor: String,
+    pub action: String,
+    pub customer_id: String,
+    pub id: Option<String>,
+    /// Action specific details. Never key material.
+    pub details: Value,
+}
+
+impl AuditEvent {
+    pub fn new(actor: &str, action: &str, customer_id: &str, id: Option<&str>, details: Value) -> Self {
+        AuditEvent {
+            at: Utc::now(),
+            actor: actor.to_string(),
+            action: action.to_string(),
+            customer_id: customer_id.to_string(),
+            id: id.map(str::to_string),
+            details,
+        }
+    }
+}
+
+/// An event as appended to the log. `hash` covers the sequence number, the previous record's
+/// hash and the event, so that altering, removing or reordering records breaks the chain.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub seq: u64,
+    pub prev_hash: String,
+    pub event: AuditEvent,
+    pub hash: String,
+}
+
+impl AuditRecord {
+    fn chain(seq: u64, prev_hash: String, event: AuditEvent) -> Result<Self, serde_json::Error> {
+        let hash = record_hash(seq, &prev_hash, &event)?;
+        Ok(AuditRecord {
+            seq,
+            prev_hash,
+            event,
+            hash,
+        })
+    }
+}
+
+fn record_hash(seq: u64, prev_hash: &str, event: &AuditEvent) -> Result<String, serde_json::Error> {
+    let mut hasher = Sha256::new();
+    hasher.update(seq.to_be_bytes());
+    hasher.update(prev_hash.as_bytes());
+    hasher.update(serde_json::to_vec(event)?);
+    Ok(hex::encode(hasher.finalize()))
+}
+
+#[derive(Debug, Error)]
+pub enum AuditError {
+    #[error("Record {0} is not valid JSON: {1}")]
+    Malformed(usize, serde_json::Error),
+    #[error("Expected record {expected}, found {found}")]
+    Gap { expected: u64, found: u64 },
+    #[error("Record {0} does not follow the hash of the previous record")]
+    BrokenLink(u64),
+    #[error("Record {0} does not match its hash")]
+    Tampered(u64),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// The verified range of a chain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChainSummary {
+    pub records: usize,
+    pub first_seq: Option<u64>,
+    /// Hash of the last record, `GENESIS_HASH` for an empty chain. Publishing it elsewhere
+    /// lets a later verification detect a truncated log.
+    pub head_hash: String,
+}
+
+/// Checks that consecutive records link up and match their hashes. An export starting past the
+/// first record is verified from its first record on.
+pub fn verify_chain<I>(records: I) -> Result<ChainSummary, AuditError>
+where
+    I: IntoIterator<Item = Result<AuditRecord, AuditError>>,
+{
+    let mut summary = ChainSummary {
+        records: 0,
+        first_seq: None,
+        head_hash: GENESIS_HASH.to_string(),
+    };
+    let mut expected_seq = None;
+    for record in records {
+        let record = record?;
+        match expected_seq {
+            Some(expected) if record.seq != expected => {
+                return Err(AuditError::Gap {
+                    expected,
+                    found: record.seq,
+                })
+            }
+            Some(_) if record.prev_hash != summary.head_hash => {
+                return Err(AuditError::BrokenLink(record.seq))
+            }
+            None if record.seq == 0 && record.prev_hash != GENESIS_HASH => {
+                return Err(AuditError::BrokenLink(record.seq))
+            }
+            _ => {}
+        }
+        let hash = record_hash(record.seq, &record.prev_hash, &record.event)
+            .map_err(|e| AuditError::Malformed(summary.records, e))?;
+        if hash != record.hash {
+            return Err(AuditError::Tampered(record.seq));
+        }
+        summary.first_seq.get_or_insert(record.seq);
+        summary.records += 1;
+        summary.head_hash = hash;
+        expected_seq = Some(record.seq + 1);
+    }
+    Ok(summary)
+}
+
+/// Reads an export, one JSON record per line.
+pub fn read_export<R: BufRead>(reader: R) -> impl Iterator<Item = Result<AuditRecord, AuditError>> {
+    reader
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| line.as_ref().map_or(true, |line| !line.trim().is_empty()))
+        .map(|(n, line)| serde_json::from_str(&line?).map_err(|e| AuditError::Malformed(n + 1, e)))
+}
+
+/// The log lives in a RocksDB database of its own, keyed by big endian sequence number, so
+/// that it is kept whatever the backend of the MPC records. It only ever appends.
+pub struct AuditLog {
+    db: DB,
+    /// Sequence number and hash the next record chains to.
+    head: Mutex<(u64, String)>,
+}
+
+impl AuditLog {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, StorageError> {
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        opts.set_paranoid_checks(true);
+        let db = DB::open(&opts, path)?;
+        let head = match db.iterator(IteratorMode::End).next() {
+            Some(item) => {
+                let (_, raw) = item?;
+                let last: AuditRecord = serde_json::from_slice(&raw)?;
+                (last.seq + 1, last.hash)
+            }
+            None => (0, GENESIS_HASH.to_string()),
+        };
+        Ok(AuditLog {
+            db,
+            head: Mutex::new(head),
+        })
+    }
+
+    /// Opens the log of a stopped server, for export.
+    pub fn open_read_only<P: AsRef<Path>>(path: P) -> Result<Self, StorageError> {
+        let db = DB::open_for_read_only(&Options::default(), path, false)?;
+        Ok(AuditLog {
+            db,
+            head: Mutex::new((0, GENESIS_HASH.to_string())),
+        })
+    }
+
+    /// Appends the event, synced to disk before returning.
+    pub fn append(&self, event: AuditEvent) -> Result<AuditRecord, StorageError> {
+        let mut head = self.head.lock().unwrap_or_else(|e| e.into_inner());
+        let record = AuditRecord::chain(head.0, head.1.clone(), event)?;
+        let mut opts = WriteOptions::default();
+        opts.set_sync(true);
+        self.db
+            .put_opt(record.seq.to_be_bytes(), serde_json::to_vec(&record)?, &opts)?;
+        *head = (record.seq + 1, record.hash.clone());
+        Ok(record)
+    }
+
+    /// Writes a consistent copy of the log to `path`.
+    pub fn checkpoint<P: AsRef<Path>>(&self, path: P) -> Result<(), StorageError> {
+        Checkpoint::new(&self.db)?.create_checkpoint(path)?;
+        Ok(())
+    }
+
+    /// Up to `limit` records from sequence number `from` on.
+    pub fn records(&self, from: u64, limit: usize) -> Result<Vec<AuditRecord>, StorageError> {
+        let start = from.to_be_bytes();
+        self.db
+            .iterator(IteratorMode::From(&start, rocksdb::Direction::Forward))
+            .take(limit)
+            .map(|item| Ok(serde_json::from_slice(&item?.1)?))
+            .collect()
+    }
+}
+
+/// The one rule of every audited MPC step: its outcome, success or failure, is only answered
+/// once recorded. When the log cannot be appended to, the caller gets that failure instead.
+pub async fn audited<T>(
+    gotham: &Arc<PublicGotham>,
+    mut event: AuditEvent,
+    outcome: Result<T, ApiError>,
+) -> Result<T, ApiError> {
+    match &outcome {
+        Ok(_) => event.details["status"] = json!(Status::Ok.code),
+        Err(error) => {
+            event.details["status"] = json!(error.status.code);
+            event.details["error"] = json!(error.code.as_str());
+        }
+    }
+    let gotham = gotham.clone();
+    match blocking(move || gotham.audit(event)).await? {
+        Ok(()) => outcome,
+        Err(e) => {
+            log::error!("Unable to append to the audit log: {}", e);
+            Err(ApiError::new(ErrorCode::from(&e), "Audit log unavailable"))
+        }
+    }
+}
+
+/// Records from sequence number `from` on, one JSON record per line, as read by `gotham_audit verify`.
+#[get("/admin/audit?<from>&<limit>")]
+pub fn export(
+    _admin: AdminToken,
+    gotham: &State<Arc<PublicGotham>>,
+    from: Option<u64>,
+    limit: Option<usize>,
+) -> Result<(ContentType, String), ApiError> {
+    let limit = limit.unwrap_or(DEFAULT_EXPORT_LIMIT).clamp(1, MAX_EXPORT_LIMIT);
+    let records = gotham.audit_records(from.unwrap_or(0), limit).map_err(|e| {
+        log::error!("Audit log export failed: {}", e);
+        ApiError::new(ErrorCode::from(&e), "Storage backend failure")
+    })?;
+    let mut lines = String::new();
+    for record in records {
+        lines.push_str(&serde_json::to_string(&record).expect("audit records serialize"));
+        lines.push('\n');
+    }
+    Ok((ContentType::new("application", "x-ndjson"), lines))
+}
@@ -0,0 +1,223 @@
+//!JWT authentication of MPC routes against a Cognito (or any JWKS based) issuer
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::string::String;
+
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use rocket::fairing::{AdHoc, Fairing};
+use rocket::http::Status;
+use rocket::request::{self, FromRequest};
+use rocket::route::{Handler, Outcome, Route};
+use rocket::{async_trait, Data, Request};
+use serde::Deserialize;
+use thiserror::Error;
+
+/// Customer id of every request while authentication is in passthrough mode.
+pub const PASSTHROUGH_CUSTOMER_ID: &str = "pass_through_guest_user";
+
+/// Signature algorithms accepted when the `jwt_algorithms` setting is empty.
+const DEFAULT_ALGORITHMS: &[Algorithm] = &[Algorithm::RS256];
+
+#[derive(Debug, Error)]
+pub enum AuthError {
+    #[error("Missing bearer token")]
+    MissingToken,
+    #[error("Token signed by unknown key")]
+    UnknownKey,
+    #[error("Token signed with a disallowed algorithm {0:?}")]
+    DisallowedAlgorithm(Algorithm),
+    #[error("Invalid token: {0}")]
+    InvalidToken(#[from] jsonwebtoken::errors::Error),
+    #[error("Unable to load the JWKS: {0}")]
+    Jwks(String),
+    #[error("Invalid jwt_algorithms setting: {0}")]
+    InvalidAlgorithm(String),
+}
+
+#[derive(Debug, Deserialize)]
+struct Claims {
+    sub: String,
+}
+
+/// Token validation settings. With `region`, `pool_id`, `issuer` and `audience` all empty
+/// the server runs in passthrough mode and accepts unauthenticated requests.
+pub struct Auth {
+    issuer: String,
+    audience: String,
+    /// Only these may sign tokens, whatever their header claims; a key publishing its `alg`
+    /// further restricts its tokens to that one.
+    algorithms: Vec<Algorithm>,
+    jwks: Option<JwkSet>,
+}
+
+impl Auth {
+    /// Reads the JWKS from `jwks_file`, or fetches it from the issuer.
+    pub async fn load(settings: &HashMap<String, String>) -> Result<Self, AuthError> {
+        let setting = |name: &str| settings.get(name).cloned().unwrap_or_default();
+        let (region, pool_id) = (setting("region"), setting("pool_id"));
+        let mut issuer = setting("issuer");
+        let audience = setting("audience");
+        let algorithms = match setting("jwt_algorithms").trim() {
+            "" => DEFAULT_ALGORITHMS.to_vec(),
+            algorithms => algorithms
+                .split(',')
+                .map(|algorithm| {
+                    Algorithm::from_str(algorithm.trim())
+                        .map_err(|_| AuthError::InvalidAlgorithm(algorithm.trim().to_string()))
+                })
+                .collect::<Result<_, _>>()?,
+        };
+
+        if region.is_empty() && pool_id.is_empty() && issuer.is_empty() && audience.is_empty() {
+            log::warn!("Auth settings are empty, requests are served in PASSTHROUGH mode");
+            return Ok(Auth {
+                issuer,
+                audience,
+                algorithms,
+                jwks: None,
+            });
+        }
+        if issuer.is_empty() {
+            issuer = format!("https://cognito-idp.{}.amazonaws.com/{}", region, pool_id);
+        }
+
+        let jwks_json = match settings.get("jwks_file").filter(|path| !path.is_empty()) {
+            Some(path) => std::fs::read_to_string(path).map_err(|e| AuthError::Jwks(format!("{}: {}", path, e)))?,
+            None => fetch_jwks(&format!("{}/.well-known/jwks.json", issuer))
+                .await
+                .map_err(|e| AuthError::Jwks(e.to_string()))?,
+        };
+        let jwks: JwkSet = serde_json::from_str(&jwks_json).map_err(|e| AuthError::Jwks(e.to_string()))?;
+
+        Ok(Auth {
+            issuer,
+            audience,
+            algorithms,
+            jwks: Some(jwks),
+        })
+    }
+
+    pub fn is_passthrough(&self) -> bool {
+        self.jwks.is_none()
+    }
+
+    /// Validates the `Authorization` header and returns the customer id it identifies.
+    pub fn authenticate(&self, authorization: Option<&str>) -> Result<String, AuthError> {
+        let jwks = match &self.jwks {
+            Some(jwks) => jwks,
+            // Without verification a token proves nothing, every request is the same guest
+            None => return Ok(PASSTHROUGH_CUSTOMER_ID.to_string()),
+        };
+        let token = authorization
+            .and_then(|header| header.strip_prefix("Bearer "))
+            .ok_or(AuthError::MissingToken)?;
+
+        let header = decode_header(token)?;
+        let jwk = header
+            .kid
+            .as_deref()
+            .and_then(|kid| jwks.find(kid))
+            .ok_or(AuthError::UnknownKey)?;
+        // Both serialize to their JWA name, e.g. "RS256"
+        let published = jwk
+            .common
+            .algorithm
+            .as_ref()
+            .and_then(|algorithm| serde_json::to_value(algorithm).ok());
+        if !self.algorithms.contains(&header.alg)
+            || published.map_or(false, |published| Some(published) != serde_json::to_value(header.alg).ok())
+        {
+            return Err(AuthError::DisallowedAlgorithm(header.alg));
+        }
+        let mut validation = Validation::new(header.alg);
+        validation.algorithms = self.algorithms.clone();
+        validation.set_issuer(&[&self.issuer]);
+        if !self.audience.is_empty() {
+            validation.set_audience(&[&self.audience]);
+        }
+
+        let token_data = decode::<Claims>(token, &DecodingKey::from_jwk(jwk)?, &validation)?;
+        Ok(token_data.claims.sub)
+    }
+}
+
+async fn fetch_jwks(url: &str) -> Result<String, reqwest::Error> {
+    reqwest::get(url).await?.error_for_status()?.text().await
+}
+
+/// Loads the token validation settings as the server ignites, failing the launch when the
+/// JWKS cannot be loaded.
+pub fn auth_loader(settings: HashMap<String, String>) -> impl Fairing {
+    AdHoc::try_on_ignite("JWT authentication", move |rocket| async move {
+        match Auth::load(&settings).await {
+            Ok(auth) => Ok(rocket.manage(auth)),
+            Err(e) => {
+                log::error!("{}", e);
+                Err(rocket)
+            }
+        }
+    })
+}
+
+fn authenticate<'r>(req: &'r Request<'_>) -> &'r Result<String, String> {
+    req.local_cache(|| {
+        let auth = req.rocket().state::<Auth>().expect("Auth is not managed");
+        auth.authenticate(req.headers().get_one("Authorization"))
+            .map_err(|e| {
+                log::warn!("Rejected request to {}: {}", req.uri(), e);
+                e.to_string()
+            })
+    })
+}
+
+/// The authenticated customer of a request, if any.
+pub fn customer_of<'r>(req: &'r Request<'_>) -> Option<&'r str> {
+    authenticate(req).as_ref().ok().map(String::as_str)
+}
+
+/// Request guard for the customer identified by the request's bearer token.
+pub struct AuthenticatedCustomer {
+    pub customer_id: String,
+}
+
+#[async_trait]
+impl<'r> FromRequest<'r> for AuthenticatedCustomer {
+    type Error = String;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        match authenticate(req) {
+            Ok(customer_id) => request::Outcome::Success(AuthenticatedCustomer {
+                customer_id: customer_id.clone(),
+            }),
+            Err(e) => request::Outcome::Failure((Status::Unauthorized, e.clone())),
+        }
+    }
+}
+
+/// Answers requests without a valid bearer token with 401 instead of the wrapped route, the
+/// engine's own routes having no `AuthenticatedCustomer` guard of ours.
+#[derive(Clone)]
+struct Authenticated(Box<dyn Handler>);
+
+#[async_trait]
+impl Handler for Authenticated {
+    async fn handle<'r>(&self, req: &'r Request<'_>, data: Data<'r>) -> Outcome<'r> {
+        match authenticate(req) {
+            Ok(_) => self.0.handle(req, data).await,
+            Err(_) => Outcome::Failure(Status::Unauthorized),
+        }
+    }
+}
+
+/// Requires a valid bearer token on every one of `routes`.
+pub fn authenticated(routes: Vec<Route>) -> Vec<Route> {
+    routes
+        .into_iter()
+        .map(|mut route| {
+            route.handler = Box::new(Authenticated(route.handler));
+            route
+        })
+        .collect()
+}
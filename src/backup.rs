@@ -0,0 +1,640 @@
+//!Encrypted, integrity-checked backups of the key store and audit log, and their restore
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::string::String;
+use std::sync::Arc;
+use std::time::Duration;
+
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::stream::{DecryptorBE32, EncryptorBE32};
+use aes_gcm::aead::{KeyInit, OsRng, Payload};
+use aes_gcm::Aes256Gcm;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use rocket::fairing::AdHoc;
+use rocket::serde::json::{json, Json};
+use rocket::{post, State};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::admin::{AdminToken, ADMIN_ACTOR};
+use crate::api_error::{ApiError, ErrorCode};
+use crate::audit::{verify_chain, AuditError, AuditEvent, AuditLog};
+use crate::error::StorageError;
+use crate::public_gotham::PublicGotham;
+use crate::rocksdb_store::RocksDbStore;
+
+/// Layout of the archive. Bumped on any incompatible change.
+pub const BACKUP_FORMAT_VERSION: u16 = 2;
+
+/// An archive is a header `MAGIC | format version (u16 BE) | nonce prefix | manifest length
+/// (u32 BE) | manifest JSON | HMAC-SHA256 of all that`, followed by the file contents in
+/// manifest order, encrypted as a STREAM of AES-256-GCM chunks authenticated with the header
+/// MAC as associated data. Chunks are `CHUNK_LEN` long but for the last one, which is shorter,
+/// possibly empty, so that neither the header nor the contents can be swapped, reordered or
+/// truncated, and neither side ever holds a whole archive in memory.
+const MAGIC: &[u8; 8] = b"GOTHAMBK";
+const VERSION_LEN: usize = 2;
+/// The 96 bit GCM nonce, less the STREAM counter and last chunk flag.
+const NONCE_PREFIX_LEN: usize = 7;
+const MAC_LEN: usize = 32;
+const TAG_LEN: usize = 16;
+const CHUNK_LEN: usize = 64 * 1024;
+/// The header up to the manifest JSON.
+const FIXED_HEADER_LEN: usize = MAGIC.len() + VERSION_LEN + NONCE_PREFIX_LEN + 4;
+const ARCHIVE_PREFIX: &str = "gotham-";
+const ARCHIVE_EXTENSION: &str = "backup";
+
+/// Directories of an archive, as restored by `restore`.
+const DB_DIR: &str = "db";
+const AUDIT_DIR: &str = "audit";
+
+#[derive(Debug, Error)]
+pub enum BackupError {
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+    #[error(transparent)]
+    Audit(#[from] AuditError),
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Backups are not supported by the {0} backend")]
+    Unsupported(&'static str),
+    #[error("Backup key must be 32 hex encoded bytes")]
+    InvalidKey,
+    #[error("Not a backup archive")]
+    NotAnArchive,
+    #[error("Unsupported backup format version {0}")]
+    UnsupportedFormat(u16),
+    #[error("Backup of server version {0} is not compatible with this server")]
+    IncompatibleVersion(String),
+    #[error("Backup failed authentication, wrong key or corrupted archive")]
+    Decryption,
+    #[error("Backup is corrupted: {0}")]
+    Corrupted(String),
+    #[error("Restore target {0} already exists")]
+    TargetExists(PathBuf),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchivedFile {
+    /// `db/` or `audit/` followed by the file name within the checkpoint.
+    pub path: String,
+    pub len: u64,
+    pub sha256: String,
+}
+
+impl ArchivedFile {
+    fn split_path(&self) -> Result<(&str, &str), BackupError> {
+        match self.path.split_once('/') {
+            Some((dir, name))
+                if (dir == DB_DIR || dir == AUDIT_DIR)
+                    && !name.is_empty()
+                    && !name.contains('/')
+                    && !name.contains("..") =>
+            {
+                Ok((dir, name))
+            }
+            _ => Err(BackupError::Corrupted(format!("file path {}", self.path))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    pub format_version: u16,
+    /// Version of the server that took the backup.
+    pub server_version: String,
+    pub created_at: DateTime<Utc>,
+    pub files: Vec<ArchivedFile>,
+}
+
+impl Manifest {
+    /// Archives are restorable by servers of the same major version.
+    fn check_compatible(&self) -> Result<(), BackupError> {
+        if self.format_version != BACKUP_FORMAT_VERSION {
+            return Err(BackupError::UnsupportedFormat(self.format_version));
+        }
+        let major = |version: &str| version.split('.').next().map(str::to_string);
+        if major(&self.server_version) != major(env!("CARGO_PKG_VERSION")) {
+            return Err(BackupError::IncompatibleVersion(self.server_version.clone()));
+        }
+        Ok(())
+    }
+}
+
+/// Key of the archives, from the `backup_key` setting. The chunks and the header MAC use keys
+/// derived from it for each purpose.
+pub struct BackupKey {
+    cipher: Aes256Gcm,
+    mac_key: Vec<u8>,
+}
+
+impl BackupKey {
+    pub fn from_hex(hex_key: &str) -> Result<Self, BackupError> {
+        let key_bytes = hex::decode(hex_key.trim()).map_err(|_| BackupError::InvalidKey)?;
+        if key_bytes.len() != 32 {
+            return Err(BackupError::InvalidKey);
+        }
+        let derive = |purpose: &[u8]| {
+            let mut mac = <HmacSha256 as Mac>::new_from_slice(&key_bytes).expect("HMAC takes keys of any length");
+            mac.update(purpose);
+            mac.finalize().into_bytes()
+        };
+        Ok(BackupKey {
+            cipher: Aes256Gcm::new(&derive(b"gotham backup chunks")),
+            mac_key: derive(b"gotham backup header").to_vec(),
+        })
+    }
+
+    fn header_mac(&self) -> HmacSha256 {
+        <HmacSha256 as Mac>::new_from_slice(&self.mac_key).expect("HMAC takes keys of any length")
+    }
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Encrypts everything written to it into STREAM chunks of `CHUNK_LEN`, the last one on `finish`.
+struct ChunkWriter<W: Write> {
+    encryptor: EncryptorBE32<Aes256Gcm>,
+    associated_data: Vec<u8>,
+    chunk: Vec<u8>,
+    out: W,
+}
+
+impl<W: Write> ChunkWriter<W> {
+    fn finish(mut self) -> io::Result<W> {
+        let last = self
+            .encryptor
+            .encrypt_last(Payload {
+                msg: &self.chunk,
+                aad: &self.associated_data,
+            })
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "Unable to encrypt the backup"))?;
+        self.out.write_all(&last)?;
+        Ok(self.out)
+    }
+}
+
+impl<W: Write> Write for ChunkWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let taken = buf.len().min(CHUNK_LEN - self.chunk.len());
+        self.chunk.extend_from_slice(&buf[..taken]);
+        // A full chunk is never the last one, so that the last is always shorter
+        if self.chunk.len() == CHUNK_LEN {
+            let sealed = self
+                .encryptor
+                .encrypt_next(Payload {
+                    msg: &self.chunk,
+                    aad: &self.associated_data,
+                })
+                .map_err(|_| io::Error::new(io::ErrorKind::Other, "Unable to encrypt the backup"))?;
+            self.out.write_all(&sealed)?;
+            self.chunk.clear();
+        }
+        Ok(taken)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Reads back what a `ChunkWriter` wrote. A chunk failing authentication, including a missing
+/// last chunk, is an `InvalidData` error.
+struct ChunkReader<R: Read> {
+    decryptor: Option<DecryptorBE32<Aes256Gcm>>,
+    associated_data: Vec<u8>,
+    chunk: Vec<u8>,
+    position: usize,
+    input: R,
+}
+
+impl<R: Read> ChunkReader<R> {
+    fn next_chunk(&mut self) -> io::Result<()> {
+        let mut sealed = Vec::with_capacity(CHUNK_LEN + TAG_LEN);
+        (&mut self.input)
+            .take((CHUNK_LEN + TAG_LEN) as u64)
+            .read_to_end(&mut sealed)?;
+        let payload = Payload {
+            msg: &sealed,
+            aad: &self.associated_data,
+        };
+        let opened = if sealed.len() == CHUNK_LEN + TAG_LEN {
+            let decryptor = self.decryptor.as_mut().expect("checked by read");
+            decryptor.decrypt_next(payload)
+        } else {
+            let decryptor = self.decryptor.take().expect("checked by read");
+            decryptor.decrypt_last(payload)
+        };
+        self.chunk = opened
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Backup chunk failed authentication"))?;
+        self.position = 0;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for ChunkReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.chunk.len() {
+            if self.decryptor.is_none() {
+                return Ok(0);
+            }
+            self.next_chunk()?;
+        }
+        let read = buf.len().min(self.chunk.len() - self.position);
+        buf[..read].copy_from_slice(&self.chunk[self.position..self.position + read]);
+        self.position += read;
+        Ok(read)
+    }
+}
+
+/// A chunk failing authentication means a wrong key or a corrupted archive.
+fn opening_error(err: io::Error) -> BackupError {
+    match err.kind() {
+        io::ErrorKind::InvalidData => BackupError::Decryption,
+        _ => BackupError::Io(err),
+    }
+}
+
+/// Hashes a file as it is copied.
+struct HashingWriter<W: Write> {
+    hasher: Sha256,
+    out: W,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.out.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+fn hash_file(path: &Path) -> Result<(u64, String), BackupError> {
+    let mut hashing = HashingWriter {
+        hasher: Sha256::new(),
+        out: io::sink(),
+    };
+    let len = io::copy(&mut File::open(path)?, &mut hashing)?;
+    Ok((len, hex::encode(hashing.hasher.finalize())))
+}
+
+/// Writes an archive of the checkpoint directories `dirs`, named by their archive directory.
+/// The archive is written next to `out` and renamed into place once complete and synced.
+pub fn write_archive(dirs: &[(&str, &Path)], key: &BackupKey, out: &Path) -> Result<Manifest, BackupError> {
+    let mut files = Vec::new();
+    let mut sources = Vec::new();
+    for (name, dir) in dirs {
+        let mut entries: Vec<PathBuf> = fs::read_dir(dir)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<_, _>>()?;
+        entries.sort();
+        for path in entries.into_iter().filter(|path| path.is_file()) {
+            let (len, sha256) = hash_file(&path)?;
+            let file_name = path.file_name().expect("read_dir entries have a name");
+            files.push(ArchivedFile {
+                path: format!("{}/{}", name, file_name.to_string_lossy()),
+                len,
+                sha256,
+            });
+            sources.push(path);
+        }
+    }
+    let manifest = Manifest {
+        format_version: BACKUP_FORMAT_VERSION,
+        server_version: env!("CARGO_PKG_VERSION").to_string(),
+        created_at: Utc::now(),
+        files,
+    };
+
+    let manifest_json = serde_json::to_vec(&manifest).map_err(StorageError::from)?;
+    let mut nonce_prefix = [0u8; NONCE_PREFIX_LEN];
+    OsRng.fill_bytes(&mut nonce_prefix);
+    let header = [
+        &MAGIC[..],
+        &BACKUP_FORMAT_VERSION.to_be_bytes(),
+        &nonce_prefix,
+        &(manifest_json.len() as u32).to_be_bytes(),
+        &manifest_json,
+    ]
+    .concat();
+    let mut mac = key.header_mac();
+    mac.update(&header);
+    let header_mac = mac.finalize().into_bytes().to_vec();
+
+    let partial = out.with_extension("partial");
+    let mut archive = BufWriter::new(File::create(&partial)?);
+    archive.write_all(&header)?;
+    archive.write_all(&header_mac)?;
+    let mut chunks = ChunkWriter {
+        encryptor: EncryptorBE32::from_aead(key.cipher.clone(), GenericArray::from_slice(&nonce_prefix)),
+        associated_data: header_mac,
+        chunk: Vec::with_capacity(CHUNK_LEN),
+        out: archive,
+    };
+    for (source, file) in sources.iter().zip(&manifest.files) {
+        // A checkpoint is immutable, anything else means it was tampered with while archived
+        let copied = io::copy(&mut File::open(source)?, &mut chunks)?;
+        if copied != file.len {
+            return Err(BackupError::Corrupted(format!("{} changed while archived", file.path)));
+        }
+    }
+    let archive = chunks.finish()?.into_inner().map_err(|e| e.into_error())?;
+    archive.sync_all()?;
+    fs::rename(&partial, out)?;
+    Ok(manifest)
+}
+
+/// Checks the header of an archive and returns its manifest and a reader of its contents.
+fn open_archive(archive: &Path, key: &BackupKey) -> Result<(Manifest, ChunkReader<BufReader<File>>), BackupError> {
+    let mut input = BufReader::new(File::open(archive)?);
+    let mut fixed = [0u8; FIXED_HEADER_LEN];
+    input.read_exact(&mut fixed).map_err(|_| BackupError::NotAnArchive)?;
+    let (magic, rest) = fixed.split_at(MAGIC.len());
+    let (format_version, rest) = rest.split_at(VERSION_LEN);
+    let (nonce_prefix, manifest_len) = rest.split_at(NONCE_PREFIX_LEN);
+    if magic != MAGIC {
+        return Err(BackupError::NotAnArchive);
+    }
+    let format_version = u16::from_be_bytes([format_version[0], format_version[1]]);
+    if format_version != BACKUP_FORMAT_VERSION {
+        return Err(BackupError::UnsupportedFormat(format_version));
+    }
+    let manifest_len = u32::from_be_bytes(manifest_len.try_into().expect("4 bytes")) as u64;
+    let mut manifest_json = Vec::new();
+    (&mut input).take(manifest_len).read_to_end(&mut manifest_json)?;
+    let mut header_mac = vec![0u8; MAC_LEN];
+    if manifest_json.len() as u64 != manifest_len || input.read_exact(&mut header_mac).is_err() {
+        return Err(BackupError::Corrupted("truncated header".to_string()));
+    }
+    let mut mac = key.header_mac();
+    mac.update(&fixed);
+    mac.update(&manifest_json);
+    mac.verify_slice(&header_mac).map_err(|_| BackupError::Decryption)?;
+
+    let manifest: Manifest =
+        serde_json::from_slice(&manifest_json).map_err(|_| BackupError::Corrupted("manifest".to_string()))?;
+    manifest.check_compatible()?;
+    let contents = ChunkReader {
+        decryptor: Some(DecryptorBE32::from_aead(key.cipher.clone(), GenericArray::from_slice(nonce_prefix))),
+        associated_data: header_mac,
+        chunk: Vec::new(),
+        position: 0,
+        input,
+    };
+    Ok((manifest, contents))
+}
+
+/// Decrypts an archive, checking its version and every file against the manifest, and hands
+/// each file's contents to `sink`. Contents are streamed: a file may be partly written to its
+/// sink before the archive turns out to be corrupted.
+fn unpack<F>(archive: &Path, key: &BackupKey, mut sink: F) -> Result<Manifest, BackupError>
+where
+    F: FnMut(&str, &str) -> io::Result<Box<dyn Write>>,
+{
+    let (manifest, mut contents) = open_archive(archive, key)?;
+    for file in &manifest.files {
+        let (dir, name) = file.split_path()?;
+        let mut hashing = HashingWriter {
+            hasher: Sha256::new(),
+            out: sink(dir, name)?,
+        };
+        let copied = io::copy(&mut (&mut contents).take(file.len), &mut hashing).map_err(opening_error)?;
+        hashing.flush()?;
+        if copied != file.len {
+            return Err(BackupError::Corrupted(format!("truncated {}", file.path)));
+        }
+        if hex::encode(hashing.hasher.finalize()) != file.sha256 {
+            return Err(BackupError::Corrupted(format!("checksum of {}", file.path)));
+        }
+    }
+    // Also authenticates the last chunk
+    let mut trailing = [0u8; 1];
+    if contents.read(&mut trailing).map_err(opening_error)? != 0 {
+        return Err(BackupError::Corrupted("trailing data".to_string()));
+    }
+    Ok(manifest)
+}
+
+/// Decrypts an archive and checks its version and every file against the manifest.
+pub fn read_archive(archive: &Path, key: &BackupKey) -> Result<Manifest, BackupError> {
+    unpack(archive, key, |_, _| Ok(Box::new(io::sink())))
+}
+
+/// Restores an archive into the key store directory `db_dir` and the audit log directory
+/// `audit_dir`, neither of which may exist. Files are unpacked next to them and discarded
+/// unless the archive decrypts, is compatible and matches its manifest, and the restored
+/// databases are only moved into place once they open and the audit log chain verifies.
+pub fn restore(archive: &Path, key: &BackupKey, db_dir: &Path, audit_dir: &Path) -> Result<Manifest, BackupError> {
+    for target in [db_dir, audit_dir] {
+        if target.exists() {
+            return Err(BackupError::TargetExists(target.to_path_buf()));
+        }
+    }
+    let staged_db = db_dir.with_extension("restoring");
+    let staged_audit = audit_dir.with_extension("restoring");
+    for staged in [&staged_db, &staged_audit] {
+        if staged.exists() {
+            fs::remove_dir_all(staged)?;
+        }
+        fs::create_dir_all(staged)?;
+    }
+    let unpacked = unpack(archive, key, |dir, name| {
+        let staged = if dir == DB_DIR { &staged_db } else { &staged_audit };
+        Ok(Box::new(BufWriter::new(File::create(staged.join(name))?)))
+    });
+    let manifest = match unpacked {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            for staged in [&staged_db, &staged_audit] {
+                let _ = fs::remove_dir_all(staged);
+            }
+            return Err(e);
+        }
+    };
+
+    drop(RocksDbStore::open(&staged_db)?);
+    {
+        let audit_log = AuditLog::open_read_only(&staged_audit)?;
+        let records = audit_log.records(0, usize::MAX)?;
+        verify_chain(records.into_iter().map(Ok))?;
+    }
+    fs::rename(&staged_db, db_dir)?;
+    fs::rename(&staged_audit, audit_dir)?;
+    Ok(manifest)
+}
+
+/// Where archives are written and how many are kept, from the `backup_*` settings.
+pub struct BackupConfig {
+    pub dir: PathBuf,
+    pub key: BackupKey,
+    pub keep: usize,
+}
+
+/// Managed by the server; backups are disabled without a `backup_key`.
+pub struct Backups(pub Option<BackupConfig>);
+
+impl Backups {
+    pub fn new(settings: &HashMap<String, String>) -> Result<Self, String> {
+        let key = match settings.get("backup_key").filter(|key| !key.trim().is_empty()) {
+            Some(key) => BackupKey::from_hex(key).map_err(|_| "backup_key must be 32 hex encoded bytes".to_string())?,
+            None => {
+                log::info!("No backup_key configured, backups are disabled");
+                return Ok(Backups(None));
+            }
+        };
+        Ok(Backups(Some(BackupConfig {
+            dir: PathBuf::from(settings.get("backup_dir").map(String::as_str).unwrap_or("./backups")),
+            key,
+            keep: settings
+                .get("backup_keep")
+                .filter(|keep| !keep.is_empty())
+                .map(|keep| keep.parse().map_err(|_| "backup_keep must be a number of archives".to_string()))
+                .transpose()?
+                .unwrap_or(7),
+        })))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BackupInfo {
+    pub archive: String,
+    pub created_at: DateTime<Utc>,
+    pub files: usize,
+}
+
+impl BackupConfig {
+    /// Checkpoints the key store, then the audit log, and archives both. The audit log is taken
+    /// last so that it holds at least every action behind the records of the key store. Old
+    /// archives are pruned once the new one is written; a failure to do so is only logged.
+    pub fn run(&self, gotham: &PublicGotham) -> Result<BackupInfo, BackupError> {
+        let staging = self.dir.join(format!(".checkpoint-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&staging)?;
+        let (db_checkpoint, audit_checkpoint) = (staging.join(DB_DIR), staging.join(AUDIT_DIR));
+        let archived = gotham
+            .checkpoint(&db_checkpoint, &audit_checkpoint)
+            .and_then(|_| {
+                let created_at = Utc::now();
+                let archive = self.dir.join(format!(
+                    "{}{}.{}",
+                    ARCHIVE_PREFIX,
+                    created_at.format("%Y%m%dT%H%M%S%.3fZ"),
+                    ARCHIVE_EXTENSION
+                ));
+                let manifest = write_archive(
+                    &[(DB_DIR, &db_checkpoint), (AUDIT_DIR, &audit_checkpoint)],
+                    &self.key,
+                    &archive,
+                )?;
+                Ok(BackupInfo {
+                    archive: archive.to_string_lossy().to_string(),
+                    created_at: manifest.created_at,
+                    files: manifest.files.len(),
+                })
+            });
+        if let Err(e) = fs::remove_dir_all(&staging) {
+            log::warn!("Unable to remove the backup checkpoint {}: {}", staging.display(), e);
+        }
+        let info = archived?;
+        // The archive is written, failing to remove older ones must not hide it
+        if let Err(e) = self.prune() {
+            log::error!("Unable to remove old backups from {}: {}", self.dir.display(), e);
+        }
+        Ok(info)
+    }
+
+    /// Removes the oldest archives beyond `keep`. Archive names sort by creation time.
+    fn prune(&self) -> Result<(), BackupError> {
+        let mut archives: Vec<PathBuf> = fs::read_dir(&self.dir)?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| {
+                let name = path.file_name().map(|name| name.to_string_lossy().to_string());
+                name.map_or(false, |name| name.starts_with(ARCHIVE_PREFIX))
+                    && path.extension().map_or(false, |extension| extension == ARCHIVE_EXTENSION)
+            })
+            .collect();
+        archives.sort();
+        let excess = archives.len().saturating_sub(self.keep.max(1));
+        for archive in &archives[..excess] {
+            fs::remove_file(archive)?;
+            log::info!("Removed old backup {}", archive.display());
+        }
+        Ok(())
+    }
+}
+
+fn backup_and_audit(gotham: &PublicGotham, config: &BackupConfig, actor: &str) -> Result<BackupInfo, BackupError> {
+    let info = config.run(gotham)?;
+    gotham.audit(AuditEvent::new(
+        actor,
+        "backup",
+        "",
+        None,
+        json!({ "archive": info.archive, "files": info.files }),
+    ))?;
+    log::info!("Backed up the key store to {}", info.archive);
+    Ok(info)
+}
+
+/// Periodically backs up the key store once the server has lifted off.
+pub fn scheduler(gotham: Arc<PublicGotham>, backups: Arc<Backups>, interval: Duration) -> AdHoc {
+    AdHoc::on_liftoff("Backup scheduler", move |_| {
+        Box::pin(async move {
+            tokio::spawn(async move {
+                let mut ticker = tokio::time::interval(interval);
+                // The first tick completes immediately, back up one interval after startup
+                ticker.tick().await;
+                loop {
+                    ticker.tick().await;
+                    let (gotham, backups) = (gotham.clone(), backups.clone());
+                    let backed_up = tokio::task::spawn_blocking(move || {
+                        let config = backups.0.as_ref().expect("scheduled without a backup_key");
+                        backup_and_audit(&gotham, config, "scheduler")
+                    })
+                    .await;
+                    match backed_up {
+                        Ok(Ok(_)) => {}
+                        Ok(Err(e)) => log::error!("Scheduled backup failed: {}", e),
+                        Err(e) => log::error!("Backup scheduler panicked: {}", e),
+                    }
+                }
+            });
+        })
+    })
+}
+
+/// Takes a backup now.
+#[post("/admin/backups")]
+pub async fn create_backup(
+    _admin: AdminToken,
+    gotham: &State<Arc<PublicGotham>>,
+    backups: &State<Arc<Backups>>,
+) -> Result<Json<BackupInfo>, ApiError> {
+    if backups.0.is_none() {
+        return Err(ApiError::new(ErrorCode::Unavailable, "Backups are not configured"));
+    }
+    let (gotham, backups) = (gotham.inner().clone(), backups.inner().clone());
+    tokio::task::spawn_blocking(move || {
+        let config = backups.0.as_ref().expect("checked above");
+        backup_and_audit(&gotham, config, ADMIN_ACTOR)
+    })
+    .await
+    .map_err(|e| ApiError::new(ErrorCode::Internal, format!("Backup panicked: {}", e)))?
+    .map(Json)
+    .map_err(|e| {
+        log::error!("Backup failed: {}", e);
+        match e {
+            BackupError::Unsupported(_) => ApiError::new(ErrorCode::Unavailable, e.to_string()),
+            _ => ApiError::new(ErrorCode::StorageError, "Backup failed"),
+        }
+    })
+}
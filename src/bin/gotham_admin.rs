@@ -0,0 +1,182 @@
+//!Inspects and maintains the RocksDB key store of a stopped server
+//!
+//!    gotham_admin <db_dir> customers                        Customers and their number of keys
+//!    gotham_admin <db_dir> keys <customer_id>               Ids of a customer's keys
+//!    gotham_admin <db_dir> show <customer_id> <id>          Tables holding a record of the key
+//!    gotham_admin <db_dir> verify [<customer_id> [<id>]]    Checks that records open and deserialize
+//!    gotham_admin <db_dir> export <customer_id> <id> [--include-secrets]
+//!    gotham_admin <db_dir> delete <customer_id> <id> [<audit_log_dir>] [--force]
+//!
+//!Sealed records are opened with the master keys of the MASTER_KEY, MASTER_KEY_FILE and
+//!MASTER_KEY_ID environment variables, as configured for the server. `export` prints the key's
+//!records as stored, refusing unsealed records holding secrets without `--include-secrets`.
+//!Only `delete` writes: it refuses to run while the server holds the store open, and to delete
+//!a key that was not deactivated first without `--force`.
+
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::process::exit;
+
+use gotham_engine::types::DbIndex;
+use public_server_lib::audit::{AuditEvent, AuditLog};
+use public_server_lib::encryption::{MasterKeyError, MasterKeys};
+use public_server_lib::expiry::SessionTtl;
+use public_server_lib::metadata::KeyStatus;
+use public_server_lib::offline::OfflineStore;
+use public_server_lib::public_gotham::{Config, PublicGotham, DB};
+use public_server_lib::rocksdb_store::RocksDbStore;
+use public_server_lib::server::get_master_keys;
+use public_server_lib::shares::SharePolicy;
+use rocket::serde::json::json;
+
+const ACTOR: &str = "gotham_admin";
+
+const USAGE: &str = "usage: gotham_admin <db_dir> customers
+       gotham_admin <db_dir> keys <customer_id>
+       gotham_admin <db_dir> show <customer_id> <id>
+       gotham_admin <db_dir> verify [<customer_id> [<id>]]
+       gotham_admin <db_dir> export <customer_id> <id> [--include-secrets]
+       gotham_admin <db_dir> delete <customer_id> <id> [<audit_log_dir>] [--force]";
+
+fn master_keys() -> Result<Option<MasterKeys>, MasterKeyError> {
+    let settings: HashMap<String, String> = env::vars()
+        .map(|(name, value)| (name.to_lowercase(), value))
+        .collect();
+    get_master_keys(&settings)
+}
+
+fn customers(store: &OfflineStore) -> Result<(), Box<dyn Error>> {
+    for (customer_id, ids) in store.customers()? {
+        println!("{}\t{} keys", customer_id, ids.len());
+    }
+    Ok(())
+}
+
+fn keys(store: &OfflineStore, customer_id: &str) -> Result<(), Box<dyn Error>> {
+    let customers = store.customers()?;
+    let ids = customers
+        .get(customer_id)
+        .ok_or_else(|| format!("No records of customer '{}'", customer_id))?;
+    for id in ids {
+        println!("{}", id);
+    }
+    Ok(())
+}
+
+fn show(store: &OfflineStore, customer_id: &str, id: &str) -> Result<(), Box<dyn Error>> {
+    let records = store.records(customer_id, id)?;
+    if records.is_empty() {
+        return Err(format!("No records of key '{}'", id).into());
+    }
+    for record in records {
+        let sealing = match record.sealed_with {
+            Some(key_id) => format!("sealed with master key {}", key_id),
+            None => "plaintext".to_string(),
+        };
+        println!("{}\t{} bytes\t{}", record.table, record.bytes, sealing);
+    }
+    Ok(())
+}
+
+fn verify(store: &OfflineStore, customer_id: Option<&str>, id: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let (checked, problems) = store.verify(customer_id, id)?;
+    for problem in &problems {
+        println!("{}\t{}\t{}", problem.table, problem.key, problem.error);
+    }
+    if !problems.is_empty() {
+        return Err(format!("{} of {} records failed", problems.len(), checked).into());
+    }
+    println!("OK: {} records", checked);
+    Ok(())
+}
+
+fn export(store: &OfflineStore, customer_id: &str, id: &str, include_secrets: bool) -> Result<(), Box<dyn Error>> {
+    let export = store
+        .export(customer_id, id, include_secrets)?
+        .ok_or_else(|| format!("No records of key '{}'", id))?;
+    println!("{}", serde_json::to_string_pretty(&export)?);
+    Ok(())
+}
+
+fn delete(
+    db_dir: &str,
+    customer_id: &str,
+    id: &str,
+    audit_dir: Option<&str>,
+    force: bool,
+) -> Result<(), Box<dyn Error>> {
+    let audit_dir = audit_dir.map_or_else(|| format!("{}_audit", db_dir.trim_end_matches('/')), str::to_string);
+    let gotham = PublicGotham::new(Config {
+        db: DB::Local(RocksDbStore::open(db_dir)?),
+        master_keys: master_keys()?,
+        share_policy: SharePolicy::default(),
+        session_ttl: SessionTtl::default(),
+        audit_log: AuditLog::open(&audit_dir)?,
+        recovery_key: None,
+    });
+    let key = DbIndex {
+        customer_id: customer_id.to_string(),
+        id: id.to_string(),
+    };
+    // As the server's delete route, unless forced
+    if !force && gotham.describe_key(&key)?.status != KeyStatus::Deactivated {
+        return Err(format!("Key '{}' must be deactivated before it is deleted, or pass --force", id).into());
+    }
+    // On record before anything goes, as the server's delete route
+    gotham.audit(AuditEvent::new(ACTOR, "delete_key", customer_id, Some(id), json!({ "forced": force })))?;
+    let purged = gotham.delete_key(&key)?;
+    let done = AuditEvent::new(ACTOR, "key_deleted", customer_id, Some(id), json!({ "records": purged }));
+    if let Err(e) = gotham.audit(done) {
+        eprintln!("Unable to audit the completed deletion of key {}: {}", id, e);
+    }
+    println!("Deleted {} records of key {}", purged, id);
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (flags, args): (Vec<&str>, Vec<&str>) = args.iter().map(String::as_str).partition(|arg| arg.starts_with("--"));
+    let (db_dir, command) = match args.as_slice() {
+        [db_dir, command @ ..] if !command.is_empty() => (*db_dir, command),
+        _ => {
+            eprintln!("{}", USAGE);
+            exit(2);
+        }
+    };
+    for flag in &flags {
+        match (*flag, command[0]) {
+            ("--include-secrets", "export") | ("--force", "delete") => {}
+            _ => {
+                eprintln!("{}", USAGE);
+                exit(2);
+            }
+        }
+    }
+    let (include_secrets, force) = (flags.contains(&"--include-secrets"), flags.contains(&"--force"));
+
+    let result = match command {
+        ["delete", customer_id, id] => delete(db_dir, customer_id, id, None, force),
+        ["delete", customer_id, id, audit_dir] => delete(db_dir, customer_id, id, Some(audit_dir), force),
+        read_only => master_keys()
+            .map_err(Box::<dyn Error>::from)
+            .and_then(|master_keys| OfflineStore::open_read_only(db_dir, master_keys).map_err(Box::<dyn Error>::from))
+            .and_then(|store| match read_only {
+                ["customers"] => customers(&store),
+                ["keys", customer_id] => keys(&store, customer_id),
+                ["show", customer_id, id] => show(&store, customer_id, id),
+                ["verify"] => verify(&store, None, None),
+                ["verify", customer_id] => verify(&store, Some(customer_id), None),
+                ["verify", customer_id, id] => verify(&store, Some(customer_id), Some(id)),
+                ["export", customer_id, id] => export(&store, customer_id, id, include_secrets),
+                _ => {
+                    eprintln!("{}", USAGE);
+                    exit(2);
+                }
+            }),
+    };
+    if let Err(e) = result {
+        eprintln!("FAILED: {}", e);
+        exit(1);
+    }
+}
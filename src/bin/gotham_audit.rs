@@ -0,0 +1,71 @@
+//!Exports and verifies the hash-chained audit log
+//!
+//!    gotham_audit export <audit_log_path> [from]   Prints the records of a stopped server's log
+//!    gotham_audit verify [export.jsonl]            Verifies an export, read from stdin without a file
+
+use std::env;
+use std::fs::File;
+use std::io::{self, BufReader, Write};
+use std::process::exit;
+
+use public_server_lib::audit::{read_export, verify_chain, AuditLog};
+
+const EXPORT_BATCH: usize = 1000;
+
+const USAGE: &str = "usage: gotham_audit export <audit_log_path> [from]
+       gotham_audit verify [export.jsonl]";
+
+fn export(path: &str, from: u64) -> Result<(), Box<dyn std::error::Error>> {
+    let log = AuditLog::open_read_only(path)?;
+    let mut stdout = io::stdout().lock();
+    let mut next = from;
+    loop {
+        let records = log.records(next, EXPORT_BATCH)?;
+        for record in &records {
+            writeln!(stdout, "{}", serde_json::to_string(record)?)?;
+        }
+        match records.last() {
+            Some(last) if records.len() == EXPORT_BATCH => next = last.seq + 1,
+            _ => return Ok(()),
+        }
+    }
+}
+
+fn verify(path: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    let summary = match path {
+        Some(path) => verify_chain(read_export(BufReader::new(File::open(path)?)))?,
+        None => verify_chain(read_export(io::stdin().lock()))?,
+    };
+    match summary.first_seq {
+        Some(first_seq) => println!(
+            "OK: {} records from {} to {}, head hash {}",
+            summary.records,
+            first_seq,
+            first_seq + summary.records as u64 - 1,
+            summary.head_hash
+        ),
+        None => println!("OK: no records"),
+    }
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        ["export", path] => export(path, 0),
+        ["export", path, from] => match from.parse() {
+            Ok(from) => export(path, from),
+            Err(_) => Err(format!("Invalid sequence number '{}'", from).into()),
+        },
+        ["verify"] => verify(None),
+        ["verify", path] => verify(Some(path)),
+        _ => {
+            eprintln!("{}", USAGE);
+            exit(2);
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("FAILED: {}", e);
+        exit(1);
+    }
+}
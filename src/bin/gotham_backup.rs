@@ -0,0 +1,56 @@
+//!Verifies and restores backup archives. The key is read from the BACKUP_KEY environment variable.
+//!
+//!    gotham_backup verify <archive>                           Checks integrity and compatibility
+//!    gotham_backup restore <archive> <db_dir> <audit_log_dir>  Restores into directories that must not exist
+
+use std::env;
+use std::path::Path;
+use std::process::exit;
+
+use public_server_lib::backup::{read_archive, restore, BackupError, BackupKey, Manifest};
+
+const USAGE: &str = "usage: gotham_backup verify <archive>
+       gotham_backup restore <archive> <db_dir> <audit_log_dir>";
+
+fn print_manifest(manifest: &Manifest) {
+    println!(
+        "Backup of {} by server {}, format {}, {} files",
+        manifest.created_at,
+        manifest.server_version,
+        manifest.format_version,
+        manifest.files.len()
+    );
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let key = match env::var("BACKUP_KEY") {
+        Ok(key) => BackupKey::from_hex(&key),
+        Err(_) => Err(BackupError::InvalidKey),
+    };
+    let key = key.unwrap_or_else(|e| {
+        eprintln!("BACKUP_KEY: {}", e);
+        exit(2);
+    });
+
+    let result = match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        ["verify", archive] => read_archive(Path::new(archive), &key),
+        ["restore", archive, db_dir, audit_dir] => {
+            restore(Path::new(archive), &key, Path::new(db_dir), Path::new(audit_dir))
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            exit(2);
+        }
+    };
+    match result {
+        Ok(manifest) => {
+            print_manifest(&manifest);
+            println!("OK");
+        }
+        Err(e) => {
+            eprintln!("FAILED: {}", e);
+            exit(1);
+        }
+    }
+}
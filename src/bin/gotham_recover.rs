@@ -0,0 +1,99 @@
+//!Unilateral recovery of a key without the server, from a `gotham_wallet` wallet and an escrow of
+//!the server's share. The wallet is opened with the WALLET_PASSPHRASE environment variable, the
+//!recovery secret key is read from RECOVERY_SECRET_KEY; GOTHAM_TOKEN, when set, is sent as bearer token.
+//!
+//!    gotham_recover keygen                                  Prints a new recovery key pair
+//!    gotham_recover escrow <wallet> [<recovery_public_key>] Escrows the server's share, or fetches its escrow
+//!    gotham_recover recover <wallet> <escrow> <x_pos> <y_pos>  Prints the private key of a child key
+//!
+//!`escrow` prints the escrow once its proofs are checked against the wallet, keep it along with
+//!the wallet. With RECOVERY_SECRET_KEY set, it is also decrypted.
+
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::process::exit;
+
+use public_server_lib::client::{GothamClient, HttpTransport};
+use public_server_lib::recovery::{
+    generate_recovery_key, parse_recovery_secret_key, recover_private_key, RecoveryEscrow,
+};
+use public_server_lib::wallet::{public_key_hex, Wallet};
+use two_party_ecdsa::curv::elliptic::curves::traits::ECScalar;
+use two_party_ecdsa::party_one::Converter;
+
+const USAGE: &str = "usage: gotham_recover keygen
+       gotham_recover escrow <wallet> [<recovery_public_key>]
+       gotham_recover recover <wallet> <escrow> <x_pos> <y_pos>";
+
+fn open_wallet(wallet_path: &str) -> Result<Wallet, Box<dyn Error>> {
+    let passphrase = env::var("WALLET_PASSPHRASE").map_err(|_| "WALLET_PASSPHRASE is not set")?;
+    Ok(Wallet::open(Path::new(wallet_path), &passphrase)?)
+}
+
+fn position(pos: &str) -> Result<u32, Box<dyn Error>> {
+    pos.parse()
+        .map_err(|_| format!("Invalid path position '{}'", pos).into())
+}
+
+fn keygen() -> Result<(), Box<dyn Error>> {
+    let (secret_key, public_key) = generate_recovery_key();
+    println!("recovery public key {}", public_key_hex(&public_key));
+    println!("recovery secret key {:0>64}", secret_key.to_big_int().to_hex());
+    Ok(())
+}
+
+async fn escrow(wallet_path: &str, recovery_public_key: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let wallet = open_wallet(wallet_path)?;
+    let client = GothamClient::new(HttpTransport::new(&wallet.server_url, env::var("GOTHAM_TOKEN").ok()));
+    let escrow = match recovery_public_key {
+        Some(recovery_public_key) => {
+            client
+                .escrow_share(&wallet.id, &wallet.master_key, recovery_public_key)
+                .await?
+        }
+        None => client.recovery_escrow(&wallet.id, &wallet.master_key).await?,
+    };
+    // Checked by the client against the wallet's public share
+    if let Ok(recovery_secret_key) = env::var("RECOVERY_SECRET_KEY") {
+        let x1 = escrow.decrypt(&parse_recovery_secret_key(&recovery_secret_key)?)?;
+        recover_private_key(&x1, &wallet.master_key, 0, 0)?;
+        // On stderr, stdout is the escrow to keep
+        eprintln!("The escrow decrypts to the server's share of the key");
+    }
+    println!("{}", serde_json::to_string_pretty(&escrow)?);
+    Ok(())
+}
+
+fn recover(wallet_path: &str, escrow_path: &str, x_pos: &str, y_pos: &str) -> Result<(), Box<dyn Error>> {
+    let wallet = open_wallet(wallet_path)?;
+    let escrow: RecoveryEscrow = serde_json::from_slice(&fs::read(escrow_path)?)?;
+    escrow.verify(&wallet.master_key.public.p1)?;
+    let recovery_secret_key = env::var("RECOVERY_SECRET_KEY").map_err(|_| "RECOVERY_SECRET_KEY is not set")?;
+    let x1 = escrow.decrypt(&parse_recovery_secret_key(&recovery_secret_key)?)?;
+    let (x_pos, y_pos) = (position(x_pos)?, position(y_pos)?);
+    let private_key = recover_private_key(&x1, &wallet.master_key, x_pos, y_pos)?;
+    println!("public key {}", public_key_hex(&wallet.child_public_key(x_pos, y_pos)));
+    println!("private key {:0>64}", private_key.to_big_int().to_hex());
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        ["keygen"] => keygen(),
+        ["escrow", wallet] => escrow(wallet, None).await,
+        ["escrow", wallet, recovery_public_key] => escrow(wallet, Some(recovery_public_key)).await,
+        ["recover", wallet, escrow, x_pos, y_pos] => recover(wallet, escrow, x_pos, y_pos),
+        _ => {
+            eprintln!("{}", USAGE);
+            exit(2);
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("FAILED: {}", e);
+        exit(1);
+    }
+}
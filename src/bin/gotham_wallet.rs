@@ -0,0 +1,135 @@
+//!Party two wallet, for exercising a deployment end to end. The wallet file is encrypted with the
+//!passphrase of the WALLET_PASSPHRASE environment variable; GOTHAM_TOKEN, when set, is sent as
+//!bearer token.
+//!
+//!    gotham_wallet create <server_url> <wallet>                     Generates a key with the server
+//!    gotham_wallet address <wallet> <x_pos> <y_pos>                 Public key of a child key
+//!    gotham_wallet sign <wallet> <x_pos> <y_pos> <hash_hex>          Signs a message hash with a child key
+//!    gotham_wallet verify <wallet> <x_pos> <y_pos> <hash_hex> <r_hex> <s_hex>
+//!
+//!Against a server started locally with `cargo run --bin public_server_exec`, the server url is
+//!`http://127.0.0.1:8000`.
+
+use std::env;
+use std::error::Error;
+use std::path::Path;
+use std::process::exit;
+
+use public_server_lib::client::{GothamClient, HttpTransport};
+use public_server_lib::wallet::{public_key_hex, verify_signature, Wallet};
+use rocket::serde::json::json;
+use two_party_ecdsa::party_one::{Converter, SignatureRecid};
+use two_party_ecdsa::BigInt;
+
+const USAGE: &str = "usage: gotham_wallet create <server_url> <wallet>
+       gotham_wallet address <wallet> <x_pos> <y_pos>
+       gotham_wallet sign <wallet> <x_pos> <y_pos> <hash_hex>
+       gotham_wallet verify <wallet> <x_pos> <y_pos> <hash_hex> <r_hex> <s_hex>";
+
+fn passphrase() -> Result<String, Box<dyn Error>> {
+    match env::var("WALLET_PASSPHRASE") {
+        Ok(passphrase) if !passphrase.is_empty() => Ok(passphrase),
+        _ => Err("WALLET_PASSPHRASE is not set".into()),
+    }
+}
+
+fn client(server_url: &str) -> GothamClient<HttpTransport> {
+    GothamClient::new(HttpTransport::new(server_url, env::var("GOTHAM_TOKEN").ok()))
+}
+
+fn position(pos: &str) -> Result<u32, Box<dyn Error>> {
+    pos.parse()
+        .map_err(|_| format!("Invalid path position '{}'", pos).into())
+}
+
+fn big_int_of_hex(what: &str, hex_value: &str) -> Result<BigInt, Box<dyn Error>> {
+    let hex_value = hex_value.trim_start_matches("0x");
+    let padded = match hex_value.len() % 2 {
+        0 => hex_value.to_string(),
+        _ => format!("0{}", hex_value),
+    };
+    let bytes = hex::decode(padded).map_err(|e| format!("{}: {}", what, e))?;
+    Ok(BigInt::from(&bytes[..]))
+}
+
+async fn create(server_url: &str, wallet_path: &str) -> Result<(), Box<dyn Error>> {
+    let passphrase = passphrase()?;
+    let path = Path::new(wallet_path);
+    if path.exists() {
+        return Err(format!("Wallet {} already exists", wallet_path).into());
+    }
+    let (id, master_key) = client(server_url).key_gen().await?;
+    let wallet = Wallet::new(server_url, id, master_key);
+    wallet.create(path, &passphrase)?;
+    println!("Created key {}", wallet.id);
+    println!("public key {}", public_key_hex(&wallet.master_key.public.q));
+    Ok(())
+}
+
+fn address(wallet_path: &str, x_pos: &str, y_pos: &str) -> Result<(), Box<dyn Error>> {
+    let wallet = Wallet::open(Path::new(wallet_path), &passphrase()?)?;
+    let public_key = wallet.child_public_key(position(x_pos)?, position(y_pos)?);
+    println!("{}", public_key_hex(&public_key));
+    Ok(())
+}
+
+async fn sign(wallet_path: &str, x_pos: &str, y_pos: &str, hash: &str) -> Result<(), Box<dyn Error>> {
+    let wallet = Wallet::open(Path::new(wallet_path), &passphrase()?)?;
+    let (x_pos, y_pos) = (position(x_pos)?, position(y_pos)?);
+    let message = big_int_of_hex("hash_hex", hash)?;
+    let signature = client(&wallet.server_url)
+        .sign(
+            &wallet.id,
+            &wallet.master_key,
+            &message,
+            &BigInt::from(x_pos),
+            &BigInt::from(y_pos),
+        )
+        .await?;
+    if !verify_signature(&signature, &wallet.child_public_key(x_pos, y_pos), &message) {
+        return Err("The server returned a signature that does not verify".into());
+    }
+    println!(
+        "{}",
+        json!({
+            "r": format!("{:0>64}", signature.r.to_hex()),
+            "s": format!("{:0>64}", signature.s.to_hex()),
+            "recid": signature.recid,
+        })
+    );
+    Ok(())
+}
+
+fn verify(wallet_path: &str, x_pos: &str, y_pos: &str, hash: &str, r: &str, s: &str) -> Result<(), Box<dyn Error>> {
+    let wallet = Wallet::open(Path::new(wallet_path), &passphrase()?)?;
+    let signature = SignatureRecid {
+        r: big_int_of_hex("r_hex", r)?,
+        s: big_int_of_hex("s_hex", s)?,
+        recid: 0,
+    };
+    let public_key = wallet.child_public_key(position(x_pos)?, position(y_pos)?);
+    if !verify_signature(&signature, &public_key, &big_int_of_hex("hash_hex", hash)?) {
+        return Err("Invalid signature".into());
+    }
+    println!("OK");
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        ["create", server_url, wallet] => create(server_url, wallet).await,
+        ["address", wallet, x_pos, y_pos] => address(wallet, x_pos, y_pos),
+        ["sign", wallet, x_pos, y_pos, hash] => sign(wallet, x_pos, y_pos, hash).await,
+        ["verify", wallet, x_pos, y_pos, hash, r, s] => verify(wallet, x_pos, y_pos, hash, r, s),
+        _ => {
+            eprintln!("{}", USAGE);
+            exit(2);
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("FAILED: {}", e);
+        exit(1);
+    }
+}
@@ -0,0 +1,405 @@
+//!Party two of the Gotham protocol: a typed client of the server routes over a pluggable transport
+
+use std::string::String;
+
+use rocket::async_trait;
+use rocket::http::ContentType;
+use rocket::local::{asynchronous, blocking};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use thiserror::Error;
+
+use gotham_engine::types::SignSecondMsgRequest;
+use two_party_ecdsa::curv::cryptographic_primitives::twoparty::coin_flip_optimal_rounds;
+use two_party_ecdsa::curv::cryptographic_primitives::twoparty::dh_key_exchange_variant_with_pok_comm::{
+    Party1FirstMessage, Party1SecondMessage,
+};
+use two_party_ecdsa::kms::chain_code::two_party::party2::ChainCode2;
+use two_party_ecdsa::kms::ecdsa::two_party::{party1, MasterKey2};
+use two_party_ecdsa::kms::rotation::two_party::party2::Rotation2;
+use two_party_ecdsa::{party_one, BigInt};
+
+use crate::recovery::RecoveryEscrow;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Post,
+    Put,
+    Delete,
+}
+
+impl From<Method> for rocket::http::Method {
+    fn from(method: Method) -> Self {
+        match method {
+            Method::Get => rocket::http::Method::Get,
+            Method::Post => rocket::http::Method::Post,
+            Method::Put => rocket::http::Method::Put,
+            Method::Delete => rocket::http::Method::Delete,
+        }
+    }
+}
+
+/// A response as received, before the client interprets it.
+#[derive(Debug, Clone)]
+pub struct Reply {
+    pub status: u16,
+    pub body: String,
+}
+
+#[derive(Debug, Error)]
+pub enum ClientError {
+    #[error("Transport error: {0}")]
+    Transport(String),
+    /// The error envelope of a failed request.
+    #[error("Server answered {status} {code}: {message}")]
+    Api {
+        status: u16,
+        code: String,
+        message: String,
+    },
+    #[error("Unexpected response: {0}")]
+    Decode(#[from] serde_json::Error),
+    /// The server's messages did not verify on the client side.
+    #[error("Protocol verification failed: {0}")]
+    Protocol(String),
+}
+
+#[derive(Deserialize)]
+struct Envelope {
+    error: EnvelopeError,
+}
+
+#[derive(Deserialize)]
+struct EnvelopeError {
+    code: String,
+    message: String,
+}
+
+impl ClientError {
+    fn from_reply(reply: Reply) -> Self {
+        match serde_json::from_str::<Envelope>(&reply.body) {
+            Ok(envelope) => ClientError::Api {
+                status: reply.status,
+                code: envelope.error.code,
+                message: envelope.error.message,
+            },
+            Err(_) => ClientError::Api {
+                status: reply.status,
+                code: "unknown".to_string(),
+                message: reply.body,
+            },
+        }
+    }
+}
+
+/// Carries requests to a Gotham server. The futures are not `Send`, so that the blocking
+/// in-process Rocket client of the tests can serve as a transport.
+#[async_trait(?Send)]
+pub trait Transport {
+    /// Sends `body`, a JSON document, to `path`, which starts with `/`.
+    async fn send(&self, method: Method, path: &str, body: Option<String>) -> Result<Reply, ClientError>;
+}
+
+#[async_trait(?Send)]
+impl<T: Transport + ?Sized> Transport for &T {
+    async fn send(&self, method: Method, path: &str, body: Option<String>) -> Result<Reply, ClientError> {
+        (**self).send(method, path, body).await
+    }
+}
+
+/// An in-process server, driven from synchronous code. Its futures must not be awaited
+/// within a Tokio runtime, run them with an executor such as `futures::executor::block_on`.
+#[async_trait(?Send)]
+impl Transport for blocking::Client {
+    async fn send(&self, method: Method, path: &str, body: Option<String>) -> Result<Reply, ClientError> {
+        let mut request = self.req(method.into(), path.to_string()).header(ContentType::JSON);
+        if let Some(body) = body {
+            request = request.body(body);
+        }
+        let response = request.dispatch();
+        Ok(Reply {
+            status: response.status().code,
+            body: response.into_string().unwrap_or_default(),
+        })
+    }
+}
+
+/// An in-process server, driven from asynchronous code.
+#[async_trait(?Send)]
+impl Transport for asynchronous::Client {
+    async fn send(&self, method: Method, path: &str, body: Option<String>) -> Result<Reply, ClientError> {
+        let mut request = self.req(method.into(), path.to_string()).header(ContentType::JSON);
+        if let Some(body) = body {
+            request = request.body(body);
+        }
+        let response = request.dispatch().await;
+        Ok(Reply {
+            status: response.status().code,
+            body: response.into_string().await.unwrap_or_default(),
+        })
+    }
+}
+
+/// A server reached over HTTP at `base_url`, authenticating with a bearer token when given.
+/// Requests must be sent from within a Tokio runtime.
+#[derive(Clone)]
+pub struct HttpTransport {
+    base_url: String,
+    token: Option<String>,
+    client: reqwest::Client,
+}
+
+impl HttpTransport {
+    pub fn new(base_url: &str, token: Option<String>) -> Self {
+        HttpTransport {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            token,
+            client: reqwest::Client::new(),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Transport for HttpTransport {
+    async fn send(&self, method: Method, path: &str, body: Option<String>) -> Result<Reply, ClientError> {
+        let method = match method {
+            Method::Get => reqwest::Method::GET,
+            Method::Post => reqwest::Method::POST,
+            Method::Put => reqwest::Method::PUT,
+            Method::Delete => reqwest::Method::DELETE,
+        };
+        let mut request = self
+            .client
+            .request(method, &format!("{}{}", self.base_url, path))
+            .header(reqwest::header::CONTENT_TYPE, "application/json");
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        if let Some(body) = body {
+            request = request.body(body);
+        }
+        let transport_error = |e: reqwest::Error| ClientError::Transport(e.to_string());
+        let response = request.send().await.map_err(transport_error)?;
+        Ok(Reply {
+            status: response.status().as_u16(),
+            body: response.text().await.map_err(transport_error)?,
+        })
+    }
+}
+
+/// Runs the party two side of the protocols against a server.
+pub struct GothamClient<T> {
+    transport: T,
+}
+
+impl<T: Transport> GothamClient<T> {
+    pub fn new(transport: T) -> Self {
+        GothamClient { transport }
+    }
+
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    /// Sends a request and deserializes its successful response, for any route of the server.
+    pub async fn request<B: Serialize, R: DeserializeOwned>(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<&B>,
+    ) -> Result<R, ClientError> {
+        let body = body.map(serde_json::to_string).transpose()?;
+        let reply = self.transport.send(method, path, body).await?;
+        if !(200..300).contains(&reply.status) {
+            return Err(ClientError::from_reply(reply));
+        }
+        Ok(serde_json::from_str(&reply.body)?)
+    }
+
+    pub async fn post<B: Serialize, R: DeserializeOwned>(&self, path: &str, body: &B) -> Result<R, ClientError> {
+        self.request(Method::Post, path, Some(body)).await
+    }
+
+    pub async fn get<R: DeserializeOwned>(&self, path: &str) -> Result<R, ClientError> {
+        self.request::<(), R>(Method::Get, path, None).await
+    }
+
+    async fn post_empty<R: DeserializeOwned>(&self, path: &str) -> Result<R, ClientError> {
+        self.request::<(), R>(Method::Post, path, None).await
+    }
+
+    /// Generates a key and its chain code, returning the key id and party two's master key.
+    pub async fn key_gen(&self) -> Result<(String, MasterKey2), ClientError> {
+        let (id, kg_party_one_first_message): (String, party_one::KeyGenFirstMsg) =
+            self.post_empty("/ecdsa/keygen/first").await?;
+        let (kg_party_two_first_message, kg_ec_key_pair_party2) = MasterKey2::key_gen_first_message();
+
+        let kg_party_one_second_message: party1::KeyGenParty1Message2 = self
+            .post(
+                &format!("/ecdsa/keygen/{}/second", id),
+                &kg_party_two_first_message.d_log_proof,
+            )
+            .await?;
+        let (party_two_second_message, party_two_paillier, party_two_pdl_chal) =
+            MasterKey2::key_gen_second_message(&kg_party_one_first_message, &kg_party_one_second_message)
+                .map_err(|_| ClientError::Protocol("party one's second keygen message".to_string()))?;
+
+        let party_one_third_message: party_one::PDLFirstMessage = self
+            .post(
+                &format!("/ecdsa/keygen/{}/third", id),
+                &party_two_second_message.pdl_first_message,
+            )
+            .await?;
+        let pdl_decom_party2 = MasterKey2::key_gen_third_message(&party_two_pdl_chal);
+
+        let party_one_pdl_second_message: party_one::PDLSecondMessage = self
+            .post(&format!("/ecdsa/keygen/{}/fourth", id), &pdl_decom_party2)
+            .await?;
+        MasterKey2::key_gen_fourth_message(
+            &party_two_pdl_chal,
+            &party_one_third_message,
+            &party_one_pdl_second_message,
+        )
+        .map_err(|_| ClientError::Protocol("party one's PDL proof".to_string()))?;
+
+        let cc_party_one_first_message: Party1FirstMessage = self
+            .post_empty(&format!("/ecdsa/keygen/{}/chaincode/first", id))
+            .await?;
+        let (cc_party_two_first_message, cc_ec_key_pair2) = ChainCode2::chain_code_first_message();
+        let cc_party_one_second_message: Party1SecondMessage = self
+            .post(
+                &format!("/ecdsa/keygen/{}/chaincode/second", id),
+                &cc_party_two_first_message.d_log_proof,
+            )
+            .await?;
+        ChainCode2::chain_code_second_message(&cc_party_one_first_message, &cc_party_one_second_message)
+            .map_err(|_| ClientError::Protocol("party one's chain code messages".to_string()))?;
+        let party2_cc = ChainCode2::compute_chain_code(
+            &cc_ec_key_pair2,
+            &cc_party_one_second_message.comm_witness.public_share,
+        )
+        .chain_code;
+
+        let master_key_2 = MasterKey2::set_master_key(
+            &party2_cc,
+            &kg_ec_key_pair_party2,
+            &kg_party_one_second_message
+                .ecdh_second_message
+                .comm_witness
+                .public_share,
+            &party_two_paillier,
+        );
+        Ok((id, master_key_2))
+    }
+
+    /// Signs `message`, a hash, with the child key at `x_pos`/`y_pos`.
+    pub async fn sign(
+        &self,
+        id: &str,
+        master_key_2: &MasterKey2,
+        message: &BigInt,
+        x_pos: &BigInt,
+        y_pos: &BigInt,
+    ) -> Result<party_one::SignatureRecid, ClientError> {
+        let (eph_key_gen_first_message_party_two, eph_comm_witness, eph_ec_key_pair_party2) =
+            MasterKey2::sign_first_message();
+        let sign_party_one_first_message: party_one::EphKeyGenFirstMsg = self
+            .post(&format!("/ecdsa/sign/{}/first", id), &eph_key_gen_first_message_party_two)
+            .await?;
+
+        let party_two_sign_message = master_key_2
+            .get_child(vec![x_pos.clone(), y_pos.clone()])
+            .sign_second_message(
+                &eph_ec_key_pair_party2,
+                eph_comm_witness,
+                &sign_party_one_first_message,
+                message,
+            );
+        let request = SignSecondMsgRequest {
+            message: message.clone(),
+            party_two_sign_message,
+            x_pos_child_key: x_pos.clone(),
+            y_pos_child_key: y_pos.clone(),
+        };
+        self.post(&format!("/ecdsa/sign/{}/second", id), &request).await
+    }
+
+    /// Rotates both shares of the key, returning party two's rotated master key. The server keeps
+    /// signing with the previous shares until `confirm_rotation`, which should follow once the
+    /// rotated key is stored.
+    pub async fn rotate(&self, id: &str, master_key_2: &MasterKey2) -> Result<MasterKey2, ClientError> {
+        let coin_flip_party1_first_message: coin_flip_optimal_rounds::Party1FirstMessage =
+            self.post_empty(&format!("/ecdsa/rotate/{}/first", id)).await?;
+        let coin_flip_party2_first_message =
+            Rotation2::key_rotate_first_message(&coin_flip_party1_first_message);
+
+        let (coin_flip_party1_second_message, rotation_party1_first_message): (
+            coin_flip_optimal_rounds::Party1SecondMessage,
+            party1::RotationParty1Message1,
+        ) = self
+            .post(&format!("/ecdsa/rotate/{}/second", id), &coin_flip_party2_first_message)
+            .await?;
+        let random2 = Rotation2::key_rotate_second_message(
+            &coin_flip_party1_second_message,
+            &coin_flip_party2_first_message,
+            &coin_flip_party1_first_message,
+        );
+        let (rotation_party_two_first_message, party_two_pdl_chal, party_two_paillier) = master_key_2
+            .clone()
+            .rotate_first_message(&random2, &rotation_party1_first_message)
+            .map_err(|_| ClientError::Protocol("party one's first rotation message".to_string()))?;
+
+        let rotation_party1_second_message: party_one::PDLFirstMessage = self
+            .post(&format!("/ecdsa/rotate/{}/third", id), &rotation_party_two_first_message)
+            .await?;
+        let rotation_party_two_second_message = MasterKey2::rotate_second_message(&party_two_pdl_chal);
+        let rotation_party1_third_message: party_one::PDLSecondMessage = self
+            .post(&format!("/ecdsa/rotate/{}/fourth", id), &rotation_party_two_second_message)
+            .await?;
+
+        master_key_2
+            .clone()
+            .rotate_third_message(
+                &random2,
+                &party_two_paillier,
+                &party_two_pdl_chal,
+                &rotation_party1_second_message,
+                &rotation_party1_third_message,
+            )
+            .map_err(|_| ClientError::Protocol("party one's rotation PDL proof".to_string()))
+    }
+
+    pub async fn confirm_rotation(&self, id: &str) -> Result<(), ClientError> {
+        let _: serde_json::Value = self.post_empty(&format!("/ecdsa/rotate/{}/confirm", id)).await?;
+        Ok(())
+    }
+
+    /// Escrows the server's share of the key under `recovery_public_key`, a hex encoded SEC1
+    /// public key, and checks the escrow against party one's public share.
+    pub async fn escrow_share(
+        &self,
+        id: &str,
+        master_key_2: &MasterKey2,
+        recovery_public_key: &str,
+    ) -> Result<RecoveryEscrow, ClientError> {
+        let request = json!({ "recovery_public_key": recovery_public_key });
+        let escrow: RecoveryEscrow = self
+            .request(Method::Put, &format!("/ecdsa/keys/{}/recovery", id), Some(&request))
+            .await?;
+        escrow
+            .verify(&master_key_2.public.p1)
+            .map_err(|e| ClientError::Protocol(e.to_string()))?;
+        Ok(escrow)
+    }
+
+    /// The current escrow of the server's share of the key, checked against party one's public share.
+    pub async fn recovery_escrow(&self, id: &str, master_key_2: &MasterKey2) -> Result<RecoveryEscrow, ClientError> {
+        let escrow: RecoveryEscrow = self.get(&format!("/ecdsa/keys/{}/recovery", id)).await?;
+        escrow
+            .verify(&master_key_2.public.p1)
+            .map_err(|e| ClientError::Protocol(e.to_string()))?;
+        Ok(escrow)
+    }
+}
@@ -0,0 +1,170 @@
+//!Authenticated encryption of MPC records at rest
+
+use std::collections::HashMap;
+use std::string::String;
+
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use thiserror::Error;
+
+use crate::error::StorageError;
+use crate::keys::encode_key;
+
+/// Marks a stored value as `{RECORD_PREFIX}:{key_id}:{nonce}:{ciphertext}`, nonce and
+/// ciphertext hex encoded. Plaintext records are JSON objects and can never start with it.
+/// The key id, table and key of the record are authenticated as associated data, so that a
+/// sealed value copied under another record does not open.
+const RECORD_PREFIX: &str = "gotham-enc-v1";
+
+/// Why a keyring could not be loaded. Never quotes key material.
+#[derive(Debug, Error)]
+pub enum MasterKeyError {
+    #[error("Unable to read master key file {0}: {1}")]
+    File(String, #[source] std::io::Error),
+    #[error("Master key entries must be formatted as key_id:hex_key")]
+    Malformed,
+    #[error("Master key id is illegal, may only contain alphanumeric characters")]
+    IllegalId,
+    #[error("Master key {0} is not valid hex")]
+    NotHex(String),
+    #[error("Master key {0} must be 32 bytes long")]
+    WrongLength(String),
+    #[error("Master keyring is empty")]
+    Empty,
+    #[error("Active master key {0} is not in the keyring")]
+    UnknownActiveKey(String),
+}
+
+/// AES-256-GCM master keys by key identifier. New records are sealed with the active key,
+/// older keys are kept so records written before a rotation can still be opened.
+pub struct MasterKeys {
+    active_key_id: String,
+    keys: HashMap<String, Aes256Gcm>,
+    accept_plaintext: bool,
+}
+
+impl MasterKeys {
+    /// Parses a keyring of `key_id:hex_key` entries separated by commas or newlines,
+    /// each key being 32 bytes. Without an explicit `active_key_id` the last entry is active,
+    /// so a key is rotated by appending a new entry.
+    pub fn parse(keyring: &str, active_key_id: Option<&str>) -> Result<Self, MasterKeyError> {
+        let mut keys = HashMap::new();
+        let mut last_key_id = None;
+        for entry in keyring
+            .split(|c| c == ',' || c == '\n')
+            .map(str::trim)
+            .filter(|e| !e.is_empty())
+        {
+            let (key_id, hex_key) = entry.split_once(':').ok_or(MasterKeyError::Malformed)?;
+            if key_id.is_empty() || !key_id.chars().all(|e| char::is_ascii_alphanumeric(&e)) {
+                return Err(MasterKeyError::IllegalId);
+            }
+            let key_bytes = hex::decode(hex_key).map_err(|_| MasterKeyError::NotHex(key_id.to_string()))?;
+            if key_bytes.len() != 32 {
+                return Err(MasterKeyError::WrongLength(key_id.to_string()));
+            }
+            let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key_bytes));
+            keys.insert(key_id.to_string(), cipher);
+            last_key_id = Some(key_id.to_string());
+        }
+
+        let active_key_id = match active_key_id {
+            Some(key_id) => key_id.to_string(),
+            None => last_key_id.ok_or(MasterKeyError::Empty)?,
+        };
+        if !keys.contains_key(&active_key_id) {
+            return Err(MasterKeyError::UnknownActiveKey(active_key_id));
+        }
+
+        Ok(MasterKeys {
+            active_key_id,
+            keys,
+            accept_plaintext: false,
+        })
+    }
+
+    /// Lets `open` pass plaintext records through, while a store written before encryption
+    /// was enabled is being migrated. They are refused otherwise.
+    pub fn accepting_plaintext(mut self, accept_plaintext: bool) -> Self {
+        self.accept_plaintext = accept_plaintext;
+        self
+    }
+
+    /// Encrypts the serialized record stored under `key` in `table` with the active key.
+    pub fn seal(&self, table: &str, key: &str, plaintext: &str) -> Result<String, StorageError> {
+        let cipher = &self.keys[&self.active_key_id];
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext.as_bytes(),
+                    aad: associated_data(&self.active_key_id, table, key).as_bytes(),
+                },
+            )
+            .map_err(|_| StorageError::Encryption)?;
+
+        Ok(format!(
+            "{}:{}:{}:{}",
+            RECORD_PREFIX,
+            self.active_key_id,
+            hex::encode(nonce),
+            hex::encode(ciphertext)
+        ))
+    }
+
+    /// Decrypts the record stored under `key` in `table`. Plaintext records written before
+    /// encryption was enabled are only passed through while migrating. The flag is set when
+    /// the record should be rewritten under the active key.
+    pub fn open(&self, table: &str, key: &str, stored: &str) -> Result<(String, bool), StorageError> {
+        let sealed = match stored.strip_prefix(RECORD_PREFIX) {
+            Some(sealed) => sealed,
+            None if self.accept_plaintext => return Ok((stored.to_string(), true)),
+            None => return Err(StorageError::Unencrypted),
+        };
+
+        let mut parts = sealed.trim_start_matches(':').splitn(3, ':');
+        let (key_id, nonce, ciphertext) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(key_id), Some(nonce), Some(ciphertext)) => (key_id, nonce, ciphertext),
+            _ => return Err(StorageError::MalformedRecord("missing fields".to_string())),
+        };
+        let cipher = self
+            .keys
+            .get(key_id)
+            .ok_or_else(|| StorageError::UnknownMasterKey(key_id.to_string()))?;
+        let nonce = hex::decode(nonce)
+            .map_err(|e| StorageError::MalformedRecord(format!("nonce: {}", e)))?;
+        if nonce.len() != 12 {
+            return Err(StorageError::MalformedRecord("nonce length".to_string()));
+        }
+        let ciphertext = hex::decode(ciphertext)
+            .map_err(|e| StorageError::MalformedRecord(format!("ciphertext: {}", e)))?;
+        let plaintext = cipher
+            .decrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &ciphertext,
+                    aad: associated_data(key_id, table, key).as_bytes(),
+                },
+            )
+            .map_err(|_| StorageError::Decryption)?;
+
+        Ok((String::from_utf8(plaintext)?, key_id != self.active_key_id))
+    }
+}
+
+fn associated_data(key_id: &str, table: &str, key: &str) -> String {
+    encode_key(&[key_id, table, key])
+}
+
+/// The id of the master key a stored value is sealed under, none for plaintext values.
+pub fn sealed_key_id(stored: &str) -> Option<&str> {
+    stored
+        .strip_prefix(RECORD_PREFIX)
+        .and_then(|sealed| sealed.trim_start_matches(':').split(':').next())
+}
+
+/// Returns true when a stored value was written by `MasterKeys::seal`.
+pub fn is_sealed(stored: &str) -> bool {
+    stored.starts_with(RECORD_PREFIX)
+}
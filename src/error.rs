@@ -0,0 +1,58 @@
+//!Storage errors of the public gotham Db backends
+
+use thiserror::Error;
+
+use gotham_engine::types::DatabaseError;
+
+#[derive(Debug, Error)]
+pub enum StorageError {
+    #[error("RocksDB error: {0}")]
+    RocksDb(#[from] rocksdb::Error),
+    #[error("Redis error: {0}")]
+    Redis(#[from] redis::RedisError),
+    #[error("Redis connection pool error: {0}")]
+    RedisPool(#[from] r2d2::Error),
+    #[error("Stored record is not valid UTF-8: {0}")]
+    Utf8(#[from] std::string::FromUtf8Error),
+    /// Only the position is displayed: serde messages quote the offending value, which
+    /// may be key material.
+    #[error("Stored record could not be (de)serialized at line {}, column {}", .0.line(), .0.column())]
+    Serde(#[from] serde_json::Error),
+    #[error("Invalid key identifier: {0}")]
+    InvalidId(String),
+    #[error("Malformed encrypted record: {0}")]
+    MalformedRecord(String),
+    #[error("Encrypted record sealed under unknown master key {0}")]
+    UnknownMasterKey(String),
+    #[error("Found encrypted record but no master key is configured")]
+    NoMasterKey,
+    #[error("Found unencrypted record, set migrate_plaintext to accept it")]
+    Unencrypted,
+    #[error("Encrypted record failed authentication")]
+    Decryption,
+    #[error("Record encryption failed")]
+    Encryption,
+}
+
+/// Backend failures, corrupt records, invalid ids and encryption failures stay apart, so that
+/// the engine's callers can tell them from one another.
+impl From<StorageError> for DatabaseError {
+    fn from(err: StorageError) -> Self {
+        log::error!("{}", err);
+        let message = err.to_string();
+        match err {
+            StorageError::RocksDb(_) | StorageError::Redis(_) | StorageError::RedisPool(_) => {
+                DatabaseError::ConnectionError(message)
+            }
+            StorageError::Utf8(_) | StorageError::Serde(_) | StorageError::MalformedRecord(_) => {
+                DatabaseError::SerializationError(message)
+            }
+            StorageError::InvalidId(_) => DatabaseError::InvalidKeyError(message),
+            StorageError::UnknownMasterKey(_)
+            | StorageError::NoMasterKey
+            | StorageError::Unencrypted
+            | StorageError::Decryption
+            | StorageError::Encryption => DatabaseError::EncryptionError(message),
+        }
+    }
+}
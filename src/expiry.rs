@@ -0,0 +1,84 @@
+//!Expiry of abandoned keygen and signing sessions
+
+use std::collections::HashMap;
+use std::string::String;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rocket::fairing::AdHoc;
+
+use crate::public_gotham::{is_ephemeral_table, PublicGotham};
+
+/// How long records of each ephemeral table outlive their last write. Tables that are not
+/// ephemeral, such as the party one master key, never expire whatever is configured.
+#[derive(Debug, Clone, Default)]
+pub struct SessionTtl {
+    default: Option<Duration>,
+    per_table: HashMap<String, Duration>,
+}
+
+impl SessionTtl {
+    /// `default_secs` applies to every ephemeral table, `per_table` overrides it with
+    /// `Table=secs` entries separated by commas, e.g. `EcdsaEphEcKeyPair=600`.
+    pub fn parse(default_secs: Option<&str>, per_table: Option<&str>) -> Result<Self, String> {
+        let parse_secs = |secs: &str| {
+            let secs = secs.trim().parse().map_err(|_| "Session TTL must be a number of seconds".to_string())?;
+            Ok::<_, String>(Duration::from_secs(secs))
+        };
+        let default = default_secs.filter(|secs| !secs.is_empty()).map(parse_secs).transpose()?;
+        let per_table = per_table
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|e| !e.is_empty())
+            .map(|entry| {
+                let (table, secs) = entry
+                    .split_once('=')
+                    .ok_or_else(|| "Session TTL entries must be formatted as Table=secs".to_string())?;
+                Ok((table.trim().to_string(), parse_secs(secs)?))
+            })
+            .collect::<Result<_, String>>()?;
+
+        Ok(SessionTtl { default, per_table })
+    }
+
+    pub fn ttl(&self, table: &str) -> Option<Duration> {
+        if !is_ephemeral_table(table) {
+            return None;
+        }
+        self.per_table.get(table).copied().or(self.default)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.default.is_some() || !self.per_table.is_empty()
+    }
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System clock is before the unix epoch")
+        .as_secs()
+}
+
+/// Periodically purges expired session records once the server has lifted off.
+pub fn sweeper(gotham: Arc<PublicGotham>, interval: Duration) -> AdHoc {
+    AdHoc::on_liftoff("Session sweeper", move |_| {
+        Box::pin(async move {
+            tokio::spawn(async move {
+                let mut ticker = tokio::time::interval(interval);
+                loop {
+                    ticker.tick().await;
+                    let gotham = gotham.clone();
+                    let swept = tokio::task::spawn_blocking(move || gotham.purge_expired(unix_now())).await;
+                    match swept {
+                        Ok(Ok(0)) => {}
+                        Ok(Ok(purged)) => log::info!("Purged {} expired session records", purged),
+                        Ok(Err(e)) => log::error!("Session sweep failed: {}", e),
+                        Err(e) => log::error!("Session sweeper panicked: {}", e),
+                    }
+                }
+            });
+        })
+    })
+}
@@ -0,0 +1,62 @@
+//!Liveness and readiness probes
+
+use std::sync::Arc;
+
+use rocket::http::Status;
+use rocket::serde::json::{json, Json, Value};
+use rocket::{get, State};
+
+use crate::public_gotham::PublicGotham;
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The process is up and serving requests.
+#[get("/health/live")]
+pub fn live() -> Json<Value> {
+    Json(json!({
+        "status": "ok",
+        "version": VERSION,
+    }))
+}
+
+/// The Db backend answers a write/read round trip.
+#[get("/health/ready")]
+pub async fn ready(gotham: &State<Arc<PublicGotham>>) -> (Status, Json<Value>) {
+    let gotham = gotham.inner().clone();
+    let probe = tokio::task::spawn_blocking(move || {
+        let probe = gotham.probe();
+        (gotham.backend_name(), probe)
+    })
+    .await;
+
+    match probe {
+        Ok((backend, Ok(()))) => (
+            Status::Ok,
+            Json(json!({
+                "status": "ok",
+                "version": VERSION,
+                "backend": backend,
+            })),
+        ),
+        Ok((backend, Err(e))) => {
+            log::error!("Readiness probe failed: {}", e);
+            (
+                Status::ServiceUnavailable,
+                Json(json!({
+                    "status": "unavailable",
+                    "version": VERSION,
+                    "backend": backend,
+                    "error": e.to_string(),
+                })),
+            )
+        }
+        Err(e) => (
+            Status::ServiceUnavailable,
+            Json(json!({
+                "status": "unavailable",
+                "version": VERSION,
+                "error": e.to_string(),
+            })),
+        ),
+    }
+}
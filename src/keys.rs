@@ -0,0 +1,55 @@
+//!Unambiguous encoding of record keys and validation of the ids they are built from
+
+use std::string::String;
+
+use crate::error::StorageError;
+
+const MAX_CUSTOMER_ID_LEN: usize = 256;
+const MAX_ID_LEN: usize = 64;
+
+/// Concatenates each part as `{byte length}:{part}`, so that distinct tuples of parts
+/// never encode to the same key whatever characters they contain.
+pub fn encode_key(parts: &[&str]) -> String {
+    parts
+        .iter()
+        .map(|part| format!("{}:{}", part.len(), part))
+        .collect()
+}
+
+/// Splits a key written by `encode_key` into its parts, none when it is not such a key.
+pub fn decode_key(key: &str) -> Option<Vec<String>> {
+    let mut parts = Vec::new();
+    let mut rest = key;
+    while !rest.is_empty() {
+        let (len, tail) = rest.split_once(':')?;
+        if len.is_empty() || !len.chars().all(|e| e.is_ascii_digit()) {
+            return None;
+        }
+        let part = tail.get(..len.parse().ok()?)?;
+        rest = &tail[part.len()..];
+        parts.push(part.to_string());
+    }
+    Some(parts)
+}
+
+/// Customer ids come from the authenticated identity and may hold any printable character.
+pub fn validate_customer_id(customer_id: &str) -> Result<(), StorageError> {
+    if customer_id.is_empty()
+        || customer_id.len() > MAX_CUSTOMER_ID_LEN
+        || customer_id.chars().any(char::is_control)
+    {
+        return Err(StorageError::InvalidId(format!("customer id {:?}", customer_id)));
+    }
+    Ok(())
+}
+
+/// Key ids are generated by the server (uuids) and may only hold alphanumerics and `-`.
+pub fn validate_id(id: &str) -> Result<(), StorageError> {
+    if id.is_empty()
+        || id.len() > MAX_ID_LEN
+        || !id.chars().all(|e| e.is_ascii_alphanumeric() || e == '-')
+    {
+        return Err(StorageError::InvalidId(format!("id {:?}", id)));
+    }
+    Ok(())
+}
@@ -1,3 +1,27 @@
 pub mod tests;
 pub mod server;
 pub mod public_gotham;
+pub mod admin;
+pub mod api_error;
+pub mod audit;
+pub mod auth;
+pub mod backup;
+pub mod client;
+pub mod encryption;
+pub mod error;
+pub mod expiry;
+pub mod health;
+pub mod keys;
+pub mod lifecycle;
+pub mod metadata;
+pub mod metrics;
+pub mod offline;
+pub mod policy;
+pub mod protocol;
+pub mod recovery;
+pub mod redis_store;
+pub mod rocksdb_store;
+pub mod rotate;
+pub mod shares;
+pub mod telemetry;
+pub mod wallet;
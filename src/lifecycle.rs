@@ -0,0 +1,202 @@
+//!Deactivation and deletion of keys, by their customer or an operator, and the guard
+//!keeping retired keys out of the routes that use their share
+
+use std::string::String;
+use std::sync::Arc;
+
+use rocket::route::{Handler, Outcome, Route};
+use rocket::serde::json::{json, Json, Value};
+use rocket::{async_trait, delete, post, Data, Request, State};
+
+use gotham_engine::types::DbIndex;
+
+use crate::admin::{AdminToken, ADMIN_ACTOR};
+use crate::api_error::{blocking, ApiError, ErrorCode};
+use crate::audit::{audited, AuditEvent};
+use crate::auth::{customer_of, AuthenticatedCustomer};
+use crate::error::StorageError;
+use crate::keys::{validate_customer_id, validate_id};
+use crate::metadata::{KeyStatus, KeySummary};
+use crate::protocol::locked;
+use crate::public_gotham::{KeyLocks, PublicGotham};
+
+fn index(customer_id: String, id: String) -> Result<DbIndex, ApiError> {
+    validate_customer_id(&customer_id)
+        .and(validate_id(&id))
+        .map_err(|e| ApiError::new(ErrorCode::InvalidId, e.to_string()))?;
+    Ok(DbIndex { customer_id, id })
+}
+
+fn storage_failure(e: StorageError) -> ApiError {
+    log::error!("Key lifecycle storage failed: {}", e);
+    ApiError::new(ErrorCode::from(&e), "Storage backend failure")
+}
+
+fn existing(gotham: &PublicGotham, key: &DbIndex) -> Result<KeySummary, ApiError> {
+    let known = gotham
+        .key_metadata(key)
+        .map_err(storage_failure)?
+        .is_some()
+        || gotham
+            .party_one_master_key(key)
+            .map_err(storage_failure)?
+            .is_some();
+    if !known {
+        return Err(ApiError::new(ErrorCode::UnknownId, format!("Unknown id '{}'", key.id)));
+    }
+    gotham.describe_key(key).map_err(storage_failure)
+}
+
+fn deactivate(gotham: &PublicGotham, key: DbIndex, actor: &str) -> Result<Json<KeySummary>, ApiError> {
+    existing(gotham, &key)?;
+    let summary = gotham.deactivate_key(&key).map_err(storage_failure)?;
+    gotham
+        .audit(AuditEvent::new(actor, "deactivate_key", &key.customer_id, Some(&key.id), json!({})))
+        .map_err(storage_failure)?;
+    log::info!("Deactivated key {}", key.id);
+    Ok(Json(summary))
+}
+
+/// Only deactivated keys may be deleted, so that a key is never lost by a single call. The
+/// deletion is audited before any record goes, and marked done once they all went.
+fn delete(gotham: &PublicGotham, key: DbIndex, actor: &str) -> Result<Json<Value>, ApiError> {
+    if existing(gotham, &key)?.status != KeyStatus::Deactivated {
+        return Err(ApiError::new(
+            ErrorCode::StepOutOfOrder,
+            format!("Key '{}' must be deactivated before it is deleted", key.id),
+        ));
+    }
+    gotham
+        .audit(AuditEvent::new(actor, "delete_key", &key.customer_id, Some(&key.id), json!({})))
+        .map_err(storage_failure)?;
+    let purged = gotham.delete_key(&key).map_err(storage_failure)?;
+    let done = AuditEvent::new(
+        actor,
+        "key_deleted",
+        &key.customer_id,
+        Some(&key.id),
+        json!({ "records": purged }),
+    );
+    // The deletion is already on record, the key is gone either way
+    if let Err(e) = gotham.audit(done) {
+        log::error!("Unable to audit the completed deletion of key {}: {}", key.id, e);
+    }
+    log::info!("Deleted key {} ({} records)", key.id, purged);
+    Ok(Json(json!({ "id": key.id, "deleted": true, "records": purged })))
+}
+
+#[post("/ecdsa/keys/<id>/deactivate")]
+pub async fn deactivate_key(
+    gotham: &State<Arc<PublicGotham>>,
+    locks: &State<KeyLocks>,
+    customer: AuthenticatedCustomer,
+    id: String,
+) -> Result<Json<KeySummary>, ApiError> {
+    let (gotham, key) = (gotham.inner().clone(), index(customer.customer_id.clone(), id)?);
+    locked(locks, key, move |key| deactivate(&gotham, key, &customer.customer_id)).await?
+}
+
+#[delete("/ecdsa/keys/<id>")]
+pub async fn delete_key(
+    gotham: &State<Arc<PublicGotham>>,
+    locks: &State<KeyLocks>,
+    customer: AuthenticatedCustomer,
+    id: String,
+) -> Result<Json<Value>, ApiError> {
+    let (gotham, key) = (gotham.inner().clone(), index(customer.customer_id.clone(), id)?);
+    locked(locks, key, move |key| delete(&gotham, key, &customer.customer_id)).await?
+}
+
+#[post("/admin/keys/<customer_id>/<id>/deactivate")]
+pub async fn admin_deactivate_key(
+    _admin: AdminToken,
+    gotham: &State<Arc<PublicGotham>>,
+    locks: &State<KeyLocks>,
+    customer_id: String,
+    id: String,
+) -> Result<Json<KeySummary>, ApiError> {
+    let (gotham, key) = (gotham.inner().clone(), index(customer_id, id)?);
+    locked(locks, key, move |key| deactivate(&gotham, key, ADMIN_ACTOR)).await?
+}
+
+#[delete("/admin/keys/<customer_id>/<id>")]
+pub async fn admin_delete_key(
+    _admin: AdminToken,
+    gotham: &State<Arc<PublicGotham>>,
+    locks: &State<KeyLocks>,
+    customer_id: String,
+    id: String,
+) -> Result<Json<Value>, ApiError> {
+    let (gotham, key) = (gotham.inner().clone(), index(customer_id, id)?);
+    locked(locks, key, move |key| delete(&gotham, key, ADMIN_ACTOR)).await?
+}
+
+/// Why the key of a request may not use its share, if it may not.
+async fn refusal(req: &Request<'_>) -> Option<ApiError> {
+    let gotham = req.rocket().state::<Arc<PublicGotham>>()?.clone();
+    // Unauthenticated requests are refused by the route itself
+    let customer_id = customer_of(req)?;
+    let id = req.param::<String>(0)?.ok()?;
+    let key = match index(customer_id.to_string(), id) {
+        Ok(key) => key,
+        Err(error) => return Some(error),
+    };
+    let described = match blocking(move || gotham.describe_key(&key).map(|summary| (key, summary))).await {
+        Ok(described) => described,
+        Err(error) => return Some(error),
+    };
+    match described {
+        Ok((key, summary)) => match summary.status {
+            KeyStatus::Deactivated => Some(ApiError::new(
+                ErrorCode::KeyDeactivated,
+                format!("Key {} is deactivated", key.id),
+            )),
+            KeyStatus::Replaced => Some(ApiError::new(
+                ErrorCode::KeyReplaced,
+                format!("Key {} was replaced by a newer keygen", key.id),
+            )),
+            KeyStatus::Active => None,
+        },
+        Err(e) => {
+            log::error!("Key status lookup failed: {}", e);
+            Some(ApiError::new(ErrorCode::from(&e), "Key status lookup failed"))
+        }
+    }
+}
+
+/// Answers requests whose key may no longer use its share instead of the wrapped route.
+#[derive(Clone)]
+struct KeyGuard(Box<dyn Handler>);
+
+#[async_trait]
+impl Handler for KeyGuard {
+    async fn handle<'r>(&self, req: &'r Request<'_>, data: Data<'r>) -> Outcome<'r> {
+        let error = match refusal(req).await {
+            Some(error) => error,
+            None => return self.0.handle(req, data).await,
+        };
+        log::warn!("Refused {} {}: {}", req.method(), req.uri(), error.message);
+        // Refused attempts are audited like those the routes answer themselves
+        let error = match (req.rocket().state::<Arc<PublicGotham>>(), customer_of(req)) {
+            (Some(gotham), Some(customer_id)) => {
+                let route = req.route().and_then(|route| route.name.as_deref()).unwrap_or("unnamed");
+                let id = req.param::<&str>(0).and_then(Result::ok);
+                let event = AuditEvent::new(customer_id, "refuse_key", customer_id, id, json!({ "route": route }));
+                audited(gotham, event, Err::<(), _>(error)).await.unwrap_err()
+            }
+            _ => error,
+        };
+        Outcome::from(req, error)
+    }
+}
+
+/// Guards routes whose first dynamic segment is the id of the key whose share they use.
+pub fn guarded(routes: Vec<Route>) -> Vec<Route> {
+    routes
+        .into_iter()
+        .map(|mut route| {
+            route.handler = Box::new(KeyGuard(route.handler));
+            route
+        })
+        .collect()
+}
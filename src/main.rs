@@ -1,25 +1,21 @@
-mod server;
-mod public_gotham;
+use std::process::exit;
 
-use std::collections::HashMap;
+use public_server_lib::server::{get_server, get_settings_as_map};
+use public_server_lib::telemetry;
 
-#[rocket::launch]
-fn rocket() -> _ {
+#[rocket::main]
+async fn main() {
     let settings = get_settings_as_map();
-    crate::server::get_server(settings)
-}
-
-fn get_settings_as_map() -> HashMap<String, String> {
-    let config_file = include_str!("../Settings.toml");
-    let mut settings = config::Config::default();
-    settings
-        .merge(config::File::from_str(
-            config_file,
-            config::FileFormat::Toml,
-        ))
-        .unwrap()
-        .merge(config::Environment::new())
-        .unwrap();
-
-    settings.try_into::<HashMap<String, String>>().unwrap()
+    telemetry::init(&settings);
+    let server = match get_server(settings) {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            exit(1);
+        }
+    };
+    if let Err(e) = server.launch().await {
+        eprintln!("Server failed: {}", e);
+        exit(1);
+    }
 }
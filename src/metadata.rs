@@ -0,0 +1,207 @@
+//!Public information about a customer's keys: joint public key, chain code, metadata and listing
+
+use std::collections::BTreeMap;
+use std::string::String;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use rocket::serde::json::{json, Json, Value};
+use rocket::{get, put, State};
+use serde::{Deserialize, Serialize};
+
+use gotham_engine::types::DbIndex;
+use two_party_ecdsa::curv::elliptic::curves::traits::ECPoint;
+use two_party_ecdsa::kms::ecdsa::two_party::MasterKey1;
+use two_party_ecdsa::party_one::Converter;
+use two_party_ecdsa::BigInt;
+
+use crate::api_error::{ApiError, ErrorCode};
+use crate::auth::AuthenticatedCustomer;
+use crate::error::StorageError;
+use crate::keys::validate_id;
+use crate::public_gotham::PublicGotham;
+
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 500;
+const MAX_LABELS: usize = 32;
+const MAX_LABEL_KEY_LEN: usize = 64;
+const MAX_LABEL_VALUE_LEN: usize = 256;
+
+/// Written when a keygen completes. Keys generated before it was introduced have none.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KeyMetadata {
+    pub created_at: Option<DateTime<Utc>>,
+    pub rotated_at: Option<DateTime<Utc>>,
+    /// Signing with the key is refused once set.
+    pub deactivated_at: Option<DateTime<Utc>>,
+    /// Free form labels set by the customer.
+    pub labels: BTreeMap<String, String>,
+}
+
+/// Secondary index of the ids a customer owns, in keygen completion order, stored as one
+/// record per customer so that it works on any backend.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct KeyIndex {
+    pub ids: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyStatus {
+    Active,
+    /// Superseded by a later keygen under the `replace` share policy.
+    Replaced,
+    /// Refuses to sign, awaiting deletion.
+    Deactivated,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KeySummary {
+    pub id: String,
+    pub created_at: Option<DateTime<Utc>>,
+    pub status: KeyStatus,
+    pub labels: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KeyPage {
+    pub keys: Vec<KeySummary>,
+    /// Pass as `after` to fetch the next page, absent on the last one.
+    pub next: Option<String>,
+}
+
+fn validate_labels(labels: &BTreeMap<String, String>) -> Result<(), ApiError> {
+    let invalid = labels.len() > MAX_LABELS
+        || labels.iter().any(|(key, value)| {
+            key.is_empty()
+                || key.len() > MAX_LABEL_KEY_LEN
+                || value.len() > MAX_LABEL_VALUE_LEN
+                || key.chars().chain(value.chars()).any(char::is_control)
+        });
+    if invalid {
+        return Err(ApiError::new(
+            ErrorCode::BadRequest,
+            format!(
+                "At most {} labels, with non empty keys of up to {} and values of up to {} characters",
+                MAX_LABELS, MAX_LABEL_KEY_LEN, MAX_LABEL_VALUE_LEN
+            ),
+        ));
+    }
+    Ok(())
+}
+
+fn public_key_json(master_key: &MasterKey1) -> Value {
+    json!({
+        "public_key": master_key.public.q.bytes_compressed_to_big_int().to_hex(),
+        "chain_code": master_key.chain_code.to_hex(),
+    })
+}
+
+fn lookup(
+    gotham: &PublicGotham,
+    customer: &AuthenticatedCustomer,
+    id: &str,
+) -> Result<(MasterKey1, KeyMetadata), ApiError> {
+    validate_id(id).map_err(|e| ApiError::new(ErrorCode::InvalidId, e.to_string()))?;
+    let key = DbIndex {
+        customer_id: customer.customer_id.clone(),
+        id: id.to_string(),
+    };
+    let master_key = gotham
+        .party_one_master_key(&key)
+        .map_err(storage_failure)?
+        .ok_or_else(|| ApiError::new(ErrorCode::UnknownId, format!("Unknown id '{}'", id)))?;
+    let metadata = gotham
+        .key_metadata(&key)
+        .map_err(storage_failure)?
+        .unwrap_or_default();
+    Ok((master_key, metadata))
+}
+
+fn storage_failure(e: StorageError) -> ApiError {
+    log::error!("Key lookup failed: {}", e);
+    ApiError::new(ErrorCode::from(&e), "Storage backend failure")
+}
+
+/// The joint public key and chain code of a key, with its metadata.
+#[get("/ecdsa/keys/<id>")]
+pub fn get_key(
+    gotham: &State<Arc<PublicGotham>>,
+    customer: AuthenticatedCustomer,
+    id: String,
+) -> Result<Json<Value>, ApiError> {
+    let (master_key, metadata) = lookup(gotham, &customer, &id)?;
+    let summary = gotham
+        .describe_key(&DbIndex {
+            customer_id: customer.customer_id.clone(),
+            id: id.clone(),
+        })
+        .map_err(storage_failure)?;
+    let mut key = public_key_json(&master_key);
+    key["id"] = json!(id);
+    key["created_at"] = json!(metadata.created_at);
+    key["rotated_at"] = json!(metadata.rotated_at);
+    key["deactivated_at"] = json!(metadata.deactivated_at);
+    key["status"] = json!(summary.status);
+    key["labels"] = json!(metadata.labels);
+    Ok(Json(key))
+}
+
+/// The public key and chain code of the child key at `x_pos`/`y_pos`, as used by sign requests.
+#[get("/ecdsa/keys/<id>/child?<x_pos>&<y_pos>")]
+pub fn get_child_key(
+    gotham: &State<Arc<PublicGotham>>,
+    customer: AuthenticatedCustomer,
+    id: String,
+    x_pos: u32,
+    y_pos: u32,
+) -> Result<Json<Value>, ApiError> {
+    let (master_key, _) = lookup(gotham, &customer, &id)?;
+    let child = master_key.get_child(vec![BigInt::from(x_pos), BigInt::from(y_pos)]);
+    let mut key = public_key_json(&child);
+    key["id"] = json!(id);
+    key["x_pos"] = json!(x_pos);
+    key["y_pos"] = json!(y_pos);
+    Ok(Json(key))
+}
+
+/// The customer's keys in creation order, `limit` per page starting after the id `after`,
+/// which must be one of them.
+#[get("/ecdsa/keys?<limit>&<after>")]
+pub fn list_keys(
+    gotham: &State<Arc<PublicGotham>>,
+    customer: AuthenticatedCustomer,
+    limit: Option<usize>,
+    after: Option<String>,
+) -> Result<Json<KeyPage>, ApiError> {
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    gotham
+        .list_keys(&customer.customer_id, after.as_deref(), limit)
+        .map_err(storage_failure)?
+        .map(Json)
+        .ok_or_else(|| {
+            let after = after.unwrap_or_default();
+            ApiError::new(ErrorCode::BadRequest, format!("Unknown cursor '{}'", after))
+        })
+}
+
+/// Replaces the labels of a key.
+#[put("/ecdsa/keys/<id>/labels", format = "json", data = "<labels>")]
+pub fn put_labels(
+    gotham: &State<Arc<PublicGotham>>,
+    customer: AuthenticatedCustomer,
+    id: String,
+    labels: Json<BTreeMap<String, String>>,
+) -> Result<Json<KeySummary>, ApiError> {
+    validate_labels(&labels)?;
+    lookup(gotham, &customer, &id)?;
+    let key = DbIndex {
+        customer_id: customer.customer_id.clone(),
+        id,
+    };
+    gotham
+        .set_key_labels(&key, labels.into_inner())
+        .map(Json)
+        .map_err(storage_failure)
+}
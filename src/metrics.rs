@@ -0,0 +1,122 @@
+//!Prometheus metrics of the MPC routes and the Db backend
+
+use std::sync::OnceLock;
+use std::time::Instant;
+
+use prometheus::{
+    exponential_buckets, Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts,
+    Registry, TextEncoder,
+};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::{async_trait, get, Data, Request, Response};
+
+pub struct Metrics {
+    registry: Registry,
+    pub requests: IntCounterVec,
+    pub request_errors: IntCounterVec,
+    pub request_latency: HistogramVec,
+    pub db_latency: HistogramVec,
+    pub keys_created: IntCounter,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+        let requests = IntCounterVec::new(
+            Opts::new("gotham_requests_total", "Requests served, by route and status"),
+            &["route", "status"],
+        )
+        .unwrap();
+        let request_errors = IntCounterVec::new(
+            Opts::new("gotham_request_errors_total", "Requests answered with an error status, by route"),
+            &["route"],
+        )
+        .unwrap();
+        // Protocol steps range from milliseconds (sign) to seconds (keygen proofs)
+        let request_latency = HistogramVec::new(
+            HistogramOpts::new("gotham_request_duration_seconds", "Request latency, by route")
+                .buckets(exponential_buckets(0.001, 2.0, 15).unwrap()),
+            &["route"],
+        )
+        .unwrap();
+        let db_latency = HistogramVec::new(
+            HistogramOpts::new("gotham_db_duration_seconds", "Db call latency, by operation")
+                .buckets(exponential_buckets(0.0001, 2.0, 15).unwrap()),
+            &["operation"],
+        )
+        .unwrap();
+        let keys_created =
+            IntCounter::new("gotham_keys_created_total", "Completed keygens").unwrap();
+
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry.register(Box::new(request_errors.clone())).unwrap();
+        registry.register(Box::new(request_latency.clone())).unwrap();
+        registry.register(Box::new(db_latency.clone())).unwrap();
+        registry.register(Box::new(keys_created.clone())).unwrap();
+
+        Metrics {
+            registry,
+            requests,
+            request_errors,
+            request_latency,
+            db_latency,
+            keys_created,
+        }
+    }
+}
+
+/// Process wide metrics, shared by every server instance.
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::new)
+}
+
+struct RequestStart(Instant);
+
+/// Counts and times every request by the name of the route that served it.
+pub struct MetricsFairing;
+
+#[async_trait]
+impl Fairing for MetricsFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Prometheus metrics",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        req.local_cache(|| RequestStart(Instant::now()));
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let route = req
+            .route()
+            .and_then(|route| route.name.as_deref())
+            .unwrap_or("unmatched");
+        let status = res.status();
+        let elapsed = req.local_cache(|| RequestStart(Instant::now())).0.elapsed();
+
+        let metrics = metrics();
+        metrics
+            .requests
+            .with_label_values(&[route, &status.code.to_string()])
+            .inc();
+        metrics
+            .request_latency
+            .with_label_values(&[route])
+            .observe(elapsed.as_secs_f64());
+        if status.code >= 400 {
+            metrics.request_errors.with_label_values(&[route]).inc();
+        }
+    }
+}
+
+#[get("/metrics")]
+pub fn export() -> String {
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&metrics().registry.gather(), &mut buffer)
+        .expect("Metrics encoding failed");
+    String::from_utf8(buffer).expect("Metrics are not valid UTF-8")
+}
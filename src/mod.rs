@@ -1,4 +0,0 @@
-pub mod public_gotham;
-pub mod server;
-pub mod main;
-pub mod tests;
@@ -0,0 +1,227 @@
+//!Offline inspection of a stopped server's RocksDB key store, as used by `gotham_admin`
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use std::string::String;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use thiserror::Error;
+
+use two_party_ecdsa::party_one::Value;
+
+use crate::encryption::{is_sealed, sealed_key_id, MasterKeys};
+use crate::error::StorageError;
+use crate::keys::decode_key;
+use crate::metadata::{KeyIndex, KeyMetadata};
+use crate::policy::{SigningPolicy, SigningUsage};
+use crate::public_gotham::{
+    idify, legacy_idify, HEALTH_PROBE_TABLE, KEY_INDEX_TABLE, KEY_METADATA_TABLE,
+    RECOVERY_ESCROW_TABLE, ROTATION_SESSION_TABLE, SHARE_REGISTRY_TABLE, SIGNING_POLICY_TABLE,
+    SIGNING_USAGE_TABLE,
+};
+use crate::recovery::RecoveryEscrow;
+use crate::rocksdb_store::RocksDbStore;
+use crate::rotate::RotationSession;
+use crate::shares::ShareRegistry;
+
+/// Tables keyed by customer id alone.
+const CUSTOMER_TABLES: &[&str] = &[SHARE_REGISTRY_TABLE, SIGNING_POLICY_TABLE, KEY_INDEX_TABLE];
+
+/// Per key tables whose records hold no secret, so that they may be exported unsealed.
+const NON_SECRET_TABLES: &[&str] = &[KEY_METADATA_TABLE, SIGNING_USAGE_TABLE, RECOVERY_ESCROW_TABLE];
+
+#[derive(Debug, Error)]
+pub enum ExportError {
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+    /// Stores without a master key hold party one's share in the clear.
+    #[error("Records of {} are not sealed and hold secrets, pass --include-secrets to export them", .0.join(", "))]
+    PlaintextSecrets(Vec<String>),
+}
+
+/// What is known of a stored record without opening it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RecordInfo {
+    pub table: String,
+    pub bytes: usize,
+    /// Master key the record is sealed under, none for plaintext records.
+    pub sealed_with: Option<String>,
+}
+
+/// A record that does not open or deserialize.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Problem {
+    pub table: String,
+    pub key: String,
+    pub error: String,
+}
+
+/// The records of a key as stored, still sealed under the master key they were written with.
+#[derive(Debug, Serialize)]
+pub struct KeyExport {
+    pub customer_id: String,
+    pub id: String,
+    pub exported_at: DateTime<Utc>,
+    pub records: BTreeMap<String, String>,
+}
+
+/// The customer and, for per key records, the key id a record of `table` is stored for.
+pub fn owner_of(table: &str, key: &str) -> (String, Option<String>) {
+    match decode_key(key).as_deref() {
+        Some([customer_id]) => (customer_id.clone(), None),
+        Some([customer_id, id]) => (customer_id.clone(), Some(id.clone())),
+        // Written before keys were length prefixed
+        _ if CUSTOMER_TABLES.contains(&table) => (key.to_string(), None),
+        _ => match key.rsplit_once('_') {
+            Some((customer_id, id)) => (customer_id.to_string(), Some(id.to_string())),
+            None => (key.to_string(), None),
+        },
+    }
+}
+
+/// Deserializes a record as its table's type, discarding the result.
+fn check_record(table: &str, plaintext: &str) -> Result<(), serde_json::Error> {
+    match table {
+        SHARE_REGISTRY_TABLE => serde_json::from_str::<ShareRegistry>(plaintext).map(drop),
+        SIGNING_POLICY_TABLE => serde_json::from_str::<SigningPolicy>(plaintext).map(drop),
+        SIGNING_USAGE_TABLE => serde_json::from_str::<SigningUsage>(plaintext).map(drop),
+        ROTATION_SESSION_TABLE => serde_json::from_str::<RotationSession>(plaintext).map(drop),
+        KEY_METADATA_TABLE => serde_json::from_str::<KeyMetadata>(plaintext).map(drop),
+        KEY_INDEX_TABLE => serde_json::from_str::<KeyIndex>(plaintext).map(drop),
+        RECOVERY_ESCROW_TABLE => serde_json::from_str::<RecoveryEscrow>(plaintext).map(drop),
+        // Engine records and staged rotated master keys
+        _ => serde_json::from_str::<Box<dyn Value>>(plaintext).map(drop),
+    }
+}
+
+/// Read-only view of a key store. Never writes, so it neither migrates legacy records nor
+/// re-encrypts them like the server does on read.
+pub struct OfflineStore {
+    store: RocksDbStore,
+    master_keys: Option<MasterKeys>,
+}
+
+impl OfflineStore {
+    pub fn open_read_only<P: AsRef<Path>>(path: P, master_keys: Option<MasterKeys>) -> Result<Self, StorageError> {
+        Ok(OfflineStore {
+            store: RocksDbStore::open_read_only(path)?,
+            master_keys,
+        })
+    }
+
+    fn records_of(&self) -> Result<impl Iterator<Item = (String, String)>, StorageError> {
+        Ok(self
+            .store
+            .keys()?
+            .into_iter()
+            .filter(|(table, _)| table != HEALTH_PROBE_TABLE))
+    }
+
+    /// Every customer with the ids of the keys they have records of.
+    pub fn customers(&self) -> Result<BTreeMap<String, BTreeSet<String>>, StorageError> {
+        let mut customers: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+        for (table, key) in self.records_of()? {
+            let (customer_id, id) = owner_of(&table, &key);
+            let ids = customers.entry(customer_id).or_default();
+            ids.extend(id);
+        }
+        Ok(customers)
+    }
+
+    /// The tables holding a record of the key.
+    pub fn records(&self, customer_id: &str, id: &str) -> Result<Vec<RecordInfo>, StorageError> {
+        let mut records = Vec::new();
+        for table in self.store.tables()? {
+            if table == HEALTH_PROBE_TABLE {
+                continue;
+            }
+            if let Some(stored) = self.stored(&table, customer_id, id)? {
+                records.push(RecordInfo {
+                    bytes: stored.len(),
+                    sealed_with: sealed_key_id(&stored).map(str::to_string),
+                    table,
+                });
+            }
+        }
+        Ok(records)
+    }
+
+    fn stored(&self, table: &str, customer_id: &str, id: &str) -> Result<Option<String>, StorageError> {
+        let stored = match self.store.get(table, idify(customer_id.to_string(), id.to_string()))? {
+            Some(stored) => Some(stored),
+            None => self.store.get(table, legacy_idify(customer_id, id))?,
+        };
+        stored.map(String::from_utf8).transpose().map_err(StorageError::from)
+    }
+
+    fn open(&self, table: &str, key: &str, stored: String) -> Result<String, StorageError> {
+        match &self.master_keys {
+            Some(master_keys) => Ok(master_keys.open(table, key, &stored)?.0),
+            None if is_sealed(&stored) => Err(StorageError::NoMasterKey),
+            None => Ok(stored),
+        }
+    }
+
+    /// Opens and deserializes every record, or those of a customer or a single key, returning
+    /// how many were checked and those that failed.
+    pub fn verify(&self, customer_id: Option<&str>, id: Option<&str>) -> Result<(usize, Vec<Problem>), StorageError> {
+        let (mut checked, mut problems) = (0, Vec::new());
+        for (table, key) in self.records_of()? {
+            let (owner, key_id) = owner_of(&table, &key);
+            if customer_id.map_or(false, |customer_id| customer_id != owner)
+                || id.map_or(false, |id| Some(id) != key_id.as_deref())
+            {
+                continue;
+            }
+            checked += 1;
+            let checked_record = self
+                .store
+                .get(&table, key.clone())?
+                .map(String::from_utf8)
+                .transpose()
+                .map_err(StorageError::from)
+                .and_then(|stored| match stored {
+                    Some(stored) => self.open(&table, &key, stored),
+                    None => Err(StorageError::MalformedRecord("vanished while reading".to_string())),
+                })
+                .and_then(|plaintext| check_record(&table, &plaintext).map_err(StorageError::from));
+            if let Err(e) = checked_record {
+                problems.push(Problem {
+                    table,
+                    key,
+                    error: e.to_string(),
+                });
+            }
+        }
+        Ok((checked, problems))
+    }
+
+    /// The key's records as stored, none when it has no records. Unsealed records of tables
+    /// holding secrets are refused unless `include_secrets`.
+    pub fn export(&self, customer_id: &str, id: &str, include_secrets: bool) -> Result<Option<KeyExport>, ExportError> {
+        let mut records = BTreeMap::new();
+        for info in self.records(customer_id, id)? {
+            if let Some(stored) = self.stored(&info.table, customer_id, id)? {
+                records.insert(info.table, stored);
+            }
+        }
+        if records.is_empty() {
+            return Ok(None);
+        }
+        let plaintext_secrets: Vec<String> = records
+            .iter()
+            .filter(|(table, stored)| !NON_SECRET_TABLES.contains(&table.as_str()) && !is_sealed(stored))
+            .map(|(table, _)| table.clone())
+            .collect();
+        if !include_secrets && !plaintext_secrets.is_empty() {
+            return Err(ExportError::PlaintextSecrets(plaintext_secrets));
+        }
+        Ok(Some(KeyExport {
+            customer_id: customer_id.to_string(),
+            id: id.to_string(),
+            exported_at: Utc::now(),
+            records,
+        }))
+    }
+}
@@ -0,0 +1,354 @@
+//!Signing policies, evaluated before party one releases its partial signature
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::string::String;
+use std::sync::Arc;
+
+use chrono::{DateTime, Datelike, NaiveTime, Utc, Weekday};
+use rocket::serde::json::{json, Json};
+use rocket::{delete, get, post, put, State};
+use serde::{Deserialize, Serialize};
+
+use gotham_engine::sign::Sign;
+use gotham_engine::types::SignSecondMsgRequest;
+use two_party_ecdsa::{party_one, BigInt};
+
+use crate::admin::{AdminToken, ADMIN_ACTOR};
+use crate::api_error::{blocking, classified, ApiError, ErrorCode};
+use crate::audit::{audited, AuditEvent};
+use crate::auth::AuthenticatedCustomer;
+use crate::keys::validate_customer_id;
+use crate::protocol::{claims, state};
+use crate::public_gotham::{KeyLocks, PublicGotham};
+
+/// A customer's signing rules, applying to each of their keys. The default policy allows everything.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SigningPolicy {
+    /// Signatures per key and UTC day.
+    pub daily_limit: Option<u32>,
+    /// Overrides `daily_limit` for individual key ids.
+    pub key_daily_limits: BTreeMap<String, u32>,
+    /// Child derivation paths that may sign; any path when empty.
+    pub allowed_paths: Vec<PathRule>,
+    /// UTC windows in which signing is allowed; any time when empty.
+    pub time_windows: Vec<TimeWindow>,
+    /// Key ids that may not sign.
+    pub frozen_keys: BTreeSet<String>,
+}
+
+/// Matches `x_pos_child_key`/`y_pos_child_key` of a sign request; an unset position matches any.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PathRule {
+    pub x_pos: Option<u32>,
+    pub y_pos: Option<u32>,
+}
+
+impl PathRule {
+    fn matches(&self, x_pos: &BigInt, y_pos: &BigInt) -> bool {
+        self.x_pos.map_or(true, |x| BigInt::from(x) == *x_pos)
+            && self.y_pos.map_or(true, |y| BigInt::from(y) == *y_pos)
+    }
+}
+
+/// From `start` (inclusive) to `end` (exclusive), wrapping past midnight when `end` is
+/// earlier than `start`, on the given weekdays or every day when empty. Times are "HH:MM:SS".
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TimeWindow {
+    #[serde(default)]
+    pub weekdays: Vec<Weekday>,
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl TimeWindow {
+    fn contains(&self, now: DateTime<Utc>) -> bool {
+        let time = now.time();
+        let (day, in_window) = if self.start <= self.end {
+            (now.weekday(), self.start <= time && time < self.end)
+        } else if time >= self.start {
+            (now.weekday(), true)
+        } else {
+            // Past midnight, the window opened the day before
+            (now.weekday().pred(), time < self.end)
+        };
+        in_window && (self.weekdays.is_empty() || self.weekdays.contains(&day))
+    }
+}
+
+/// Signatures released by a key on a UTC day, stored per key.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SigningUsage {
+    pub day: String,
+    pub count: u32,
+}
+
+/// A sign request as seen by the rules.
+pub struct SignAttempt<'a> {
+    pub customer_id: &'a str,
+    pub id: &'a str,
+    pub message: &'a BigInt,
+    pub x_pos: &'a BigInt,
+    pub y_pos: &'a BigInt,
+    pub now: DateTime<Utc>,
+    /// Signatures already released for the key on the current UTC day.
+    pub signed_today: u32,
+}
+
+/// A check of sign requests against the customer's policy, returning why it refuses one.
+pub trait SigningRule: Send + Sync {
+    fn check(&self, policy: &SigningPolicy, attempt: &SignAttempt) -> Result<(), String>;
+}
+
+pub struct FrozenKeys;
+
+impl SigningRule for FrozenKeys {
+    fn check(&self, policy: &SigningPolicy, attempt: &SignAttempt) -> Result<(), String> {
+        if policy.frozen_keys.contains(attempt.id) {
+            return Err(format!("Key {} is frozen", attempt.id));
+        }
+        Ok(())
+    }
+}
+
+pub struct DailyLimit;
+
+impl SigningRule for DailyLimit {
+    fn check(&self, policy: &SigningPolicy, attempt: &SignAttempt) -> Result<(), String> {
+        let limit = policy.key_daily_limits.get(attempt.id).copied().or(policy.daily_limit);
+        match limit {
+            Some(limit) if attempt.signed_today >= limit => Err(format!(
+                "Key {} reached its daily limit of {} signatures",
+                attempt.id, limit
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+pub struct AllowedPaths;
+
+impl SigningRule for AllowedPaths {
+    fn check(&self, policy: &SigningPolicy, attempt: &SignAttempt) -> Result<(), String> {
+        if policy.allowed_paths.is_empty()
+            || policy
+                .allowed_paths
+                .iter()
+                .any(|rule| rule.matches(attempt.x_pos, attempt.y_pos))
+        {
+            return Ok(());
+        }
+        Err(format!("Derivation path {}/{} is not allowed", attempt.x_pos, attempt.y_pos))
+    }
+}
+
+pub struct TimeWindows;
+
+impl SigningRule for TimeWindows {
+    fn check(&self, policy: &SigningPolicy, attempt: &SignAttempt) -> Result<(), String> {
+        if policy.time_windows.is_empty()
+            || policy.time_windows.iter().any(|window| window.contains(attempt.now))
+        {
+            return Ok(());
+        }
+        Err("Signing is not allowed at this time".to_string())
+    }
+}
+
+/// The rules every sign request must pass. Deployments may plug in their own with `with_rule`.
+pub struct PolicyEngine {
+    rules: Vec<Box<dyn SigningRule>>,
+}
+
+impl Default for PolicyEngine {
+    fn default() -> Self {
+        PolicyEngine {
+            rules: vec![
+                Box::new(FrozenKeys),
+                Box::new(TimeWindows),
+                Box::new(AllowedPaths),
+                Box::new(DailyLimit),
+            ],
+        }
+    }
+}
+
+impl PolicyEngine {
+    pub fn with_rule<R: SigningRule + 'static>(mut self, rule: R) -> Self {
+        self.rules.push(Box::new(rule));
+        self
+    }
+
+    /// The first rule refusing the attempt, if any.
+    pub fn evaluate(&self, policy: &SigningPolicy, attempt: &SignAttempt) -> Result<(), String> {
+        self.rules
+            .iter()
+            .try_for_each(|rule| rule.check(policy, attempt))
+    }
+}
+
+/// Replaces the engine's `wrap_sign_second`, under the same name, so that the customer's
+/// policy is enforced before the partial signature is computed, and only signatures actually
+/// released count against it. Every attempt is audited, and a signature is only released
+/// once recorded, so that every signature in the wild is in the audit log.
+#[post("/ecdsa/sign/<id>/second", format = "json", data = "<request>")]
+pub async fn wrap_sign_second(
+    gotham: &State<Arc<PublicGotham>>,
+    locks: &State<KeyLocks>,
+    engine: &State<Arc<PolicyEngine>>,
+    customer: AuthenticatedCustomer,
+    id: String,
+    request: Json<SignSecondMsgRequest>,
+) -> Result<Json<party_one::SignatureRecid>, ApiError> {
+    let mut event = AuditEvent::new(
+        &customer.customer_id,
+        "sign",
+        &customer.customer_id,
+        Some(&id),
+        json!({
+            "message": request.message.to_hex(),
+            "x_pos": request.x_pos_child_key.to_string(),
+            "y_pos": request.y_pos_child_key.to_string(),
+        }),
+    );
+    let now = Utc::now();
+    let authorized = authorize(gotham, engine, &customer.customer_id, &id, &request, now).await;
+    let signed = match &authorized {
+        Ok(()) => sign_second(gotham, locks, &customer.customer_id, &id, request).await,
+        Err(error) => Err(error.clone()),
+    };
+    if let Ok(signature) = &signed {
+        event.details["signature"] = json!({
+            "r": signature.r.to_hex(),
+            "s": signature.s.to_hex(),
+            "recid": signature.recid,
+        });
+    }
+    let released = audited(gotham, event, signed).await;
+
+    if authorized.is_ok() && released.is_err() {
+        let gotham = gotham.inner().clone();
+        let (customer_id, id) = (customer.customer_id.clone(), id.clone());
+        let given_back = blocking(move || gotham.release_signature(&customer_id, &id, now)).await?;
+        if let Err(e) = given_back {
+            log::error!("Unable to give back the signature reserved for key {}: {}", id, e);
+        }
+    }
+    released
+}
+
+/// Evaluates the customer's policy, reserving a signature of the key's daily allowance.
+async fn authorize(
+    gotham: &State<Arc<PublicGotham>>,
+    engine: &State<Arc<PolicyEngine>>,
+    customer_id: &str,
+    id: &str,
+    request: &SignSecondMsgRequest,
+    now: DateTime<Utc>,
+) -> Result<(), ApiError> {
+    let (gotham, engine) = (gotham.inner().clone(), engine.inner().clone());
+    let (customer_id, id) = (customer_id.to_string(), id.to_string());
+    let (message, x_pos, y_pos) = (
+        request.message.clone(),
+        request.x_pos_child_key.clone(),
+        request.y_pos_child_key.clone(),
+    );
+    let authorized = blocking(move || {
+        gotham.authorize_signature(&customer_id, &id, now, |policy, signed_today| {
+            let attempt = SignAttempt {
+                customer_id: &customer_id,
+                id: &id,
+                message: &message,
+                x_pos: &x_pos,
+                y_pos: &y_pos,
+                now,
+                signed_today,
+            };
+            engine.evaluate(policy, &attempt)
+        })
+    })
+    .await?;
+    match authorized {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(reason)) => {
+            log::warn!("Refused to sign: {}", reason);
+            Err(ApiError::new(ErrorCode::PolicyViolation, reason))
+        }
+        Err(e) => {
+            log::error!("Signing policy lookup failed: {}", e);
+            Err(ApiError::new(ErrorCode::from(&e), "Signing policy lookup failed"))
+        }
+    }
+}
+
+async fn sign_second(
+    gotham: &State<Arc<PublicGotham>>,
+    locks: &State<KeyLocks>,
+    customer_id: &str,
+    id: &str,
+    request: Json<SignSecondMsgRequest>,
+) -> Result<Json<party_one::SignatureRecid>, ApiError> {
+    let lock = locks.of(customer_id, id);
+    classified(
+        Some(id),
+        gotham.inner().as_ref().sign_second(state(&lock), claims(customer_id), id.to_string(), request),
+    )
+    .await
+}
+
+#[get("/admin/policies/<customer_id>")]
+pub fn get_policy(
+    _admin: AdminToken,
+    gotham: &State<Arc<PublicGotham>>,
+    customer_id: String,
+) -> Result<Json<SigningPolicy>, ApiError> {
+    validate_customer_id(&customer_id).map_err(invalid)?;
+    gotham
+        .signing_policy(&customer_id)
+        .map(Json)
+        .map_err(storage_failure)
+}
+
+#[put("/admin/policies/<customer_id>", format = "json", data = "<policy>")]
+pub fn put_policy(
+    _admin: AdminToken,
+    gotham: &State<Arc<PublicGotham>>,
+    customer_id: String,
+    policy: Json<SigningPolicy>,
+) -> Result<Json<SigningPolicy>, ApiError> {
+    validate_customer_id(&customer_id).map_err(invalid)?;
+    gotham
+        .set_signing_policy(&customer_id, &policy)
+        .map_err(storage_failure)?;
+    gotham
+        .audit(AuditEvent::new(ADMIN_ACTOR, "put_policy", &customer_id, None, json!(&*policy)))
+        .map_err(storage_failure)?;
+    log::info!("Updated the signing policy of customer {}", customer_id);
+    Ok(policy)
+}
+
+#[delete("/admin/policies/<customer_id>")]
+pub fn delete_policy(
+    _admin: AdminToken,
+    gotham: &State<Arc<PublicGotham>>,
+    customer_id: String,
+) -> Result<Json<SigningPolicy>, ApiError> {
+    validate_customer_id(&customer_id).map_err(invalid)?;
+    gotham
+        .delete_signing_policy(&customer_id)
+        .map_err(storage_failure)?;
+    gotham
+        .audit(AuditEvent::new(ADMIN_ACTOR, "delete_policy", &customer_id, None, json!({})))
+        .map_err(storage_failure)?;
+    log::info!("Removed the signing policy of customer {}", customer_id);
+    Ok(Json(SigningPolicy::default()))
+}
+
+fn invalid(e: crate::error::StorageError) -> ApiError {
+    ApiError::new(ErrorCode::InvalidId, e.to_string())
+}
+
+fn storage_failure(e: crate::error::StorageError) -> ApiError {
+    log::error!("Signing policy storage failed: {}", e);
+    ApiError::new(ErrorCode::StorageError, "Storage backend failure")
+}
@@ -0,0 +1,173 @@
+//!The engine's keygen and sign first routes, under the same names: each step takes the lock
+//!of its own key rather than one shared by every request, and is classified and audited
+
+use std::string::String;
+use std::sync::Arc;
+
+use rocket::serde::json::{json, Json};
+use rocket::{post, State};
+
+use gotham_engine::keygen::KeyGen;
+use gotham_engine::sign::Sign;
+use gotham_engine::types::{Claims, DbIndex};
+use two_party_ecdsa::curv::cryptographic_primitives::proofs::sigma_dlog::DLogProof;
+use two_party_ecdsa::curv::cryptographic_primitives::twoparty::dh_key_exchange_variant_with_pok_comm::{
+    Party1FirstMessage, Party1SecondMessage,
+};
+use two_party_ecdsa::kms::ecdsa::two_party::party1;
+use two_party_ecdsa::{party_one, party_two};
+
+use crate::api_error::{blocking, classified, ApiError};
+use crate::audit::{audited, AuditEvent};
+use crate::auth::AuthenticatedCustomer;
+use crate::public_gotham::{DbLock, KeyLocks, PublicGotham};
+
+/// Every step is audited, see `audited`.
+fn event(customer: &AuthenticatedCustomer, action: &str, id: Option<&str>) -> AuditEvent {
+    AuditEvent::new(&customer.customer_id, action, &customer.customer_id, id, json!({}))
+}
+
+/// The engine's claims for the authenticated customer. The engine keys its records by the
+/// claims' subject, which must be the customer every other route knows, never one the engine
+/// reads from the request itself.
+pub(crate) fn claims(customer_id: &str) -> Claims {
+    Claims {
+        sub: customer_id.to_string(),
+        // The token was validated by the customer guard, the engine does not look at it again
+        exp: usize::MAX,
+    }
+}
+
+/// The engine takes its Db as managed state; a key's lock is handed over as if it were.
+pub(crate) fn state(lock: &DbLock) -> &State<DbLock> {
+    <&State<DbLock>>::from(lock)
+}
+
+/// Runs storage work on a key's records off the async workers, taking turns with the engine's
+/// steps on the same key.
+pub(crate) async fn locked<T, F>(locks: &KeyLocks, key: DbIndex, work: F) -> Result<T, ApiError>
+where
+    T: Send + 'static,
+    F: FnOnce(DbIndex) -> T + Send + 'static,
+{
+    let lock = locks.of(&key.customer_id, &key.id);
+    let _turn = lock.lock().await;
+    blocking(move || work(key)).await
+}
+
+#[post("/ecdsa/keygen/first")]
+pub async fn wrap_keygen_first(
+    gotham: &State<Arc<PublicGotham>>,
+    locks: &State<KeyLocks>,
+    customer: AuthenticatedCustomer,
+) -> Result<Json<(String, party_one::KeyGenFirstMsg)>, ApiError> {
+    let lock = locks.unshared();
+    let outcome = classified(None, gotham.inner().as_ref().first(state(&lock), claims(&customer.customer_id))).await;
+    // The first message answers with the id it drew
+    let id = outcome.as_ref().ok().map(|reply| reply.0 .0.clone());
+    audited(gotham, event(&customer, "wrap_keygen_first", id.as_deref()), outcome).await
+}
+
+#[post("/ecdsa/keygen/<id>/second", format = "json", data = "<dlog_proof>")]
+pub async fn wrap_keygen_second(
+    gotham: &State<Arc<PublicGotham>>,
+    locks: &State<KeyLocks>,
+    customer: AuthenticatedCustomer,
+    id: String,
+    dlog_proof: Json<DLogProof>,
+) -> Result<Json<party1::KeyGenParty1Message2>, ApiError> {
+    let lock = locks.of(&customer.customer_id, &id);
+    let outcome = classified(
+        Some(&id),
+        gotham.inner().as_ref().second(state(&lock), claims(&customer.customer_id), id.clone(), dlog_proof),
+    )
+    .await;
+    audited(gotham, event(&customer, "wrap_keygen_second", Some(&id)), outcome).await
+}
+
+#[post("/ecdsa/keygen/<id>/third", format = "json", data = "<pdl_first_message>")]
+pub async fn wrap_keygen_third(
+    gotham: &State<Arc<PublicGotham>>,
+    locks: &State<KeyLocks>,
+    customer: AuthenticatedCustomer,
+    id: String,
+    pdl_first_message: Json<party_two::PDLFirstMessage>,
+) -> Result<Json<party_one::PDLFirstMessage>, ApiError> {
+    let lock = locks.of(&customer.customer_id, &id);
+    let outcome = classified(
+        Some(&id),
+        gotham.inner().as_ref().third(state(&lock), claims(&customer.customer_id), id.clone(), pdl_first_message),
+    )
+    .await;
+    audited(gotham, event(&customer, "wrap_keygen_third", Some(&id)), outcome).await
+}
+
+#[post("/ecdsa/keygen/<id>/fourth", format = "json", data = "<pdl_second_message>")]
+pub async fn wrap_keygen_fourth(
+    gotham: &State<Arc<PublicGotham>>,
+    locks: &State<KeyLocks>,
+    customer: AuthenticatedCustomer,
+    id: String,
+    pdl_second_message: Json<party_two::PDLSecondMessage>,
+) -> Result<Json<party_one::PDLSecondMessage>, ApiError> {
+    let lock = locks.of(&customer.customer_id, &id);
+    let outcome = classified(
+        Some(&id),
+        gotham.inner().as_ref().fourth(state(&lock), claims(&customer.customer_id), id.clone(), pdl_second_message),
+    )
+    .await;
+    audited(gotham, event(&customer, "wrap_keygen_fourth", Some(&id)), outcome).await
+}
+
+#[post("/ecdsa/keygen/<id>/chaincode/first")]
+pub async fn wrap_chain_code_first_message(
+    gotham: &State<Arc<PublicGotham>>,
+    locks: &State<KeyLocks>,
+    customer: AuthenticatedCustomer,
+    id: String,
+) -> Result<Json<Party1FirstMessage>, ApiError> {
+    let lock = locks.of(&customer.customer_id, &id);
+    let outcome = classified(
+        Some(&id),
+        gotham.inner().as_ref().chain_code_first_message(state(&lock), claims(&customer.customer_id), id.clone()),
+    )
+    .await;
+    audited(gotham, event(&customer, "wrap_chain_code_first_message", Some(&id)), outcome).await
+}
+
+#[post("/ecdsa/keygen/<id>/chaincode/second", format = "json", data = "<dlog_proof>")]
+pub async fn wrap_chain_code_second_message(
+    gotham: &State<Arc<PublicGotham>>,
+    locks: &State<KeyLocks>,
+    customer: AuthenticatedCustomer,
+    id: String,
+    dlog_proof: Json<DLogProof>,
+) -> Result<Json<Party1SecondMessage>, ApiError> {
+    let lock = locks.of(&customer.customer_id, &id);
+    let outcome = classified(
+        Some(&id),
+        gotham
+            .inner()
+            .as_ref()
+            .chain_code_second_message(state(&lock), claims(&customer.customer_id), id.clone(), dlog_proof),
+    )
+    .await;
+    audited(gotham, event(&customer, "wrap_chain_code_second_message", Some(&id)), outcome).await
+}
+
+#[post("/ecdsa/sign/<id>/first", format = "json", data = "<eph_first_message>")]
+pub async fn wrap_sign_first(
+    gotham: &State<Arc<PublicGotham>>,
+    locks: &State<KeyLocks>,
+    customer: AuthenticatedCustomer,
+    id: String,
+    eph_first_message: Json<party_two::EphKeyGenFirstMsg>,
+) -> Result<Json<party_one::EphKeyGenFirstMsg>, ApiError> {
+    let lock = locks.of(&customer.customer_id, &id);
+    let outcome = classified(
+        Some(&id),
+        gotham.inner().as_ref().sign_first(state(&lock), claims(&customer.customer_id), id.clone(), eph_first_message),
+    )
+    .await;
+    audited(gotham, event(&customer, "sign_first", Some(&id)), outcome).await
+}
@@ -1,9 +1,16 @@
 //!Public gotham implementation
 
 use rocket::async_trait;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
 use std::string::String;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
 
+use chrono::{DateTime, Utc};
+
+use two_party_ecdsa::curv::GE;
+use two_party_ecdsa::kms::ecdsa::two_party::MasterKey1;
 use two_party_ecdsa::party_one::Value;
 
 use gotham_engine::keygen::KeyGen;
@@ -11,52 +18,656 @@ use gotham_engine::sign::Sign;
 use gotham_engine::traits::*;
 use gotham_engine::types::*;
 
+use crate::api_error::Lookups;
+use crate::audit::{AuditEvent, AuditLog, AuditRecord};
+use crate::backup::BackupError;
+use crate::encryption::{is_sealed, MasterKeys};
+use crate::error::StorageError;
+use crate::expiry::SessionTtl;
+use crate::keys::{encode_key, validate_customer_id, validate_id};
+use crate::metadata::{KeyIndex, KeyMetadata, KeyPage, KeyStatus, KeySummary};
+use crate::metrics::metrics;
+use crate::policy::{SigningPolicy, SigningUsage};
+use crate::recovery::{EscrowChoice, RecoveryEscrow};
+use crate::redis_store::RedisStore;
+use crate::rotate::{RotationSession, RotationStore};
+use crate::rocksdb_store::RocksDbStore;
+use crate::shares::{SharePolicy, ShareRegistry};
+
 
 pub struct PublicGotham {
-    rocksdb_client: rocksdb::DB,
+    db: DB,
+    master_keys: Option<MasterKeys>,
+    share_policy: SharePolicy,
+    session_ttl: SessionTtl,
+    audit_log: AuditLog,
+    recovery_key: Option<GE>,
+    /// Serializes the signing usage updates of each customer.
+    usage_locks: NamedLocks,
+    /// Serializes updates of the per customer share registry and key index.
+    customer_records_lock: Mutex<()>,
 }
 
 pub struct Config {
     pub db: DB,
+    /// Records are stored in plaintext when no master key is configured.
+    pub master_keys: Option<MasterKeys>,
+    pub share_policy: SharePolicy,
+    pub session_ttl: SessionTtl,
+    /// Kept apart from `db`, whatever its backend.
+    pub audit_log: AuditLog,
+    /// Party one's share of every completed keygen is escrowed under it when set.
+    pub recovery_key: Option<GE>,
 }
 
+/// Storage backend selected by the `db` setting.
 pub enum DB {
-    Local(rocksdb::DB),
+    Local(RocksDbStore),
+    Redis(RedisStore),
 }
 
-fn get_settings_as_map() -> HashMap<String, String> {
-    let config_file = include_str!("../Settings.toml");
-    let mut settings = config::Config::default();
-    settings
-        .merge(config::File::from_str(
-            config_file,
-            config::FileFormat::Toml,
-        ))
-        .unwrap()
-        .merge(config::Environment::new())
-        .unwrap();
+impl DB {
+    /// RocksDB stamps ephemeral records for the sweeper, redis expires them natively after `ttl`.
+    fn put(&self, table: &str, key: String, value: String, ttl: Option<Duration>) -> Result<(), StorageError> {
+        match (self, ttl) {
+            (DB::Local(rocksdb_client), _) => rocksdb_client.put(table, key, value)?,
+            // SETEX refuses a zero expiry
+            (DB::Redis(redis_client), Some(ttl)) => {
+                redis_client.set_ex(table, &key, value, ttl.as_secs().max(1) as usize)?
+            }
+            (DB::Redis(redis_client), None) => redis_client.set(table, &key, value)?,
+        }
+        Ok(())
+    }
 
-    settings.try_into::<HashMap<String, String>>().unwrap()
+    fn get(&self, table: &str, key: String) -> Result<Option<Vec<u8>>, StorageError> {
+        match self {
+            DB::Local(rocksdb_client) => rocksdb_client.get(table, key),
+            DB::Redis(redis_client) => Ok(redis_client.get(table, &key)?.map(String::into_bytes)),
+        }
+    }
+
+    fn delete(&self, table: &str, key: String) -> Result<(), StorageError> {
+        match self {
+            DB::Local(rocksdb_client) => rocksdb_client.delete(table, key)?,
+            DB::Redis(redis_client) => {
+                redis_client.del(table, &key)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes the records of a key stored under any of `keys`, returning how many.
+    fn purge(&self, keys: &[String]) -> Result<usize, StorageError> {
+        match self {
+            DB::Local(rocksdb_client) => rocksdb_client.purge(keys),
+            // Looked up table by table, SCAN being neither routed across a cluster nor cheap
+            DB::Redis(redis_client) => {
+                let mut purged = 0;
+                for table in key_tables() {
+                    for key in keys {
+                        if redis_client.del(&table, key)? {
+                            purged += 1;
+                        }
+                    }
+                }
+                Ok(purged)
+            }
+        }
+    }
+
+    /// Every (table, key) pair in the store.
+    fn keys(&self) -> Result<Vec<(String, String)>, StorageError> {
+        match self {
+            DB::Local(rocksdb_client) => rocksdb_client.keys(),
+            DB::Redis(redis_client) => Ok(redis_client.keys()?),
+        }
+    }
 }
 
 impl PublicGotham {
-    pub fn new() -> Self {
-        let settings = get_settings_as_map();
-        let db_name = settings.get("db_name").unwrap_or(&"db".to_string()).clone();
-        if !db_name.chars().all(|e| char::is_ascii_alphanumeric(&e)) {
-            panic!("DB name is illegal, may only contain alphanumeric characters");
+    pub fn new(config: Config) -> Self {
+        if config.master_keys.is_none() {
+            log::warn!("No master key configured, MPC records are stored unencrypted");
         }
-        let rocksdb_client = rocksdb::DB::open_default(format!("./{}", db_name)).unwrap();
-
         PublicGotham {
-            rocksdb_client,
+            db: config.db,
+            master_keys: config.master_keys,
+            share_policy: config.share_policy,
+            session_ttl: config.session_ttl,
+            audit_log: config.audit_log,
+            recovery_key: config.recovery_key,
+            usage_locks: NamedLocks::default(),
+            customer_records_lock: Mutex::new(()),
+        }
+    }
+
+    fn write_value(&self, key: &DbIndex, table: &str, value: &dyn Value) -> Result<(), StorageError> {
+        validate_customer_id(&key.customer_id).and(validate_id(&key.id))?;
+        let identifier = idify(key.clone().customer_id, key.clone().id);
+        let v_string = serde_json::to_string(&value)?;
+        self.write_record(table, identifier, v_string)?;
+        // The party one master key is the last record written by a successful keygen
+        if table == EcdsaStruct::Party1MasterKey.to_string() {
+            self.record_completed_keygen(key)?;
+            self.escrow_share(key, None)?;
+        }
+        Ok(())
+    }
+
+    fn read_value(&self, key: &DbIndex, table: &str) -> Result<Option<Box<dyn Value>>, StorageError> {
+        validate_customer_id(&key.customer_id).and(validate_id(&key.id))?;
+        let identifier = idify(key.clone().customer_id, key.clone().id);
+        let legacy_identifier = legacy_idify(&key.customer_id, &key.id);
+        match self.read_record(table, identifier, Some(legacy_identifier))? {
+            Some(plaintext) => {
+                let final_val: Box<dyn Value> = serde_json::from_str(plaintext.as_str())?;
+                Ok(Option::from(final_val))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn seal(&self, table: &str, identifier: &str, plaintext: String) -> Result<String, StorageError> {
+        match &self.master_keys {
+            Some(master_keys) => master_keys.seal(table, identifier, &plaintext),
+            None => Ok(plaintext),
+        }
+    }
+
+    /// Returns the record plaintext and whether it should be rewritten under the active key.
+    fn unseal(&self, table: &str, identifier: &str, stored: String) -> Result<(String, bool), StorageError> {
+        match &self.master_keys {
+            Some(master_keys) => master_keys.open(table, identifier, &stored),
+            None if is_sealed(&stored) => Err(StorageError::NoMasterKey),
+            None => Ok((stored, false)),
+        }
+    }
+
+    fn write_record(&self, table: &str, identifier: String, plaintext: String) -> Result<(), StorageError> {
+        let sealed = self.seal(table, &identifier, plaintext)?;
+        self.db.put(table, identifier, sealed, self.session_ttl.ttl(table))
+    }
+
+    /// Reads and decrypts a record, lazily migrating plaintext or rotated records to the active master key.
+    /// A record missing under `identifier` is looked up under its pre length-prefix `legacy_identifier`
+    /// and moved to `identifier` when found.
+    fn read_record(
+        &self,
+        table: &str,
+        identifier: String,
+        legacy_identifier: Option<String>,
+    ) -> Result<Option<String>, StorageError> {
+        let (vec, legacy) = match self.db.get(table, identifier.clone())? {
+            Some(vec) => (vec, None),
+            None => match legacy_identifier {
+                Some(legacy_identifier) => match self.db.get(table, legacy_identifier.clone())? {
+                    Some(vec) => (vec, Some(legacy_identifier)),
+                    None => return Ok(None),
+                },
+                None => return Ok(None),
+            },
+        };
+        let stored_under = legacy.as_deref().unwrap_or(&identifier);
+        let (plaintext, stale) = self.unseal(table, stored_under, String::from_utf8(vec)?)?;
+        if stale || legacy.is_some() {
+            self.write_record(table, identifier, plaintext.clone())?;
+        }
+        if let Some(legacy_identifier) = legacy {
+            self.db.delete(table, legacy_identifier)?;
+        }
+        Ok(Some(plaintext))
+    }
+
+    fn share_registry(&self, customer_id: &str) -> Result<ShareRegistry, StorageError> {
+        validate_customer_id(customer_id)?;
+        match self.read_record(
+            SHARE_REGISTRY_TABLE,
+            encode_key(&[customer_id]),
+            Some(customer_id.to_string()),
+        )? {
+            Some(record) => Ok(serde_json::from_str(&record)?),
+            None => Ok(ShareRegistry::default()),
+        }
+    }
+
+    /// Ids of the customer's completed keygens that have not been replaced.
+    pub fn active_shares(&self, customer_id: &str) -> Result<Vec<String>, StorageError> {
+        Ok(self.share_registry(customer_id)?.active)
+    }
+
+    /// Registers the master key of `key` as an active share of its customer.
+    pub fn record_completed_keygen(&self, key: &DbIndex) -> Result<(), StorageError> {
+        validate_id(&key.id)?;
+        let _customer_records = self.customer_records_lock.lock().unwrap_or_else(|e| e.into_inner());
+        let mut registry = self.share_registry(&key.customer_id)?;
+        registry.complete(key.id.clone(), self.share_policy);
+        self.write_record(
+            SHARE_REGISTRY_TABLE,
+            encode_key(&[&key.customer_id]),
+            serde_json::to_string(&registry)?,
+        )?;
+        let mut index = self.key_index(&key.customer_id)?;
+        if !index.ids.contains(&key.id) {
+            index.ids.push(key.id.clone());
+            self.write_record(
+                KEY_INDEX_TABLE,
+                encode_key(&[&key.customer_id]),
+                serde_json::to_string(&index)?,
+            )?;
+        }
+        if self.key_metadata(key)?.is_none() {
+            let metadata = KeyMetadata {
+                created_at: Some(Utc::now()),
+                ..KeyMetadata::default()
+            };
+            self.set_key_metadata(key, &metadata)?;
+        }
+        metrics().keys_created.inc();
+        Ok(())
+    }
+
+    /// The customer's signing policy, or the default one allowing everything.
+    pub fn signing_policy(&self, customer_id: &str) -> Result<SigningPolicy, StorageError> {
+        validate_customer_id(customer_id)?;
+        match self.read_record(SIGNING_POLICY_TABLE, encode_key(&[customer_id]), None)? {
+            Some(record) => Ok(serde_json::from_str(&record)?),
+            None => Ok(SigningPolicy::default()),
+        }
+    }
+
+    pub fn set_signing_policy(&self, customer_id: &str, policy: &SigningPolicy) -> Result<(), StorageError> {
+        validate_customer_id(customer_id)?;
+        self.write_record(
+            SIGNING_POLICY_TABLE,
+            encode_key(&[customer_id]),
+            serde_json::to_string(policy)?,
+        )
+    }
+
+    pub fn delete_signing_policy(&self, customer_id: &str) -> Result<(), StorageError> {
+        validate_customer_id(customer_id)?;
+        self.db.delete(SIGNING_POLICY_TABLE, encode_key(&[customer_id]))
+    }
+
+    /// Evaluates `check` with the customer's policy and the signatures the key released on
+    /// the UTC day of `now`, reserving one more when it passes; a reservation whose signature
+    /// is not released must be given back with `release_signature`. Requests of a customer are
+    /// serialized so that they cannot overrun a daily limit together.
+    pub fn authorize_signature<F>(
+        &self,
+        customer_id: &str,
+        id: &str,
+        now: DateTime<Utc>,
+        check: F,
+    ) -> Result<Result<(), String>, StorageError>
+    where
+        F: FnOnce(&SigningPolicy, u32) -> Result<(), String>,
+    {
+        validate_customer_id(customer_id).and(validate_id(id))?;
+        let policy = self.signing_policy(customer_id)?;
+        let lock = self.usage_locks.get(customer_id);
+        let _usage = lock.lock().unwrap_or_else(|e| e.into_inner());
+
+        let mut usage = self.signing_usage(customer_id, id, now)?;
+        if let Err(reason) = check(&policy, usage.count) {
+            return Ok(Err(reason));
+        }
+        usage.count += 1;
+        self.set_signing_usage(customer_id, id, &usage)?;
+        Ok(Ok(()))
+    }
+
+    /// Gives back a signature reserved by `authorize_signature` on the UTC day of `now`.
+    pub fn release_signature(&self, customer_id: &str, id: &str, now: DateTime<Utc>) -> Result<(), StorageError> {
+        validate_customer_id(customer_id).and(validate_id(id))?;
+        let lock = self.usage_locks.get(customer_id);
+        let _usage = lock.lock().unwrap_or_else(|e| e.into_inner());
+
+        let mut usage = self.signing_usage(customer_id, id, now)?;
+        if usage.count == 0 {
+            return Ok(());
+        }
+        usage.count -= 1;
+        self.set_signing_usage(customer_id, id, &usage)
+    }
+
+    /// The key's usage on the UTC day of `now`.
+    fn signing_usage(&self, customer_id: &str, id: &str, now: DateTime<Utc>) -> Result<SigningUsage, StorageError> {
+        let identifier = idify(customer_id.to_string(), id.to_string());
+        let day = now.date_naive().to_string();
+        let usage = match self.read_record(SIGNING_USAGE_TABLE, identifier, None)? {
+            Some(record) => serde_json::from_str::<SigningUsage>(&record)?,
+            None => SigningUsage::default(),
+        };
+        if usage.day != day {
+            return Ok(SigningUsage { day, count: 0 });
+        }
+        Ok(usage)
+    }
+
+    fn set_signing_usage(&self, customer_id: &str, id: &str, usage: &SigningUsage) -> Result<(), StorageError> {
+        let identifier = idify(customer_id.to_string(), id.to_string());
+        self.write_record(SIGNING_USAGE_TABLE, identifier, serde_json::to_string(usage)?)
+    }
+
+    /// The party one master key of a completed keygen.
+    pub fn party_one_master_key(&self, key: &DbIndex) -> Result<Option<MasterKey1>, StorageError> {
+        let value = self.read_value(key, &EcdsaStruct::Party1MasterKey.to_string())?;
+        Ok(value.and_then(|value| value.as_any().downcast_ref::<MasterKey1>().cloned()))
+    }
+
+    pub fn key_metadata(&self, key: &DbIndex) -> Result<Option<KeyMetadata>, StorageError> {
+        validate_customer_id(&key.customer_id).and(validate_id(&key.id))?;
+        let identifier = idify(key.customer_id.clone(), key.id.clone());
+        match self.read_record(KEY_METADATA_TABLE, identifier, None)? {
+            Some(record) => Ok(Some(serde_json::from_str(&record)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn set_key_metadata(&self, key: &DbIndex, metadata: &KeyMetadata) -> Result<(), StorageError> {
+        let identifier = idify(key.customer_id.clone(), key.id.clone());
+        self.write_record(KEY_METADATA_TABLE, identifier, serde_json::to_string(metadata)?)
+    }
+
+    /// Ids of the customer's keys in keygen completion order.
+    fn key_index(&self, customer_id: &str) -> Result<KeyIndex, StorageError> {
+        validate_customer_id(customer_id)?;
+        match self.read_record(KEY_INDEX_TABLE, encode_key(&[customer_id]), None)? {
+            Some(record) => Ok(serde_json::from_str(&record)?),
+            // Keys completed before the index was introduced are listed by the share registry
+            None => {
+                let registry = self.share_registry(customer_id)?;
+                Ok(KeyIndex {
+                    ids: registry.replaced.into_iter().chain(registry.active).collect(),
+                })
+            }
+        }
+    }
+
+    fn key_summary(&self, key: &DbIndex, registry: &ShareRegistry) -> Result<KeySummary, StorageError> {
+        let metadata = self.key_metadata(key)?.unwrap_or_default();
+        let status = if metadata.deactivated_at.is_some() {
+            KeyStatus::Deactivated
+        } else if registry.replaced.contains(&key.id) {
+            KeyStatus::Replaced
+        } else {
+            KeyStatus::Active
+        };
+        Ok(KeySummary {
+            id: key.id.clone(),
+            created_at: metadata.created_at,
+            status,
+            labels: metadata.labels,
+        })
+    }
+
+    /// Status, creation time and labels of a key.
+    pub fn describe_key(&self, key: &DbIndex) -> Result<KeySummary, StorageError> {
+        let registry = self.share_registry(&key.customer_id)?;
+        self.key_summary(key, &registry)
+    }
+
+    /// Refuses further signatures with the key, which no longer counts as an active share.
+    pub fn deactivate_key(&self, key: &DbIndex) -> Result<KeySummary, StorageError> {
+        let _customer_records = self.customer_records_lock.lock().unwrap_or_else(|e| e.into_inner());
+        let mut metadata = self.key_metadata(key)?.unwrap_or_default();
+        if metadata.deactivated_at.is_none() {
+            metadata.deactivated_at = Some(Utc::now());
+            self.set_key_metadata(key, &metadata)?;
+        }
+        let mut registry = self.share_registry(&key.customer_id)?;
+        if registry.active.contains(&key.id) {
+            registry.active.retain(|id| id != &key.id);
+            self.write_record(
+                SHARE_REGISTRY_TABLE,
+                encode_key(&[&key.customer_id]),
+                serde_json::to_string(&registry)?,
+            )?;
+        }
+        self.key_summary(key, &registry)
+    }
+
+    /// Removes every record of the key, in every table, and its entries in the customer's
+    /// share registry and key index. Returns how many records were deleted.
+    pub fn delete_key(&self, key: &DbIndex) -> Result<usize, StorageError> {
+        validate_customer_id(&key.customer_id).and(validate_id(&key.id))?;
+        let _customer_records = self.customer_records_lock.lock().unwrap_or_else(|e| e.into_inner());
+        let mut registry = self.share_registry(&key.customer_id)?;
+        registry.active.retain(|id| id != &key.id);
+        registry.replaced.retain(|id| id != &key.id);
+        self.write_record(
+            SHARE_REGISTRY_TABLE,
+            encode_key(&[&key.customer_id]),
+            serde_json::to_string(&registry)?,
+        )?;
+        let mut index = self.key_index(&key.customer_id)?;
+        index.ids.retain(|id| id != &key.id);
+        self.write_record(
+            KEY_INDEX_TABLE,
+            encode_key(&[&key.customer_id]),
+            serde_json::to_string(&index)?,
+        )?;
+
+        self.db.purge(&[
+            idify(key.customer_id.clone(), key.id.clone()),
+            legacy_idify(&key.customer_id, &key.id),
+        ])
+    }
+
+    pub fn recovery_escrow(&self, key: &DbIndex) -> Result<Option<RecoveryEscrow>, StorageError> {
+        validate_customer_id(&key.customer_id).and(validate_id(&key.id))?;
+        let identifier = idify(key.customer_id.clone(), key.id.clone());
+        match self.read_record(RECOVERY_ESCROW_TABLE, identifier, None)? {
+            Some(record) => Ok(Some(serde_json::from_str(&record)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Escrows party one's current share of the key under `recovery_key`, or else under the key
+    /// of its existing escrow or the configured one. None when there is no such key or no
+    /// completed keygen.
+    pub fn escrow_share(&self, key: &DbIndex, recovery_key: Option<&GE>) -> Result<Option<RecoveryEscrow>, StorageError> {
+        let recovery_key = match recovery_key {
+            Some(recovery_key) => *recovery_key,
+            None => match self.recovery_escrow(key)? {
+                Some(escrow) => escrow.recovery_public_key,
+                None => match self.recovery_key {
+                    Some(recovery_key) => recovery_key,
+                    None => return Ok(None),
+                },
+            },
+        };
+        let master_key = match self.party_one_master_key(key)? {
+            Some(master_key) => master_key,
+            None => return Ok(None),
+        };
+        let escrow = RecoveryEscrow::new(&master_key, &recovery_key)?;
+        let identifier = idify(key.customer_id.clone(), key.id.clone());
+        self.write_record(RECOVERY_ESCROW_TABLE, identifier, serde_json::to_string(&escrow)?)?;
+        Ok(Some(escrow))
+    }
+
+    /// Escrows party one's share of the key under a recovery key of the customer's choosing.
+    /// Customers choose it once: an escrow under any key but the configured one is only
+    /// replaced when `replace`, as administrators may.
+    pub fn choose_recovery_key(
+        &self,
+        key: &DbIndex,
+        recovery_key: &GE,
+        replace: bool,
+    ) -> Result<EscrowChoice, StorageError> {
+        let _customer_records = self.customer_records_lock.lock().unwrap_or_else(|e| e.into_inner());
+        if !replace {
+            if let Some(escrow) = self.recovery_escrow(key)? {
+                if Some(escrow.recovery_public_key) != self.recovery_key {
+                    return Ok(EscrowChoice::AlreadyChosen);
+                }
+            }
+        }
+        Ok(match self.escrow_share(key, Some(recovery_key))? {
+            Some(escrow) => EscrowChoice::Escrowed(escrow),
+            None => EscrowChoice::UnknownKey,
+        })
+    }
+
+    /// Appends an event to the audit log.
+    pub fn audit(&self, event: AuditEvent) -> Result<(), StorageError> {
+        self.audit_log.append(event).map(|_| ())
+    }
+
+    /// Moves the events that releases before the hash-chained log stored among the key records
+    /// into the log, oldest first, deleting each once appended. Runs once, behind a marker record.
+    pub fn migrate_legacy_audit_events(&self) -> Result<usize, StorageError> {
+        let marker = LEGACY_AUDIT_MIGRATION.to_string();
+        if self.read_record(MIGRATIONS_TABLE, marker.clone(), None)?.is_some() {
+            return Ok(0);
         }
+        let mut events = Vec::new();
+        for (table, identifier) in self.db.keys()? {
+            if table != LEGACY_AUDIT_TABLE {
+                continue;
+            }
+            if let Some(record) = self.read_record(&table, identifier.clone(), None)? {
+                events.push((serde_json::from_str::<AuditEvent>(&record)?, identifier));
+            }
+        }
+        events.sort_by_key(|(event, _)| event.at);
+
+        let migrated = events.len();
+        for (event, identifier) in events {
+            self.audit(event)?;
+            self.db.delete(LEGACY_AUDIT_TABLE, identifier)?;
+        }
+        self.write_record(MIGRATIONS_TABLE, marker, Utc::now().to_rfc3339())?;
+        Ok(migrated)
+    }
+
+    /// Consistent copies of the key store and of the audit log, in directories that must not exist yet.
+    pub fn checkpoint(&self, db_dir: &Path, audit_dir: &Path) -> Result<(), BackupError> {
+        match &self.db {
+            DB::Local(rocksdb_client) => rocksdb_client.checkpoint(db_dir)?,
+            DB::Redis(_) => return Err(BackupError::Unsupported("redis")),
+        }
+        self.audit_log.checkpoint(audit_dir)?;
+        Ok(())
+    }
+
+    /// Up to `limit` audit records from sequence number `from` on.
+    pub fn audit_records(&self, from: u64, limit: usize) -> Result<Vec<AuditRecord>, StorageError> {
+        self.audit_log.records(from, limit)
+    }
+
+    /// Up to `limit` of the customer's keys following the id `after`, in creation order, or
+    /// nothing when `after` is not one of them.
+    pub fn list_keys(
+        &self,
+        customer_id: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Option<KeyPage>, StorageError> {
+        let ids = self.key_index(customer_id)?.ids;
+        let registry = self.share_registry(customer_id)?;
+        let start = match after {
+            Some(after) => match ids.iter().position(|id| id == after) {
+                Some(position) => position + 1,
+                None => return Ok(None),
+            },
+            None => 0,
+        };
+        let end = ids.len().min(start.saturating_add(limit));
+        let keys = ids[start..end]
+            .iter()
+            .map(|id| {
+                let key = DbIndex {
+                    customer_id: customer_id.to_string(),
+                    id: id.clone(),
+                };
+                self.key_summary(&key, &registry)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let next = if end < ids.len() {
+            keys.last().map(|key| key.id.clone())
+        } else {
+            None
+        };
+        Ok(Some(KeyPage { keys, next }))
+    }
+
+    pub fn set_key_labels(
+        &self,
+        key: &DbIndex,
+        labels: BTreeMap<String, String>,
+    ) -> Result<KeySummary, StorageError> {
+        let mut metadata = self.key_metadata(key)?.unwrap_or_default();
+        metadata.labels = labels;
+        self.set_key_metadata(key, &metadata)?;
+        self.describe_key(key)
+    }
+
+    pub fn backend_name(&self) -> &'static str {
+        match &self.db {
+            DB::Local(_) => "local",
+            DB::Redis(RedisStore::Single(_)) => "redis",
+            DB::Redis(RedisStore::Cluster(..)) => "redis_cluster",
+        }
+    }
+
+    /// Writes and reads back a probe record through the full record path, encryption included.
+    pub fn probe(&self) -> Result<(), StorageError> {
+        let nonce = uuid::Uuid::new_v4().to_string();
+        let identifier = encode_key(&["probe"]);
+        self.write_record(HEALTH_PROBE_TABLE, identifier.clone(), nonce.clone())?;
+        match self.read_record(HEALTH_PROBE_TABLE, identifier, None)? {
+            Some(read) if read == nonce => Ok(()),
+            _ => Err(StorageError::MalformedRecord("health probe read back a different value".to_string())),
+        }
+    }
+
+    /// Deletes ephemeral records whose session TTL elapsed before `now`. Redis expires them
+    /// by itself, so this only sweeps RocksDB.
+    pub fn purge_expired(&self, now: u64) -> Result<usize, StorageError> {
+        let rocksdb_client = match &self.db {
+            DB::Local(rocksdb_client) => rocksdb_client,
+            DB::Redis(_) => return Ok(0),
+        };
+        let mut purged = 0;
+        for table in rocksdb_client.tables()? {
+            if let Some(ttl) = self.session_ttl.ttl(&table) {
+                purged += rocksdb_client.purge_expired(&table, ttl, now)?;
+            }
+        }
+        Ok(purged)
+    }
+
+    /// Migrates every stored record to the active master key, encrypting legacy plaintext
+    /// records and re-encrypting records sealed under a rotated key. Returns how many were rewritten.
+    pub fn reencrypt_all(&self) -> Result<usize, StorageError> {
+        if self.master_keys.is_none() {
+            return Ok(0);
+        }
+        let mut rewritten = 0;
+        for (table, identifier) in self.db.keys()? {
+            let stored = match self.db.get(&table, identifier.clone())? {
+                Some(vec) => String::from_utf8(vec)?,
+                None => continue,
+            };
+            let (plaintext, stale) = self.unseal(&table, &identifier, stored)?;
+            if stale {
+                self.write_record(&table, identifier, plaintext)?;
+                rewritten += 1;
+            }
+        }
+        Ok(rewritten)
     }
 }
 
+/// The store `public_server_exec` opens, configured by Settings.toml and the environment.
 impl Default for PublicGotham {
     fn default() -> Self {
-        Self::new()
+        let config = crate::server::get_config(&crate::server::get_settings_as_map());
+        PublicGotham::new(config.unwrap_or_else(|e| panic!("Invalid server configuration: {}", e)))
     }
 }
 
@@ -64,9 +675,233 @@ impl KeyGen for PublicGotham {}
 
 impl Sign for PublicGotham {}
 
+/// Shared handle to a `PublicGotham`. Both backends are safe for concurrent use, so handlers
+/// share one store through an `Arc` rather than taking turns on a lock.
+#[derive(Clone)]
+pub struct GothamHandle(pub Arc<PublicGotham>);
+
+#[async_trait]
+impl Db for GothamHandle {
+    async fn insert(
+        &self,
+        key: &DbIndex,
+        table_name: &dyn MPCStruct,
+        value: &dyn Value,
+    ) -> Result<(), DatabaseError> {
+        self.0.insert(key, table_name, value).await
+    }
+
+    async fn get(
+        &self,
+        key: &DbIndex,
+        table_name: &dyn MPCStruct,
+    ) -> Result<Option<Box<dyn Value>>, DatabaseError> {
+        self.0.get(key, table_name).await
+    }
+
+    async fn has_active_share(&self, user_id: &str) -> Result<bool, String> {
+        self.0.has_active_share(user_id).await
+    }
+}
+
+/// One lock per name, kept only while someone holds it.
+#[derive(Default)]
+struct NamedLocks(Mutex<HashMap<String, Weak<Mutex<()>>>>);
+
+impl NamedLocks {
+    fn get(&self, name: &str) -> Arc<Mutex<()>> {
+        let mut locks = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(lock) = locks.get(name).and_then(Weak::upgrade) {
+            return lock;
+        }
+        locks.retain(|_, lock| lock.strong_count() > 0);
+        let lock = Arc::new(Mutex::new(()));
+        locks.insert(name.to_string(), Arc::downgrade(&lock));
+        lock
+    }
+}
+
+/// The lock the engine's protocol steps take for the whole step, over a `GothamHandle`.
+pub type DbLock = tokio::sync::Mutex<Box<dyn Db>>;
+
+/// Hands each key its own `DbLock`: steps on one key take turns, as the engine expects,
+/// while steps on different keys run in parallel.
+pub struct KeyLocks {
+    gotham: Arc<PublicGotham>,
+    locks: Mutex<HashMap<(String, String), Weak<DbLock>>>,
+}
+
+impl KeyLocks {
+    pub fn new(gotham: Arc<PublicGotham>) -> Self {
+        KeyLocks {
+            gotham,
+            locks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn new_lock(&self) -> Arc<DbLock> {
+        Arc::new(tokio::sync::Mutex::new(Box::new(GothamHandle(self.gotham.clone()))))
+    }
+
+    /// The lock of a customer's key, shared by every step running on it.
+    pub fn of(&self, customer_id: &str, id: &str) -> Arc<DbLock> {
+        let mut locks = self.locks.lock().unwrap_or_else(|e| e.into_inner());
+        let key = (customer_id.to_string(), id.to_string());
+        if let Some(lock) = locks.get(&key).and_then(Weak::upgrade) {
+            return lock;
+        }
+        // A lock lives as long as a step holds it; forget those of keys no step runs on
+        locks.retain(|_, lock| lock.strong_count() > 0);
+        let lock = self.new_lock();
+        locks.insert(key, Arc::downgrade(&lock));
+        lock
+    }
+
+    /// A lock of its own, for a keygen's first step whose key id is not drawn yet.
+    pub fn unshared(&self) -> Arc<DbLock> {
+        self.new_lock()
+    }
+}
+
+impl RotationStore for PublicGotham {
+    fn master_key(&self, key: &DbIndex) -> Result<Option<MasterKey1>, StorageError> {
+        self.party_one_master_key(key)
+    }
+
+    fn rotation_session(&self, key: &DbIndex) -> Result<Option<RotationSession>, StorageError> {
+        validate_customer_id(&key.customer_id).and(validate_id(&key.id))?;
+        let identifier = idify(key.customer_id.clone(), key.id.clone());
+        match self.read_record(ROTATION_SESSION_TABLE, identifier, None)? {
+            Some(record) => Ok(Some(serde_json::from_str(&record)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn set_rotation_session(&self, key: &DbIndex, session: &RotationSession) -> Result<(), StorageError> {
+        validate_customer_id(&key.customer_id).and(validate_id(&key.id))?;
+        let identifier = idify(key.customer_id.clone(), key.id.clone());
+        self.write_record(ROTATION_SESSION_TABLE, identifier, serde_json::to_string(session)?)
+    }
+
+    fn stage_rotated_key(&self, key: &DbIndex, master_key: &MasterKey1) -> Result<(), StorageError> {
+        validate_customer_id(&key.customer_id).and(validate_id(&key.id))?;
+        let identifier = idify(key.customer_id.clone(), key.id.clone());
+        // Same representation as the records the engine writes through `Db::insert`
+        let value: &dyn Value = master_key;
+        self.write_record(ROTATED_MASTER_KEY_TABLE, identifier, serde_json::to_string(&value)?)
+    }
+
+    fn confirm_rotated_key(&self, key: &DbIndex) -> Result<bool, StorageError> {
+        validate_customer_id(&key.customer_id).and(validate_id(&key.id))?;
+        let identifier = idify(key.customer_id.clone(), key.id.clone());
+        let rotated = match self.read_record(ROTATED_MASTER_KEY_TABLE, identifier.clone(), None)? {
+            Some(rotated) => rotated,
+            None => return Ok(false),
+        };
+        self.write_record(&EcdsaStruct::Party1MasterKey.to_string(), identifier.clone(), rotated)?;
+        self.db.delete(ROTATED_MASTER_KEY_TABLE, identifier.clone())?;
+        self.db.delete(ROTATION_SESSION_TABLE, identifier)?;
+
+        let mut metadata = self.key_metadata(key)?.unwrap_or_default();
+        metadata.rotated_at = Some(Utc::now());
+        self.set_key_metadata(key, &metadata)?;
+        // The escrow of the previous share would no longer combine with the client's
+        self.escrow_share(key, None)?;
+        Ok(true)
+    }
+}
+
+/// Table of the per customer `ShareRegistry` records.
+pub(crate) const SHARE_REGISTRY_TABLE: &str = "ShareRegistry";
+
+/// Table of the per customer `SigningPolicy` records.
+pub(crate) const SIGNING_POLICY_TABLE: &str = "SigningPolicy";
+
+/// Table of the per key `SigningUsage` records, counting signatures of the current day.
+pub(crate) const SIGNING_USAGE_TABLE: &str = "SigningUsage";
+
+/// Table of the rotation state of party one between the rotation steps.
+pub(crate) const ROTATION_SESSION_TABLE: &str = "RotationSession";
+
+/// Table of rotated party one master keys awaiting the client's confirmation.
+pub(crate) const ROTATED_MASTER_KEY_TABLE: &str = "RotatedMasterKey";
+
+/// Table of the per key `KeyMetadata` records.
+pub(crate) const KEY_METADATA_TABLE: &str = "KeyMetadata";
+
+/// Table of the per customer `KeyIndex` records.
+pub(crate) const KEY_INDEX_TABLE: &str = "KeyIndex";
+
+/// Table of the per key `RecoveryEscrow` records.
+pub(crate) const RECOVERY_ESCROW_TABLE: &str = "RecoveryEscrow";
+
+
+/// Table in which releases before the hash-chained audit log stored `AuditEvent` records.
+const LEGACY_AUDIT_TABLE: &str = "AuditLog";
+
+/// Table of the markers of one-off migrations of the stored records.
+const MIGRATIONS_TABLE: &str = "Migrations";
+
+const LEGACY_AUDIT_MIGRATION: &str = "legacy_audit_events";
+
+/// Table written by the readiness probe.
+pub(crate) const HEALTH_PROBE_TABLE: &str = "HealthProbe";
+
+/// Key of a record within its table.
+#[inline(always)]
+pub(crate) fn idify(user_id: String, id: String) -> String {
+    encode_key(&[&user_id, &id])
+}
+
+/// Key written by releases that joined customer and key ids with an underscore. Only looked up
+/// for validated ids, which cannot contain one, so the customer id is the part before the last `_`.
 #[inline(always)]
-fn idify(user_id: String, id: String, name: &dyn MPCStruct) -> String {
-    format!("{}_{}_{}", user_id, id, name.to_string())
+pub(crate) fn legacy_idify(user_id: &str, id: &str) -> String {
+    format!("{}_{}", user_id, id)
+}
+
+/// Engine tables of keygen and signing state, read again only by a later step of the same session.
+const EPHEMERAL_ENGINE_TABLES: &[EcdsaStruct] = &[
+    EcdsaStruct::KeyGenFirstMsg,
+    EcdsaStruct::CommWitness,
+    EcdsaStruct::EcKeyPair,
+    EcdsaStruct::PaillierKeyPair,
+    EcdsaStruct::Party1Private,
+    EcdsaStruct::Party2Public,
+    EcdsaStruct::PDLProver,
+    EcdsaStruct::PDLDecommit,
+    EcdsaStruct::Alpha,
+    EcdsaStruct::Party2PDLFirstMsg,
+    EcdsaStruct::CCKeyGenFirstMsg,
+    EcdsaStruct::CCCommWitness,
+    EcdsaStruct::CCEcKeyPair,
+    EcdsaStruct::CC,
+    EcdsaStruct::EphEcKeyPair,
+    EcdsaStruct::EphKeyGenFirstMsg,
+];
+
+/// Every table holding records of a single key, which deleting it must look up.
+fn key_tables() -> Vec<String> {
+    let engine_tables = EPHEMERAL_ENGINE_TABLES
+        .iter()
+        .chain([&EcdsaStruct::Party1MasterKey])
+        .map(ToString::to_string);
+    let tables = [
+        SIGNING_USAGE_TABLE,
+        ROTATION_SESSION_TABLE,
+        ROTATED_MASTER_KEY_TABLE,
+        KEY_METADATA_TABLE,
+        RECOVERY_ESCROW_TABLE,
+    ];
+    engine_tables.chain(tables.map(str::to_string)).collect()
+}
+
+/// Protocol state that only lives until a keygen, signing or rotation session completes. Any
+/// other table, including one a later release adds, is kept until its key is deleted.
+pub(crate) fn is_ephemeral_table(table: &str) -> bool {
+    table == ROTATION_SESSION_TABLE
+        || table == HEALTH_PROBE_TABLE
+        || EPHEMERAL_ENGINE_TABLES.iter().any(|ephemeral| ephemeral.to_string() == table)
 }
 
 #[async_trait]
@@ -77,18 +912,14 @@ impl Db for PublicGotham {
         table_name: &dyn MPCStruct,
         value: &dyn Value,
     ) -> Result<(), DatabaseError> {
-        let identifier = idify(key.clone().customer_id, key.clone().id, table_name);
-        // let val_json = serde_json::to_string(value);
-        let v_string = serde_json::to_string(&value).unwrap();
-        // println!("Insert to RocksDB : {:?}", v_string.clone());
-
-        // let mut prefix: String = "{".to_owned();
-        // let preformat = &v_string[v_string.find(':').unwrap()..v_string.len()-1];
-        // let format = prefix+&preformat[2..preformat.len()];
-        // // let final = format.clone()[..format.clone().len()-1];
-
-        let _ = self.rocksdb_client.put(identifier, v_string.clone());
-        Ok(())
+        let _timer = metrics().db_latency.with_label_values(&["insert"]).start_timer();
+        let table = table_name.to_string();
+        let span = tracing::debug_span!("db_insert", table = %table, id = %key.id);
+        let written = span.in_scope(|| self.write_value(key, &table, value));
+        if let Err(e) = &written {
+            Lookups::record_failure(e);
+        }
+        Ok(written?)
     }
 
     async fn get(
@@ -96,30 +927,26 @@ impl Db for PublicGotham {
         key: &DbIndex,
         table_name: &dyn MPCStruct,
     ) -> Result<Option<Box<dyn Value>>, DatabaseError> {
-        let identifier = idify(key.clone().customer_id, key.clone().id, table_name);
-        // debug!("Getting from db ({})", identifier);
-        let result = self.rocksdb_client.get(identifier.clone()).unwrap();
-        let vec_option: Option<Vec<u8>> = result.map(|v| v.to_vec());
-        match vec_option {
-            Some(vec) => {
-
-                // let mut prefix:String = "{\"".to_owned();
-                // let val: String = String::from_utf8(vec.clone()).unwrap();
-                // println!("result from get = {:?}", val.clone());
-
-                // let preformat = &val[val.find(',').unwrap()..];
-                //
-                // let format = prefix +&preformat[2..preformat.len()];
-                //
-                // println!("result from get = {:?}", format.clone());
-
-                let final_val: Box<dyn Value> = serde_json::from_str(String::from_utf8(vec.clone()).expect("Found invalid UTF-8").as_str()).unwrap();
-                Ok(Option::from(final_val))
-            }
-            None => Ok(None),
+        let _timer = metrics().db_latency.with_label_values(&["get"]).start_timer();
+        let table = table_name.to_string();
+        let span = tracing::debug_span!("db_get", table = %table, id = %key.id);
+        let read = span.in_scope(|| self.read_value(key, &table));
+        if let Ok(found) = &read {
+            span.in_scope(|| tracing::debug!(found = found.is_some(), "Db get"));
         }
+        Lookups::record_read(&table, &read);
+        Ok(read?)
     }
-    async fn has_active_share(&self, _user_id: &str) -> Result<bool, String> {
-        Ok(false)
+    /// Consulted by the engine before a new keygen; answers whether the share policy refuses it.
+    async fn has_active_share(&self, user_id: &str) -> Result<bool, String> {
+        let registry = self.share_registry(user_id).map_err(|e| {
+            Lookups::record_failure(&e);
+            e.to_string()
+        })?;
+        let blocked = registry.blocks_keygen(self.share_policy);
+        if blocked {
+            Lookups::record_share_refusal();
+        }
+        Ok(blocked)
     }
 }
@@ -0,0 +1,510 @@
+//!Escrow of party one's share under a recovery key, so that a customer holding `MasterKey2`
+//!can rebuild the full private key without the server
+
+use std::collections::HashMap;
+use std::string::String;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use rocket::serde::json::{json, Json};
+use rocket::{get, put, State};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use gotham_engine::types::DbIndex;
+use two_party_ecdsa::curv::arithmetic::traits::Modulo;
+use two_party_ecdsa::curv::cryptographic_primitives::proofs::sigma_ec_ddh::{
+    ECDDHProof, ECDDHStatement, ECDDHWitness,
+};
+use two_party_ecdsa::curv::elliptic::curves::traits::{ECPoint, ECScalar};
+use two_party_ecdsa::curv::{FE, GE};
+use two_party_ecdsa::kms::ecdsa::two_party::{MasterKey1, MasterKey2};
+use two_party_ecdsa::party_one::Converter;
+use two_party_ecdsa::BigInt;
+
+use crate::admin::{AdminToken, ADMIN_ACTOR};
+use crate::api_error::{blocking, ApiError, ErrorCode};
+use crate::audit::AuditEvent;
+use crate::auth::AuthenticatedCustomer;
+use crate::error::StorageError;
+use crate::keys::validate_id;
+use crate::metadata::KeyStatus;
+use crate::public_gotham::PublicGotham;
+
+/// The share is escrowed as `CHUNKS` chunks of `CHUNK_BITS` bits, small enough for the holder
+/// of the recovery key to find each one from its encryption in the exponent.
+const CHUNK_BITS: usize = 16;
+const CHUNKS: usize = 256 / CHUNK_BITS;
+/// Baby steps of the search for a chunk, the square root of its range.
+const BABY_STEPS: u32 = 1 << (CHUNK_BITS / 2);
+
+#[derive(Debug, Error)]
+pub enum RecoveryError {
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+    #[error("Invalid recovery key: {0}")]
+    InvalidKey(String),
+    #[error("The escrow was made for another public share")]
+    WrongShare,
+    #[error("The escrow proof does not verify")]
+    InvalidProof,
+    #[error("Chunk {0} of the escrow is not proven to be in range")]
+    OutOfRange(usize),
+    #[error("The escrow was made for another recovery key")]
+    WrongRecoveryKey,
+    #[error("Chunk {0} of the escrow does not decrypt")]
+    Undecryptable(usize),
+    #[error("The decrypted share does not match the public share")]
+    ShareMismatch,
+    #[error("The recovered private key does not match the public key of the wallet")]
+    KeyMismatch,
+}
+
+/// ElGamal encryption of `(m + 1) G` under the recovery key `Y`: `c1 = r G`, `c2 = (m + 1) G + r Y`.
+/// Chunks are offset by one so that none encrypts the point at infinity.
+///
+/// `bits` range proves the chunk: they encrypt the bits `b_j` of `m` with `r = Σ 2^j r_j`, so
+/// that `Σ 2^j c1_j = c1` and `Σ 2^j c2_j + G = c2`, and each is proven to encrypt 0 or 1.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EncryptedChunk {
+    pub c1: GE,
+    pub c2: GE,
+    pub bits: Vec<EncryptedBit>,
+}
+
+/// ElGamal encryption of a bit `b` under `Y`, `c1 = r G` and `c2 = b G + r Y`, with a proof that
+/// `b` is 0 or 1: a disjunction of Chaum-Pedersen proofs that `(G, c1, Y, c2 - b G)` is a DDH
+/// tuple for either value, made non-interactive with Fiat-Shamir. Only the branch of the actual
+/// bit is proven, the other one is simulated, and their challenges add up to the hash.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EncryptedBit {
+    pub c1: GE,
+    pub c2: GE,
+    /// Of the branches for 0 and for 1.
+    pub challenges: [FE; 2],
+    pub responses: [FE; 2],
+}
+
+impl EncryptedBit {
+    fn new(bit: u32, r: &FE, recovery_public_key: &GE) -> Self {
+        let mask = *recovery_public_key * r;
+        let (c1, c2) = match bit {
+            0 => (GE::generator() * r, mask),
+            _ => (GE::generator() * r, GE::generator() + mask),
+        };
+        let (fake_challenge, fake_response): (FE, FE) = (ECScalar::new_random(), ECScalar::new_random());
+        let fake = branch_commitments(recovery_public_key, &c1, &c2, 1 - bit, &fake_challenge, &fake_response);
+        let nonce: FE = ECScalar::new_random();
+        let real = (GE::generator() * &nonce, *recovery_public_key * &nonce);
+        let commitments = if bit == 0 { [real, fake] } else { [fake, real] };
+
+        let q = FE::q();
+        let challenge = bit_challenge(recovery_public_key, &c1, &c2, &commitments);
+        let real_challenge = BigInt::mod_sub(&challenge, &fake_challenge.to_big_int(), &q);
+        let real_response = BigInt::mod_add(
+            &nonce.to_big_int(),
+            &BigInt::mod_mul(&real_challenge, &r.to_big_int(), &q),
+            &q,
+        );
+        let (real_challenge, real_response) = (scalar(&real_challenge), scalar(&real_response));
+        let (challenges, responses) = if bit == 0 {
+            ([real_challenge, fake_challenge], [real_response, fake_response])
+        } else {
+            ([fake_challenge, real_challenge], [fake_response, real_response])
+        };
+        EncryptedBit {
+            c1,
+            c2,
+            challenges,
+            responses,
+        }
+    }
+
+    fn verify(&self, recovery_public_key: &GE) -> bool {
+        let zero = BigInt::from(0u32);
+        if self.challenges.iter().chain(&self.responses).any(|e| e.to_big_int() == zero) {
+            return false;
+        }
+        let commitments = [0, 1].map(|bit| {
+            branch_commitments(
+                recovery_public_key,
+                &self.c1,
+                &self.c2,
+                bit,
+                &self.challenges[bit as usize],
+                &self.responses[bit as usize],
+            )
+        });
+        let challenge = BigInt::mod_add(
+            &self.challenges[0].to_big_int(),
+            &self.challenges[1].to_big_int(),
+            &FE::q(),
+        );
+        challenge == bit_challenge(recovery_public_key, &self.c1, &self.c2, &commitments)
+    }
+}
+
+/// The commitments `z G - e c1` and `z Y - e (c2 - b G)` of the branch for `bit`, which are
+/// those the prover made when `z` answers the challenge `e`.
+fn branch_commitments(recovery_public_key: &GE, c1: &GE, c2: &GE, bit: u32, challenge: &FE, response: &FE) -> (GE, GE) {
+    let statement = match bit {
+        0 => *c2,
+        _ => c2.sub_point(&GE::generator().get_element()),
+    };
+    (
+        (GE::generator() * response).sub_point(&(*c1 * challenge).get_element()),
+        (*recovery_public_key * response).sub_point(&(statement * challenge).get_element()),
+    )
+}
+
+fn bit_challenge(recovery_public_key: &GE, c1: &GE, c2: &GE, commitments: &[(GE, GE); 2]) -> BigInt {
+    let mut hasher = Sha256::new();
+    hasher.update(b"gotham recovery escrow bit");
+    let [(a0, b0), (a1, b1)] = commitments;
+    for point in [recovery_public_key, c1, c2, a0, b0, a1, b1] {
+        hasher.update(format!("{:0>66}", point.bytes_compressed_to_big_int().to_hex()));
+    }
+    BigInt::modulus(&BigInt::from(&hasher.finalize()[..]), &FE::q())
+}
+
+/// Party one's share `x1` of a key, encrypted under a recovery public key `Y` as the chunks
+/// `m_i` of `x1 = Σ 2^(16 i) m_i`. With `w_i = 2^(16 i)` and `K = Σ w_i`, the weighted sums
+/// of the chunks are `Σ w_i c1_i = R G` and `Σ w_i c2_i - public_share - K G = R Y` for
+/// `R = Σ w_i r_i`, which `proof` shows, binding the escrow to `public_share`.
+///
+/// With each chunk range proven, anyone can check that the escrow decrypts to the share of
+/// `public_share` without the recovery secret key, whoever made it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecoveryEscrow {
+    pub recovery_public_key: GE,
+    /// Party one's public share `x1 G`, `p1` of the client's `MasterKey2`.
+    pub public_share: GE,
+    pub chunks: Vec<EncryptedChunk>,
+    pub proof: ECDDHProof,
+    pub created_at: DateTime<Utc>,
+}
+
+fn scalar(n: &BigInt) -> FE {
+    ECScalar::from(n)
+}
+
+fn weight(i: usize) -> BigInt {
+    BigInt::from(1u32) << (CHUNK_BITS * i)
+}
+
+fn bit_weight(j: usize) -> BigInt {
+    BigInt::from(1u32) << j
+}
+
+fn weighted_sum<'a, I: Iterator<Item = &'a GE>>(points: I, weight: fn(usize) -> BigInt) -> GE {
+    points
+        .enumerate()
+        .map(|(i, point)| *point * &scalar(&weight(i)))
+        .reduce(|sum, point| sum + point)
+        .expect("escrows have chunks")
+}
+
+/// `K G`, the sum of the chunk offsets.
+fn offset() -> GE {
+    let k = (0..CHUNKS).fold(BigInt::from(0u32), |k, i| BigInt::mod_add(&k, &weight(i), &FE::q()));
+    GE::generator() * &scalar(&k)
+}
+
+fn statement(recovery_public_key: &GE, public_share: &GE, chunks: &[EncryptedChunk]) -> ECDDHStatement {
+    let c1 = weighted_sum(chunks.iter().map(|chunk| &chunk.c1), weight);
+    let c2 = weighted_sum(chunks.iter().map(|chunk| &chunk.c2), weight);
+    ECDDHStatement {
+        g1: GE::generator(),
+        h1: c1,
+        g2: *recovery_public_key,
+        h2: c2.sub_point(&public_share.get_element()).sub_point(&offset().get_element()),
+    }
+}
+
+/// `Party1Private` keeps the share private to two_party_ecdsa, it is read from its serialized form.
+fn party_one_secret_share(master_key: &MasterKey1) -> Result<FE, serde_json::Error> {
+    serde_json::from_value(serde_json::to_value(&master_key.private)?["x1"].take())
+}
+
+fn party_two_secret_share(master_key: &MasterKey2) -> Result<FE, serde_json::Error> {
+    serde_json::from_value(serde_json::to_value(&master_key.private)?["x2"].take())
+}
+
+impl EncryptedChunk {
+    fn is_range_proven(&self, recovery_public_key: &GE) -> bool {
+        self.bits.len() == CHUNK_BITS
+            && self.bits.iter().all(|bit| bit.verify(recovery_public_key))
+            && weighted_sum(self.bits.iter().map(|bit| &bit.c1), bit_weight) == self.c1
+            && weighted_sum(self.bits.iter().map(|bit| &bit.c2), bit_weight) + GE::generator() == self.c2
+    }
+}
+
+impl RecoveryEscrow {
+    pub fn new(master_key: &MasterKey1, recovery_public_key: &GE) -> Result<Self, serde_json::Error> {
+        let x1 = format!("{:0>64}", party_one_secret_share(master_key)?.to_big_int().to_hex());
+        let mut r = BigInt::from(0u32);
+        let mut chunks = Vec::with_capacity(CHUNKS);
+        for i in 0..CHUNKS {
+            let digits = &x1[x1.len() - (i + 1) * CHUNK_BITS / 4..x1.len() - i * CHUNK_BITS / 4];
+            let m = u32::from_str_radix(digits, 16).expect("hex encoded share");
+            let mut r_i = BigInt::from(0u32);
+            let bits = (0..CHUNK_BITS)
+                .map(|j| {
+                    let r_j: FE = ECScalar::new_random();
+                    let weighted = BigInt::mod_mul(&bit_weight(j), &r_j.to_big_int(), &FE::q());
+                    r_i = BigInt::mod_add(&r_i, &weighted, &FE::q());
+                    EncryptedBit::new((m >> j) & 1, &r_j, recovery_public_key)
+                })
+                .collect();
+            let r_i = scalar(&r_i);
+            chunks.push(EncryptedChunk {
+                c1: GE::generator() * &r_i,
+                c2: GE::generator() * &scalar(&BigInt::from(m + 1)) + *recovery_public_key * &r_i,
+                bits,
+            });
+            r = BigInt::mod_add(&r, &BigInt::mod_mul(&weight(i), &r_i.to_big_int(), &FE::q()), &FE::q());
+        }
+        let public_share = master_key.public.p1;
+        let proof = ECDDHProof::prove(
+            &ECDDHWitness { x: scalar(&r) },
+            &statement(recovery_public_key, &public_share, &chunks),
+        );
+        Ok(RecoveryEscrow {
+            recovery_public_key: *recovery_public_key,
+            public_share,
+            chunks,
+            proof,
+            created_at: Utc::now(),
+        })
+    }
+
+    /// Checks that the escrow encrypts the share whose public part is `public_share`, in chunks
+    /// that the holder of the recovery secret key can decrypt.
+    pub fn verify(&self, public_share: &GE) -> Result<(), RecoveryError> {
+        if &self.public_share != public_share {
+            return Err(RecoveryError::WrongShare);
+        }
+        if self.chunks.len() != CHUNKS {
+            return Err(RecoveryError::InvalidProof);
+        }
+        for (i, chunk) in self.chunks.iter().enumerate() {
+            if !chunk.is_range_proven(&self.recovery_public_key) {
+                return Err(RecoveryError::OutOfRange(i));
+            }
+        }
+        self.proof
+            .verify(&statement(&self.recovery_public_key, &self.public_share, &self.chunks))
+            .map_err(|_| RecoveryError::InvalidProof)
+    }
+
+    /// Decrypts party one's share with the recovery secret key.
+    pub fn decrypt(&self, recovery_secret_key: &FE) -> Result<FE, RecoveryError> {
+        if GE::generator() * recovery_secret_key != self.recovery_public_key {
+            return Err(RecoveryError::WrongRecoveryKey);
+        }
+        let baby_steps: HashMap<String, u32> = (1..=BABY_STEPS)
+            .map(|k| {
+                let point = GE::generator() * &scalar(&BigInt::from(k));
+                (point.bytes_compressed_to_big_int().to_hex(), k)
+            })
+            .collect();
+        let giant_step = GE::generator() * &scalar(&BigInt::from(BABY_STEPS));
+
+        let mut x1 = BigInt::from(0u32);
+        for (i, chunk) in self.chunks.iter().enumerate() {
+            let target = chunk.c2.sub_point(&(chunk.c1 * recovery_secret_key).get_element());
+            let m = find_chunk(&target, &baby_steps, &giant_step).ok_or(RecoveryError::Undecryptable(i))?;
+            x1 = BigInt::mod_add(&x1, &BigInt::mod_mul(&weight(i), &BigInt::from(m), &FE::q()), &FE::q());
+        }
+        let x1 = scalar(&x1);
+        if GE::generator() * &x1 != self.public_share {
+            return Err(RecoveryError::ShareMismatch);
+        }
+        Ok(x1)
+    }
+}
+
+/// Finds `m` from `target = (m + 1) G`, writing `m + 1` as `BABY_STEPS j + k` with `k` in
+/// `1..=BABY_STEPS`. Subtracting the giant steps never reaches the point at infinity: a target
+/// that is a multiple of a giant step is found with `k = BABY_STEPS` one step earlier.
+fn find_chunk(target: &GE, baby_steps: &HashMap<String, u32>, giant_step: &GE) -> Option<u32> {
+    let mut point = *target;
+    for j in 0..BABY_STEPS {
+        if let Some(k) = baby_steps.get(&point.bytes_compressed_to_big_int().to_hex()) {
+            return Some(BABY_STEPS * j + k - 1);
+        }
+        if j + 1 < BABY_STEPS {
+            point = point.sub_point(&giant_step.get_element());
+        }
+    }
+    None
+}
+
+/// The private key of the child key at `x_pos`/`y_pos`, from party one's decrypted share and
+/// party two's master key. Keys are shared multiplicatively, `x = x1 x2`, and child keys only
+/// update party two's share.
+pub fn recover_private_key(x1: &FE, master_key_2: &MasterKey2, x_pos: u32, y_pos: u32) -> Result<FE, RecoveryError> {
+    let child = master_key_2.get_child(vec![BigInt::from(x_pos), BigInt::from(y_pos)]);
+    let x2 = party_two_secret_share(&child)?;
+    let x = scalar(&BigInt::mod_mul(&x1.to_big_int(), &x2.to_big_int(), &FE::q()));
+    if GE::generator() * &x != child.public.q {
+        return Err(RecoveryError::KeyMismatch);
+    }
+    Ok(x)
+}
+
+/// A new recovery key pair.
+pub fn generate_recovery_key() -> (FE, GE) {
+    let secret_key: FE = ECScalar::new_random();
+    let public_key = GE::generator() * &secret_key;
+    (secret_key, public_key)
+}
+
+/// Parses a hex encoded SEC1 public key, compressed or not.
+pub fn parse_recovery_public_key(hex_key: &str) -> Result<GE, RecoveryError> {
+    let bytes = hex::decode(hex_key.trim()).map_err(|e| RecoveryError::InvalidKey(e.to_string()))?;
+    GE::from_bytes(&bytes).map_err(|_| RecoveryError::InvalidKey("not a point of secp256k1".to_string()))
+}
+
+/// Parses a 32 byte hex encoded secret key.
+pub fn parse_recovery_secret_key(hex_key: &str) -> Result<FE, RecoveryError> {
+    let bytes = hex::decode(hex_key.trim()).map_err(|e| RecoveryError::InvalidKey(e.to_string()))?;
+    let secret_key = BigInt::from(&bytes[..]);
+    if bytes.len() != 32 || secret_key == BigInt::from(0u32) || secret_key >= FE::q() {
+        return Err(RecoveryError::InvalidKey("not a secp256k1 secret key".to_string()));
+    }
+    Ok(scalar(&secret_key))
+}
+
+/// What choosing the recovery key of a key came to.
+#[derive(Debug)]
+pub enum EscrowChoice {
+    Escrowed(RecoveryEscrow),
+    /// No completed keygen under the id.
+    UnknownKey,
+    /// The customer already chose a recovery key, only an administrator may replace it.
+    AlreadyChosen,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EscrowRequest {
+    /// Hex encoded SEC1 public key.
+    pub recovery_public_key: String,
+}
+
+fn storage_failure(e: StorageError) -> ApiError {
+    log::error!("Recovery escrow storage failed: {}", e);
+    ApiError::new(ErrorCode::from(&e), "Storage backend failure")
+}
+
+fn index(customer_id: String, id: String) -> Result<DbIndex, ApiError> {
+    validate_id(&id).map_err(|e| ApiError::new(ErrorCode::InvalidId, e.to_string()))?;
+    Ok(DbIndex { customer_id, id })
+}
+
+/// Escrows the share under the requested recovery key. Refused attempts to replace a chosen
+/// key are audited, as they may come from a stolen token.
+fn escrow(
+    gotham: &PublicGotham,
+    key: DbIndex,
+    request: &EscrowRequest,
+    actor: &str,
+    replace: bool,
+) -> Result<Json<RecoveryEscrow>, ApiError> {
+    let recovery_public_key = parse_recovery_public_key(&request.recovery_public_key)
+        .map_err(|e| ApiError::new(ErrorCode::BadRequest, e.to_string()))?;
+    let details = json!({ "recovery_public_key": request.recovery_public_key.trim() });
+    match gotham
+        .choose_recovery_key(&key, &recovery_public_key, replace)
+        .map_err(storage_failure)?
+    {
+        EscrowChoice::Escrowed(escrow) => {
+            gotham
+                .audit(AuditEvent::new(actor, "escrow_share", &key.customer_id, Some(&key.id), details))
+                .map_err(storage_failure)?;
+            if replace {
+                log::warn!("Recovery key of key {} replaced by {}", key.id, actor);
+            }
+            Ok(Json(escrow))
+        }
+        EscrowChoice::UnknownKey => Err(ApiError::new(ErrorCode::UnknownId, format!("Unknown id '{}'", key.id))),
+        EscrowChoice::AlreadyChosen => {
+            log::warn!("Refused to replace the recovery key of key {} for {}", key.id, actor);
+            gotham
+                .audit(AuditEvent::new(actor, "refuse_escrow", &key.customer_id, Some(&key.id), details))
+                .map_err(storage_failure)?;
+            Err(ApiError::new(
+                ErrorCode::Forbidden,
+                format!(
+                    "The recovery key of key '{}' was already chosen, only an administrator may replace it",
+                    key.id
+                ),
+            ))
+        }
+    }
+}
+
+/// Escrows party one's share of the key under the customer's recovery key, once: the escrow
+/// made at keygen under the configured key may be replaced, a key the customer chose may not.
+/// It is kept up to date across rotations.
+#[put("/ecdsa/keys/<id>/recovery", format = "json", data = "<request>")]
+pub async fn put_escrow(
+    gotham: &State<Arc<PublicGotham>>,
+    customer: AuthenticatedCustomer,
+    id: String,
+    request: Json<EscrowRequest>,
+) -> Result<Json<RecoveryEscrow>, ApiError> {
+    let (gotham, key) = (gotham.inner().clone(), index(customer.customer_id.clone(), id)?);
+    blocking(move || escrow(&gotham, key, &request, &customer.customer_id, false)).await?
+}
+
+/// Escrows party one's share of a key under a new recovery key, whoever chose the current one.
+#[put("/admin/keys/<customer_id>/<id>/recovery", format = "json", data = "<request>")]
+pub async fn admin_put_escrow(
+    _admin: AdminToken,
+    gotham: &State<Arc<PublicGotham>>,
+    customer_id: String,
+    id: String,
+    request: Json<EscrowRequest>,
+) -> Result<Json<RecoveryEscrow>, ApiError> {
+    let (gotham, key) = (gotham.inner().clone(), index(customer_id, id)?);
+    blocking(move || {
+        // Customer routes are refused by the key guard, which knows no customer here
+        if gotham.describe_key(&key).map_err(storage_failure)?.status == KeyStatus::Deactivated {
+            return Err(ApiError::new(
+                ErrorCode::KeyDeactivated,
+                format!("Key {} is deactivated", key.id),
+            ));
+        }
+        escrow(&gotham, key, &request, ADMIN_ACTOR, true)
+    })
+    .await?
+}
+
+#[get("/ecdsa/keys/<id>/recovery")]
+pub async fn get_escrow(
+    gotham: &State<Arc<PublicGotham>>,
+    customer: AuthenticatedCustomer,
+    id: String,
+) -> Result<Json<RecoveryEscrow>, ApiError> {
+    let (gotham, key) = (gotham.inner().clone(), index(customer.customer_id, id)?);
+    blocking(move || exported_escrow(&gotham, key)).await?
+}
+
+fn exported_escrow(gotham: &PublicGotham, key: DbIndex) -> Result<Json<RecoveryEscrow>, ApiError> {
+    let escrow = gotham
+        .recovery_escrow(&key)
+        .map_err(storage_failure)?
+        .ok_or_else(|| ApiError::new(ErrorCode::UnknownId, format!("No recovery escrow for key '{}'", key.id)))?;
+    gotham
+        .audit(AuditEvent::new(
+            &key.customer_id,
+            "export_escrow",
+            &key.customer_id,
+            Some(&key.id),
+            json!({}),
+        ))
+        .map_err(storage_failure)?;
+    Ok(Json(escrow))
+}
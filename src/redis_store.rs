@@ -0,0 +1,142 @@
+//!Redis and Redis Cluster storage backend
+
+use std::string::String;
+
+use redis::cluster::ClusterClient;
+use redis::{Cmd, ConnectionAddr, ConnectionInfo, FromRedisValue, IntoConnectionInfo};
+
+use crate::error::StorageError;
+use crate::keys::decode_key;
+
+/// A single redis node or a redis cluster, each behind a pool of connections so that
+/// concurrent requests do not wait on one another.
+pub enum RedisStore {
+    Single(r2d2::Pool<redis::Client>),
+    /// With the connection info of its first initial node, whose credentials reach every node.
+    Cluster(r2d2::Pool<ClusterClient>, ConnectionInfo),
+}
+
+impl RedisStore {
+    /// Pool of connections to a single redis node, e.g. `redis://127.0.0.1:6379`. Connections
+    /// are opened on demand, an unreachable node fails the requests and the readiness probe.
+    pub fn new(redis_url: &str) -> Result<Self, StorageError> {
+        let client = redis::Client::open(redis_url)?;
+        Ok(RedisStore::Single(r2d2::Pool::builder().build_unchecked(client)))
+    }
+
+    /// Pool of connections to a redis cluster given a comma separated list of its initial nodes.
+    pub fn new_cluster(redis_urls: &str) -> Result<Self, StorageError> {
+        let nodes: Vec<&str> = redis_urls.split(',').map(str::trim).collect();
+        let info = nodes[0].into_connection_info()?;
+        let client = ClusterClient::new(nodes)?;
+        Ok(RedisStore::Cluster(r2d2::Pool::builder().build_unchecked(client), info))
+    }
+
+    fn query<T: FromRedisValue>(&self, cmd: Cmd) -> Result<T, StorageError> {
+        match self {
+            RedisStore::Single(pool) => Ok(cmd.query(&mut *pool.get()?)?),
+            RedisStore::Cluster(pool, _) => Ok(cmd.query(&mut *pool.get()?)?),
+        }
+    }
+
+    pub fn set(&self, table: &str, key: &str, value: String) -> Result<(), StorageError> {
+        self.query(Cmd::set(redis_key(table, key), value))
+    }
+
+    pub fn set_ex(&self, table: &str, key: &str, value: String, seconds: usize) -> Result<(), StorageError> {
+        self.query(Cmd::set_ex(redis_key(table, key), value, seconds))
+    }
+
+    pub fn get(&self, table: &str, key: &str) -> Result<Option<String>, StorageError> {
+        self.query(Cmd::get(redis_key(table, key)))
+    }
+
+    /// Returns whether there was a record to delete.
+    pub fn del(&self, table: &str, key: &str) -> Result<bool, StorageError> {
+        Ok(self.query::<usize>(Cmd::del(redis_key(table, key)))? > 0)
+    }
+
+    /// Lists every (table, key) pair. SCAN is not routed across a cluster's nodes, so on redis
+    /// cluster each primary is scanned in turn over a connection of its own.
+    pub fn keys(&self) -> Result<Vec<(String, String)>, StorageError> {
+        match self {
+            RedisStore::Single(pool) => scan(&mut *pool.get()?),
+            RedisStore::Cluster(pool, info) => {
+                let nodes: String = redis::cmd("CLUSTER").arg("NODES").query(&mut *pool.get()?)?;
+                let mut keys = Vec::new();
+                for addr in cluster_primaries(&nodes, &info.addr) {
+                    let client = redis::Client::open(ConnectionInfo {
+                        addr,
+                        redis: info.redis.clone(),
+                    })?;
+                    keys.extend(scan(&mut client.get_connection()?)?);
+                }
+                Ok(keys)
+            }
+        }
+    }
+}
+
+fn scan(connection: &mut dyn redis::ConnectionLike) -> Result<Vec<(String, String)>, StorageError> {
+    Ok(Cmd::scan()
+        .iter::<String>(connection)?
+        .filter_map(|stored| parse_redis_key(&stored))
+        .collect())
+}
+
+/// Addresses of the primaries listed by `CLUSTER NODES`, reached as the initial node is,
+/// with or without TLS. Failed primaries are skipped, their replicas took over their slots.
+pub(crate) fn cluster_primaries(nodes: &str, initial: &ConnectionAddr) -> Vec<ConnectionAddr> {
+    nodes
+        .lines()
+        .filter_map(|line| {
+            // `<id> <ip:port@cport[,hostname]> <flags> ...`
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let flags: Vec<&str> = fields.get(2)?.split(',').collect();
+            if !flags.contains(&"master") || flags.iter().any(|flag| flag.starts_with("fail")) {
+                return None;
+            }
+            let address = fields.get(1)?.split(['@', ',']).next()?;
+            let (host, port) = address.rsplit_once(':')?;
+            let port = port.parse().ok()?;
+            if host.is_empty() {
+                return None;
+            }
+            let host = host.to_string();
+            Some(match initial {
+                ConnectionAddr::TcpTls { insecure, .. } => ConnectionAddr::TcpTls {
+                    host,
+                    port,
+                    insecure: *insecure,
+                },
+                _ => ConnectionAddr::Tcp(host, port),
+            })
+        })
+        .collect()
+}
+
+/// Name of a record in the single redis keyspace, `{key}_{table}` prefixed by the hex encoded
+/// customer id, the first part of an `encode_key` key, as hash tag: all the records of a
+/// customer hash to the same cluster slot, whatever braces the customer id holds. Table names
+/// never contain `_`, so the table is whatever follows the last one.
+pub(crate) fn redis_key(table: &str, key: &str) -> String {
+    match decode_key(key).as_ref().and_then(|parts| parts.first()) {
+        Some(customer_id) => format!("{{{}}}{}_{}", hex::encode(customer_id), key, table),
+        None => format!("{}_{}", key, table),
+    }
+}
+
+/// The (table, key) pair named by `redis_key`.
+pub(crate) fn parse_redis_key(stored: &str) -> Option<(String, String)> {
+    let (name, table) = stored.rsplit_once('_')?;
+    // The hex encoded tag holds no brace, the key follows the first closing one
+    let tagged = name
+        .strip_prefix('{')
+        .and_then(|tagged| tagged.split_once('}'))
+        .map(|(_, key)| key.to_string());
+    [tagged, Some(name.to_string())]
+        .into_iter()
+        .flatten()
+        .map(|key| (table.to_string(), key))
+        .find(|(table, key)| redis_key(table, key) == stored)
+}
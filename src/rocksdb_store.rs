@@ -0,0 +1,240 @@
+//!RocksDB storage backend, one column family per MPCStruct table
+
+use std::path::Path;
+use std::string::String;
+use std::time::Duration;
+
+use rocksdb::checkpoint::Checkpoint;
+use rocksdb::{
+    ColumnFamilyDescriptor, DBCompressionType, DBWithThreadMode, IteratorMode, MultiThreaded,
+    Options, WriteBatch,
+};
+
+use crate::error::StorageError;
+use crate::expiry::unix_now;
+use crate::public_gotham::is_ephemeral_table;
+
+/// Column families are created on first use, which needs the multi threaded flavour of RocksDB.
+pub type RocksDb = DBWithThreadMode<MultiThreaded>;
+
+const DEFAULT_FAMILY: &str = "default";
+
+/// Records of ephemeral tables are prefixed with this marker and their write time, in big
+/// endian unix seconds. Stored records are text, so they can never start with a NUL byte.
+const WRITTEN_AT_MARKER: &[u8] = b"\0ts";
+
+/// Written to the default column family once its flat records are moved, so that later
+/// opens skip the scan. Flat keys are text too, so none can collide with it.
+const LAYOUT_MIGRATED_MARKER: &[u8] = b"\0layout_migrated";
+
+fn stamp(value: &[u8], written_at: u64) -> Vec<u8> {
+    [WRITTEN_AT_MARKER, &written_at.to_be_bytes(), value].concat()
+}
+
+/// Splits a stored value into its write time, if stamped, and the record.
+fn unstamp(raw: &[u8]) -> (Option<u64>, &[u8]) {
+    let header_len = WRITTEN_AT_MARKER.len() + 8;
+    if raw.len() < header_len || !raw.starts_with(WRITTEN_AT_MARKER) {
+        return (None, raw);
+    }
+    let mut written_at = [0u8; 8];
+    written_at.copy_from_slice(&raw[WRITTEN_AT_MARKER.len()..header_len]);
+    (Some(u64::from_be_bytes(written_at)), &raw[header_len..])
+}
+
+pub struct RocksDbStore {
+    db: RocksDb,
+}
+
+/// Per table tuning: ephemeral protocol state is small and short lived, master key material
+/// is read on every sign and kept forever.
+fn family_options(table: &str) -> Options {
+    let mut opts = Options::default();
+    if is_ephemeral_table(table) {
+        opts.set_write_buffer_size(4 << 20);
+        opts.set_compression_type(DBCompressionType::None);
+    } else {
+        opts.optimize_for_point_lookup(64);
+        opts.set_level_compaction_dynamic_level_bytes(true);
+        opts.set_paranoid_checks(true);
+    }
+    opts
+}
+
+impl RocksDbStore {
+    /// Opens the store with all of its existing column families and, on the first open only,
+    /// splits records of a database written with the flat `{customer_id}_{id}_{table}` layout.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, StorageError> {
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        opts.create_missing_column_families(true);
+
+        let families = RocksDb::list_cf(&opts, &path).unwrap_or_default();
+        let descriptors = families
+            .iter()
+            .map(|name| ColumnFamilyDescriptor::new(name, family_options(name)));
+        let db = RocksDb::open_cf_descriptors(&opts, &path, descriptors)?;
+
+        let store = RocksDbStore { db };
+        if store.db.get_pinned(LAYOUT_MIGRATED_MARKER)?.is_none() {
+            let migrated = store.migrate_flat_layout()?;
+            if migrated > 0 {
+                log::info!("Moved {} flat records to their table column family", migrated);
+            }
+            store.db.put(LAYOUT_MIGRATED_MARKER, [])?;
+        }
+        Ok(store)
+    }
+
+    /// Opens the store of a stopped server without writing to it, not even to migrate records.
+    pub fn open_read_only<P: AsRef<Path>>(path: P) -> Result<Self, StorageError> {
+        let opts = Options::default();
+        let families = RocksDb::list_cf(&opts, &path)?;
+        let db = RocksDb::open_cf_for_read_only(&opts, &path, families, false)?;
+        Ok(RocksDbStore { db })
+    }
+
+    fn family(&self, table: &str) -> Result<std::sync::Arc<rocksdb::BoundColumnFamily>, StorageError> {
+        if let Some(cf) = self.db.cf_handle(table) {
+            return Ok(cf);
+        }
+        // A concurrent writer may have created it in the meantime
+        if let Err(e) = self.db.create_cf(table, &family_options(table)) {
+            if self.db.cf_handle(table).is_none() {
+                return Err(e.into());
+            }
+        }
+        Ok(self.db.cf_handle(table).expect("column family was just created"))
+    }
+
+    pub fn put<V: AsRef<[u8]>>(&self, table: &str, key: String, value: V) -> Result<(), StorageError> {
+        let cf = self.family(table)?;
+        if is_ephemeral_table(table) {
+            self.db.put_cf(&cf, key, stamp(value.as_ref(), unix_now()))?;
+        } else {
+            self.db.put_cf(&cf, key, value)?;
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, table: &str, key: String) -> Result<Option<Vec<u8>>, StorageError> {
+        match self.db.cf_handle(table) {
+            Some(cf) => Ok(self
+                .db
+                .get_cf(&cf, key)?
+                .map(|raw| unstamp(&raw).1.to_vec())),
+            None => Ok(None),
+        }
+    }
+
+    /// Tables that have a column family, other than the default one.
+    pub fn tables(&self) -> Result<Vec<String>, StorageError> {
+        Ok(RocksDb::list_cf(&Options::default(), self.db.path())?
+            .into_iter()
+            .filter(|table| table != DEFAULT_FAMILY)
+            .collect())
+    }
+
+    /// Deletes records of an ephemeral table last written more than `ttl` before `now`.
+    /// Records written before stamping was introduced are stamped with `now` instead.
+    pub fn purge_expired(&self, table: &str, ttl: Duration, now: u64) -> Result<usize, StorageError> {
+        if !is_ephemeral_table(table) {
+            return Ok(0);
+        }
+        let cf = match self.db.cf_handle(table) {
+            Some(cf) => cf,
+            None => return Ok(0),
+        };
+        let mut purged = 0;
+        let mut batch = WriteBatch::default();
+        for item in self.db.iterator_cf(&cf, IteratorMode::Start) {
+            let (key, raw) = item?;
+            match unstamp(&raw) {
+                (Some(written_at), _) if written_at.saturating_add(ttl.as_secs()) < now => {
+                    batch.delete_cf(&cf, &key);
+                    purged += 1;
+                }
+                (Some(_), _) => {}
+                (None, record) => batch.put_cf(&cf, &key, stamp(record, now)),
+            }
+        }
+        self.db.write(batch)?;
+        Ok(purged)
+    }
+
+    pub fn delete(&self, table: &str, key: String) -> Result<(), StorageError> {
+        match self.db.cf_handle(table) {
+            Some(cf) => Ok(self.db.delete_cf(&cf, key)?),
+            None => Ok(()),
+        }
+    }
+
+    /// Deletes the records stored under any of `keys` in every table, then flushes and compacts
+    /// the tables they were found in so that their data is dropped from the SST files.
+    /// Returns how many records were deleted.
+    pub fn purge(&self, keys: &[String]) -> Result<usize, StorageError> {
+        let mut purged = 0;
+        for table in self.tables()? {
+            let cf = self.family(&table)?;
+            let mut batch = WriteBatch::default();
+            for key in keys {
+                if self.db.get_pinned_cf(&cf, key)?.is_some() {
+                    batch.delete_cf(&cf, key);
+                    purged += 1;
+                }
+            }
+            if batch.is_empty() {
+                continue;
+            }
+            self.db.write(batch)?;
+            self.db.flush_cf(&cf)?;
+            self.db.compact_range_cf(&cf, None::<&[u8]>, None::<&[u8]>);
+        }
+        Ok(purged)
+    }
+
+    /// Writes a consistent copy of the store to `path`, hard linking its SST files.
+    pub fn checkpoint<P: AsRef<Path>>(&self, path: P) -> Result<(), StorageError> {
+        Checkpoint::new(&self.db)?.create_checkpoint(path)?;
+        Ok(())
+    }
+
+    /// Every (table, key) pair in the store.
+    pub fn keys(&self) -> Result<Vec<(String, String)>, StorageError> {
+        let mut keys = Vec::new();
+        for table in self.tables()? {
+            let cf = self.family(&table)?;
+            for item in self.db.iterator_cf(&cf, IteratorMode::Start) {
+                let (key, _) = item?;
+                keys.push((table.clone(), String::from_utf8(key.to_vec())?));
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Moves records keyed `{key}_{table}` in the default column family into the
+    /// `table` column family under `key`, one atomic batch per record.
+    fn migrate_flat_layout(&self) -> Result<usize, StorageError> {
+        let mut migrated = 0;
+        let flat: Vec<(Box<[u8]>, Box<[u8]>)> = self
+            .db
+            .iterator(IteratorMode::Start)
+            .collect::<Result<_, _>>()?;
+        for (flat_key, value) in flat {
+            let flat_key = String::from_utf8(flat_key.to_vec())?;
+            let (key, table) = match flat_key.rsplit_once('_') {
+                Some((key, table)) if !table.is_empty() => (key, table),
+                _ => {
+                    log::warn!("Leaving unrecognized record {} in the default column family", flat_key);
+                    continue;
+                }
+            };
+            let mut batch = WriteBatch::default();
+            batch.put_cf(&self.family(table)?, key, value);
+            batch.delete(&flat_key);
+            self.db.write(batch)?;
+            migrated += 1;
+        }
+        Ok(migrated)
+    }
+}
@@ -0,0 +1,285 @@
+//!Coin flip based rotation of both master key shares, keeping the joint public key
+
+use std::string::String;
+use std::sync::Arc;
+
+use rocket::serde::json::{json, Json, Value};
+use rocket::{post, State};
+use serde::{Deserialize, Serialize};
+
+use gotham_engine::types::DbIndex;
+use two_party_ecdsa::curv::cryptographic_primitives::twoparty::coin_flip_optimal_rounds;
+use two_party_ecdsa::curv::{FE, GE};
+use two_party_ecdsa::kms::ecdsa::two_party::party1::RotationParty1Message1;
+use two_party_ecdsa::kms::ecdsa::two_party::MasterKey1;
+use two_party_ecdsa::kms::rotation::two_party::party1::Rotation1;
+use two_party_ecdsa::kms::rotation::two_party::Rotation;
+use two_party_ecdsa::{party_one, party_two, BigInt};
+
+use crate::api_error::{ApiError, ErrorCode};
+use crate::audit::AuditEvent;
+use crate::auth::AuthenticatedCustomer;
+use crate::error::StorageError;
+use crate::keys::validate_id;
+use crate::protocol::locked;
+use crate::public_gotham::{KeyLocks, PublicGotham};
+
+/// Party one's state between rotation steps, filled in as the protocol progresses.
+#[derive(Serialize, Deserialize)]
+pub struct RotationSession {
+    m1: GE,
+    r1: FE,
+    random1: Option<Rotation>,
+    first_message: Option<RotationParty1Message1>,
+    private_new: Option<party_one::Party1Private>,
+    party_two_pdl_first_message: Option<party_two::PDLFirstMessage>,
+    pdl_decommit: Option<party_one::PDLdecommit>,
+    alpha: Option<BigInt>,
+}
+
+/// Storage the rotation protocol needs from a Db backend.
+pub trait RotationStore {
+    fn master_key(&self, key: &DbIndex) -> Result<Option<MasterKey1>, StorageError>;
+    fn rotation_session(&self, key: &DbIndex) -> Result<Option<RotationSession>, StorageError>;
+    fn set_rotation_session(&self, key: &DbIndex, session: &RotationSession) -> Result<(), StorageError>;
+    /// Keeps the rotated share next to the current one until the client confirms it.
+    fn stage_rotated_key(&self, key: &DbIndex, master_key: &MasterKey1) -> Result<(), StorageError>;
+    /// Replaces the current share by the staged one, returning false when none is staged.
+    fn confirm_rotated_key(&self, key: &DbIndex) -> Result<bool, StorageError>;
+}
+
+/// Party one of the share rotation, after the pattern of the engine's `KeyGen` and `Sign`.
+/// The client keeps signing with its current share until it confirms the rotation.
+pub trait Rotate: RotationStore {
+    fn rotate_first(
+        &self,
+        key: &DbIndex,
+    ) -> Result<coin_flip_optimal_rounds::Party1FirstMessage, ApiError> {
+        self.current_master_key(key)?;
+        let (party1_coin_flip_first_message, m1, r1) = Rotation1::key_rotate_first_message();
+        let session = RotationSession {
+            m1,
+            r1,
+            random1: None,
+            first_message: None,
+            private_new: None,
+            party_two_pdl_first_message: None,
+            pdl_decommit: None,
+            alpha: None,
+        };
+        self.set_rotation_session(key, &session).map_err(storage_failure)?;
+        Ok(party1_coin_flip_first_message)
+    }
+
+    fn rotate_second(
+        &self,
+        key: &DbIndex,
+        party2_first_message: &coin_flip_optimal_rounds::Party2FirstMessage,
+    ) -> Result<(coin_flip_optimal_rounds::Party1SecondMessage, RotationParty1Message1), ApiError> {
+        let master_key = self.current_master_key(key)?;
+        let mut session = self.session(key)?;
+        let (party1_second_message, random1) =
+            Rotation1::key_rotate_second_message(party2_first_message, &session.m1, &session.r1);
+        let (rotation_party_one_first_message, party_one_private_new) =
+            master_key.rotation_first_message(&random1);
+
+        session.random1 = Some(random1);
+        session.first_message = Some(rotation_party_one_first_message);
+        session.private_new = Some(party_one_private_new);
+        self.set_rotation_session(key, &session).map_err(storage_failure)?;
+        Ok((party1_second_message, session.first_message.expect("first message was just set")))
+    }
+
+    fn rotate_third(
+        &self,
+        key: &DbIndex,
+        rotation_party_two_first_message: party_two::PDLFirstMessage,
+    ) -> Result<party_one::PDLFirstMessage, ApiError> {
+        let mut session = self.session(key)?;
+        let party_one_private_new = session
+            .private_new
+            .as_ref()
+            .ok_or_else(|| out_of_order(key))?;
+        let (rotation_party_one_second_message, party_one_pdl_decommit, alpha) =
+            MasterKey1::rotation_second_message(
+                &rotation_party_two_first_message,
+                party_one_private_new,
+            );
+
+        session.party_two_pdl_first_message = Some(rotation_party_two_first_message);
+        session.pdl_decommit = Some(party_one_pdl_decommit);
+        session.alpha = Some(alpha);
+        self.set_rotation_session(key, &session).map_err(storage_failure)?;
+        Ok(rotation_party_one_second_message)
+    }
+
+    fn rotate_fourth(
+        &self,
+        key: &DbIndex,
+        rotation_party_two_second_message: &party_two::PDLSecondMessage,
+    ) -> Result<party_one::PDLSecondMessage, ApiError> {
+        let master_key = self.current_master_key(key)?;
+        let RotationSession {
+            first_message: Some(first_message),
+            private_new: Some(private_new),
+            random1: Some(random1),
+            party_two_pdl_first_message: Some(party_two_pdl_first_message),
+            pdl_decommit: Some(pdl_decommit),
+            alpha: Some(alpha),
+            ..
+        } = self.session(key)?
+        else {
+            return Err(out_of_order(key));
+        };
+
+        let (rotation_party_one_third_message, party_one_master_key_rotated) = master_key
+            .rotation_third_message(
+                &first_message,
+                private_new,
+                &random1,
+                &party_two_pdl_first_message,
+                rotation_party_two_second_message,
+                pdl_decommit,
+                alpha,
+            )
+            .map_err(|_| {
+                ApiError::new(ErrorCode::ProofFailed, "Rotation PDL proof verification failed")
+            })?;
+
+        self.stage_rotated_key(key, &party_one_master_key_rotated)
+            .map_err(storage_failure)?;
+        Ok(rotation_party_one_third_message)
+    }
+
+    fn rotate_confirm(&self, key: &DbIndex) -> Result<(), ApiError> {
+        if !self.confirm_rotated_key(key).map_err(storage_failure)? {
+            return Err(out_of_order(key));
+        }
+        Ok(())
+    }
+
+    fn current_master_key(&self, key: &DbIndex) -> Result<MasterKey1, ApiError> {
+        self.master_key(key)
+            .map_err(storage_failure)?
+            .ok_or_else(|| unknown_id(key))
+    }
+
+    fn session(&self, key: &DbIndex) -> Result<RotationSession, ApiError> {
+        self.rotation_session(key)
+            .map_err(storage_failure)?
+            .ok_or_else(|| out_of_order(key))
+    }
+}
+
+impl Rotate for PublicGotham {}
+
+fn unknown_id(key: &DbIndex) -> ApiError {
+    ApiError::new(ErrorCode::UnknownId, format!("Unknown id '{}'", key.id))
+}
+
+fn out_of_order(key: &DbIndex) -> ApiError {
+    ApiError::new(
+        ErrorCode::StepOutOfOrder,
+        format!("No rotation in progress for id '{}', a previous step is missing", key.id),
+    )
+}
+
+fn storage_failure(e: StorageError) -> ApiError {
+    log::error!("Rotation storage failed: {}", e);
+    ApiError::new(ErrorCode::from(&e), "Storage backend failure")
+}
+
+fn index(customer: &AuthenticatedCustomer, id: String) -> Result<DbIndex, ApiError> {
+    validate_id(&id).map_err(|e| ApiError::new(ErrorCode::InvalidId, e.to_string()))?;
+    Ok(DbIndex {
+        customer_id: customer.customer_id.clone(),
+        id,
+    })
+}
+
+#[post("/ecdsa/rotate/<id>/first", format = "json")]
+pub async fn rotate_first(
+    gotham: &State<Arc<PublicGotham>>,
+    locks: &State<KeyLocks>,
+    customer: AuthenticatedCustomer,
+    id: String,
+) -> Result<Json<coin_flip_optimal_rounds::Party1FirstMessage>, ApiError> {
+    let (gotham, key) = (gotham.inner().clone(), index(&customer, id)?);
+    locked(locks, key, move |key| gotham.rotate_first(&key).map(Json)).await?
+}
+
+#[post("/ecdsa/rotate/<id>/second", format = "json", data = "<party2_first_message>")]
+pub async fn rotate_second(
+    gotham: &State<Arc<PublicGotham>>,
+    locks: &State<KeyLocks>,
+    customer: AuthenticatedCustomer,
+    id: String,
+    party2_first_message: Json<coin_flip_optimal_rounds::Party2FirstMessage>,
+) -> Result<Json<(coin_flip_optimal_rounds::Party1SecondMessage, RotationParty1Message1)>, ApiError> {
+    let (gotham, key) = (gotham.inner().clone(), index(&customer, id)?);
+    locked(locks, key, move |key| {
+        gotham.rotate_second(&key, &party2_first_message).map(Json)
+    })
+    .await?
+}
+
+#[post("/ecdsa/rotate/<id>/third", format = "json", data = "<rotation_party_two_first_message>")]
+pub async fn rotate_third(
+    gotham: &State<Arc<PublicGotham>>,
+    locks: &State<KeyLocks>,
+    customer: AuthenticatedCustomer,
+    id: String,
+    rotation_party_two_first_message: Json<party_two::PDLFirstMessage>,
+) -> Result<Json<party_one::PDLFirstMessage>, ApiError> {
+    let (gotham, key) = (gotham.inner().clone(), index(&customer, id)?);
+    locked(locks, key, move |key| {
+        gotham
+            .rotate_third(&key, rotation_party_two_first_message.into_inner())
+            .map(Json)
+    })
+    .await?
+}
+
+#[post("/ecdsa/rotate/<id>/fourth", format = "json", data = "<rotation_party_two_second_message>")]
+pub async fn rotate_fourth(
+    gotham: &State<Arc<PublicGotham>>,
+    locks: &State<KeyLocks>,
+    customer: AuthenticatedCustomer,
+    id: String,
+    rotation_party_two_second_message: Json<party_two::PDLSecondMessage>,
+) -> Result<Json<party_one::PDLSecondMessage>, ApiError> {
+    let (gotham, key) = (gotham.inner().clone(), index(&customer, id)?);
+    locked(locks, key, move |key| {
+        gotham
+            .rotate_fourth(&key, &rotation_party_two_second_message)
+            .map(Json)
+    })
+    .await?
+}
+
+/// Sent once the client stored its rotated share; the previous shares are discarded.
+#[post("/ecdsa/rotate/<id>/confirm")]
+pub async fn rotate_confirm(
+    gotham: &State<Arc<PublicGotham>>,
+    locks: &State<KeyLocks>,
+    customer: AuthenticatedCustomer,
+    id: String,
+) -> Result<Json<Value>, ApiError> {
+    let (gotham, key) = (gotham.inner().clone(), index(&customer, id)?);
+    locked(locks, key, move |key| confirm(&gotham, key)).await?
+}
+
+fn confirm(gotham: &PublicGotham, key: DbIndex) -> Result<Json<Value>, ApiError> {
+    gotham.rotate_confirm(&key)?;
+    gotham
+        .audit(AuditEvent::new(
+            &key.customer_id,
+            "rotate_key",
+            &key.customer_id,
+            Some(&key.id),
+            json!({}),
+        ))
+        .map_err(storage_failure)?;
+    log::info!("Rotated the master key shares of {}", key.id);
+    Ok(Json(json!({ "id": key.id, "rotated": true })))
+}
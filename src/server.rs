@@ -1,54 +1,285 @@
-use crate::public_gotham::{Config, PublicGotham, DB};
+use crate::admin::AdminAuth;
+use crate::api_error::{ApiError, ErrorCode, RequestIdFairing};
+use crate::audit::AuditLog;
+use crate::backup::{scheduler, Backups};
+use crate::auth::{auth_loader, authenticated};
+use crate::encryption::{MasterKeyError, MasterKeys};
+use crate::error::StorageError;
+use crate::expiry::{sweeper, SessionTtl};
+use crate::lifecycle::guarded;
+use crate::metrics::MetricsFairing;
+use crate::public_gotham::{Config, KeyLocks, PublicGotham, DB};
+use crate::policy::PolicyEngine;
+use crate::recovery::{parse_recovery_public_key, RecoveryError};
+use crate::shares::SharePolicy;
+use crate::telemetry::traced;
+use crate::redis_store::RedisStore;
+use crate::rocksdb_store::RocksDbStore;
+use rocket::http::Status;
 use rocket::{self, catch, routes, Build, Request, Rocket, catchers};
 use std::collections::HashMap;
-use tokio::sync::Mutex;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+
+/// Why the server could not be configured from its settings.
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error(transparent)]
+    MasterKeys(#[from] MasterKeyError),
+    #[error("Unable to open the audit log: {0}")]
+    AuditLog(#[source] StorageError),
+    #[error("recovery_public_key must be a hex encoded SEC1 public key: {0}")]
+    RecoveryKey(#[from] RecoveryError),
+    #[error("DB name is illegal, may only contain alphanumeric characters")]
+    IllegalDbName,
+    #[error("Unsupported db backend '{0}'")]
+    UnsupportedBackend(String),
+    #[error("Unable to open the store: {0}")]
+    Store(#[source] StorageError),
+    #[error("Master key migration failed: {0}")]
+    Reencryption(#[source] StorageError),
+    #[error("{0}")]
+    Setting(String),
+}
 
 #[catch(500)]
-fn internal_error() -> &'static str {
-    "Internal server error"
+fn internal_error() -> ApiError {
+    ApiError::new(ErrorCode::Internal, "Internal server error")
 }
 
 #[catch(400)]
-fn bad_request() -> &'static str {
-    "Bad request"
+fn bad_request() -> ApiError {
+    ApiError::new(ErrorCode::BadRequest, "Bad request")
+}
+
+#[catch(401)]
+fn unauthorized() -> ApiError {
+    ApiError::new(ErrorCode::Unauthorized, "Missing or invalid bearer token")
 }
 
 #[catch(404)]
-fn not_found(req: &Request) -> String {
-    format!("Unknown route '{}'.", req.uri())
+fn not_found(req: &Request) -> ApiError {
+    ApiError::new(ErrorCode::RouteNotFound, format!("Unknown route '{}'.", req.uri()))
 }
 
-pub fn get_server(settings: HashMap<String, String>) -> Rocket<Build> {
+/// Raised by the JSON data guard when a request body does not deserialize.
+#[catch(422)]
+fn unprocessable_entity() -> ApiError {
+    ApiError::new(ErrorCode::MalformedRequest, "Request body is malformed")
+}
 
-    // let settings = get_settings_as_map();
-    let db_config = Config {
-        db: get_db(settings.clone()),
+/// Answers with the status that was raised, under the closest error code.
+#[catch(default)]
+fn default_catcher(status: Status, _: &Request) -> ApiError {
+    let code = match status.code {
+        403 => ErrorCode::Forbidden,
+        503 => ErrorCode::Unavailable,
+        code if code >= 500 => ErrorCode::Internal,
+        _ => ErrorCode::BadRequest,
     };
-    let x = PublicGotham::new();
-    rocket::Rocket::build()
-        .register("/", catchers![internal_error, not_found, bad_request])
-        .mount(
+    ApiError::new(code, status.reason().unwrap_or("Request failed")).with_status(status)
+}
+
+/// Settings.toml overridden by the environment.
+pub fn get_settings_as_map() -> HashMap<String, String> {
+    let config_file = include_str!("../Settings.toml");
+    let mut settings = config::Config::default();
+    settings
+        .merge(config::File::from_str(
+            config_file,
+            config::FileFormat::Toml,
+        ))
+        .unwrap()
+        .merge(config::Environment::new())
+        .unwrap();
+
+    settings.try_into::<HashMap<String, String>>().unwrap()
+}
+
+/// Store configuration described by `settings`.
+pub fn get_config(settings: &HashMap<String, String>) -> Result<Config, ConfigError> {
+    Ok(Config {
+        db: get_db(settings.clone())?,
+        master_keys: get_master_keys(settings)?,
+        share_policy: SharePolicy::from_setting(
+            settings.get("share_policy").map(String::as_str).unwrap_or_default(),
+        )
+        .map_err(ConfigError::Setting)?,
+        session_ttl: SessionTtl::parse(
+            settings.get("session_ttl_secs").map(String::as_str),
+            settings.get("session_ttls").map(String::as_str),
+        )
+        .map_err(ConfigError::Setting)?,
+        audit_log: AuditLog::open(audit_log_path(settings)).map_err(ConfigError::AuditLog)?,
+        recovery_key: settings
+            .get("recovery_public_key")
+            .map(String::as_str)
+            .filter(|key| !key.is_empty())
+            .map(parse_recovery_public_key)
+            .transpose()?,
+    })
+}
+
+pub fn get_server(settings: HashMap<String, String>) -> Result<Rocket<Build>, ConfigError> {
+    let db_config = get_config(&settings)?;
+    let sweep_interval = Duration::from_secs(
+        settings
+            .get("sweep_interval_secs")
+            .map(|secs| {
+                secs.parse()
+                    .map_err(|_| ConfigError::Setting("sweep_interval_secs must be a number of seconds".to_string()))
+            })
+            .transpose()?
+            .unwrap_or(300),
+    );
+    let sessions_expire = db_config.session_ttl.is_enabled();
+    let gotham = Arc::new(PublicGotham::new(db_config));
+    if settings.get("reencrypt_on_start").map(String::as_str) == Some("true") {
+        let rewritten = gotham.reencrypt_all().map_err(ConfigError::Reencryption)?;
+        log::info!("Migrated {} records to the active master key", rewritten);
+    }
+    match gotham.migrate_legacy_audit_events() {
+        Ok(0) => {}
+        Ok(migrated) => log::info!("Moved {} audit events of the key store to the audit log", migrated),
+        // Retried on the next start
+        Err(e) => log::error!("Unable to move the audit events of the key store to the audit log: {}", e),
+    }
+    let backups = Arc::new(Backups::new(&settings).map_err(ConfigError::Setting)?);
+    let backup_interval = settings
+        .get("backup_interval_secs")
+        .filter(|secs| !secs.is_empty())
+        .map(|secs| match secs.parse() {
+            Ok(secs) if secs > 0 => Ok(Duration::from_secs(secs)),
+            _ => Err(ConfigError::Setting(
+                "backup_interval_secs must be a positive number of seconds".to_string(),
+            )),
+        })
+        .transpose()?;
+    let mut server = rocket::Rocket::build();
+    if sessions_expire {
+        server = server.attach(sweeper(gotham.clone(), sweep_interval));
+    }
+    if let (Some(interval), Some(_)) = (backup_interval, &backups.0) {
+        server = server.attach(scheduler(gotham.clone(), backups.clone(), interval));
+    }
+    Ok(server
+        .register(
             "/",
-            routes![
-                gotham_engine::routes::wrap_keygen_first,
-                gotham_engine::routes::wrap_keygen_second,
-                gotham_engine::routes::wrap_keygen_third,
-                gotham_engine::routes::wrap_keygen_fourth,
-                gotham_engine::routes::wrap_chain_code_first_message,
-                gotham_engine::routes::wrap_chain_code_second_message,
-                gotham_engine::routes::wrap_sign_first,
-                gotham_engine::routes::wrap_sign_second,
+            catchers![
+                internal_error,
+                not_found,
+                bad_request,
+                unauthorized,
+                unprocessable_entity,
+                default_catcher
             ],
         )
-        .manage(Mutex::new(Box::new(x) as Box<dyn gotham_engine::traits::Db>))
-        .manage(db_config)
+        .attach(RequestIdFairing)
+        .attach(MetricsFairing)
+        .attach(auth_loader(settings.clone()))
+        .mount(
+            "/",
+            traced(routes![
+                crate::health::live,
+                crate::health::ready,
+                crate::metrics::export,
+                crate::policy::get_policy,
+                crate::policy::put_policy,
+                crate::policy::delete_policy,
+                crate::lifecycle::admin_deactivate_key,
+                crate::lifecycle::admin_delete_key,
+                crate::recovery::admin_put_escrow,
+                crate::audit::export,
+                crate::backup::create_backup,
+            ]),
+        )
+        .mount(
+            "/",
+            traced(authenticated(routes![
+                crate::protocol::wrap_keygen_first,
+                crate::protocol::wrap_keygen_second,
+                crate::protocol::wrap_keygen_third,
+                crate::protocol::wrap_keygen_fourth,
+                crate::protocol::wrap_chain_code_first_message,
+                crate::protocol::wrap_chain_code_second_message,
+                crate::protocol::wrap_sign_first,
+                crate::metadata::get_key,
+                crate::metadata::get_child_key,
+                crate::metadata::list_keys,
+                crate::metadata::put_labels,
+                crate::lifecycle::deactivate_key,
+                crate::lifecycle::delete_key,
+                crate::recovery::get_escrow,
+            ])),
+        )
+        // Routes using the share of their key, which retired keys may no longer do
+        .mount(
+            "/",
+            traced(authenticated(guarded(routes![
+                crate::policy::wrap_sign_second,
+                crate::rotate::rotate_first,
+                crate::rotate::rotate_second,
+                crate::rotate::rotate_third,
+                crate::rotate::rotate_fourth,
+                crate::rotate::rotate_confirm,
+                crate::recovery::put_escrow,
+            ]))),
+        )
+        .manage(KeyLocks::new(gotham.clone()))
+        .manage(gotham)
+        .manage(AdminAuth::new(&settings))
+        .manage(Arc::new(PolicyEngine::default()))
+        .manage(backups))
 }
 
-fn get_db(settings: HashMap<String, String>) -> DB {
-    let db_name = settings.get("db_name").unwrap_or(&"db".to_string()).clone();
-    if !db_name.chars().all(|e| char::is_ascii_alphanumeric(&e)) {
-        panic!("DB name is illegal, may only contain alphanumeric characters");
+fn get_db(settings: HashMap<String, String>) -> Result<DB, ConfigError> {
+    let default_url = "redis://127.0.0.1:6379".to_string();
+    match settings.get("db").map(String::as_str).unwrap_or("local") {
+        "local" => {
+            let db_name = settings.get("db_name").unwrap_or(&"db".to_string()).clone();
+            if !db_name.chars().all(|e| char::is_ascii_alphanumeric(&e)) {
+                return Err(ConfigError::IllegalDbName);
+            }
+
+            Ok(DB::Local(RocksDbStore::open(format!("./{}", db_name)).map_err(ConfigError::Store)?))
+        }
+        "redis" => {
+            let redis_url = settings.get("redis_url").unwrap_or(&default_url);
+            Ok(DB::Redis(RedisStore::new(redis_url).map_err(ConfigError::Store)?))
+        }
+        "redis_cluster" => {
+            let redis_url = settings.get("redis_url").unwrap_or(&default_url);
+            Ok(DB::Redis(RedisStore::new_cluster(redis_url).map_err(ConfigError::Store)?))
+        }
+        other => Err(ConfigError::UnsupportedBackend(other.to_string())),
     }
+}
+
+/// The audit log is a RocksDB database of its own, at `audit_log_path` or `./{db_name}_audit`.
+fn audit_log_path(settings: &HashMap<String, String>) -> String {
+    match settings.get("audit_log_path").filter(|path| !path.is_empty()) {
+        Some(path) => path.clone(),
+        None => format!("./{}_audit", settings.get("db_name").map(String::as_str).unwrap_or("db")),
+    }
+}
+
+/// The keyring is read from the `master_key_file` setting, or inline from `master_key`
+/// (set through the MASTER_KEY environment variable).
+pub fn get_master_keys(settings: &HashMap<String, String>) -> Result<Option<MasterKeys>, MasterKeyError> {
+    let keyring = match settings.get("master_key_file").filter(|path| !path.is_empty()) {
+        Some(path) => std::fs::read_to_string(path).map_err(|e| MasterKeyError::File(path.clone(), e))?,
+        None => settings.get("master_key").cloned().unwrap_or_default(),
+    };
+    if keyring.trim().is_empty() {
+        return Ok(None);
+    }
+    let active_key_id = settings
+        .get("master_key_id")
+        .map(String::as_str)
+        .filter(|key_id| !key_id.is_empty());
+
+    let migrate_plaintext = settings.get("migrate_plaintext").map(String::as_str) == Some("true");
 
-    DB::Local(rocksdb::DB::open_default(format!("./{}", db_name)).unwrap())
+    Ok(Some(MasterKeys::parse(&keyring, active_key_id)?.accepting_plaintext(migrate_plaintext)))
 }
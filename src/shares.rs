@@ -0,0 +1,58 @@
+//!Tracking of completed keygens per customer
+
+use serde::{Deserialize, Serialize};
+use std::string::String;
+
+/// What a new keygen does when the customer already holds an active share,
+/// selected by the `share_policy` setting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SharePolicy {
+    /// Keygen is refused while the customer has an active share.
+    Reject,
+    /// Customers may hold any number of active shares.
+    AllowMultiple,
+    /// A completed keygen supersedes the customer's previous shares.
+    Replace,
+}
+
+impl SharePolicy {
+    pub fn from_setting(setting: &str) -> Result<Self, String> {
+        match setting {
+            "reject" => Ok(SharePolicy::Reject),
+            "allow_multiple" | "" => Ok(SharePolicy::AllowMultiple),
+            "replace" => Ok(SharePolicy::Replace),
+            other => Err(format!("Unknown share policy '{}'", other)),
+        }
+    }
+}
+
+impl Default for SharePolicy {
+    fn default() -> Self {
+        SharePolicy::AllowMultiple
+    }
+}
+
+/// Ids of a customer's completed keygens, stored as one record per customer.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ShareRegistry {
+    pub active: Vec<String>,
+    pub replaced: Vec<String>,
+}
+
+impl ShareRegistry {
+    /// Records a completed keygen according to the policy.
+    pub fn complete(&mut self, id: String, policy: SharePolicy) {
+        if self.active.contains(&id) {
+            return;
+        }
+        if policy == SharePolicy::Replace {
+            self.replaced.append(&mut self.active);
+        }
+        self.active.push(id);
+    }
+
+    /// Whether a new keygen must be refused for this customer.
+    pub fn blocks_keygen(&self, policy: SharePolicy) -> bool {
+        policy == SharePolicy::Reject && !self.active.is_empty()
+    }
+}
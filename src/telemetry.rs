@@ -0,0 +1,79 @@
+//!Structured tracing: JSON output, and a span carrying the request id around every route
+
+use std::collections::HashMap;
+use std::string::String;
+
+use rocket::route::{Handler, Outcome, Route};
+use rocket::{async_trait, Data, Request};
+use tracing::Instrument;
+use tracing_subscriber::EnvFilter;
+
+use crate::api_error::RequestId;
+
+/// Installs the global subscriber, which also receives `log` records, Rocket's included.
+/// `log_format` is "json" (default) or "pretty", `log_level` an env filter such as "info".
+///
+/// Spans and events only ever carry ids, table names and routes. Never record key material,
+/// records, messages to sign, tokens or settings in them.
+pub fn init(settings: &HashMap<String, String>) {
+    let filter = EnvFilter::try_new(settings.get("log_level").map(String::as_str).unwrap_or("info"))
+        .expect("log_level is not a valid filter");
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    let installed = match settings.get("log_format").map(String::as_str).unwrap_or("json") {
+        "json" => builder
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .try_init(),
+        "pretty" => builder.pretty().try_init(),
+        other => panic!("Unsupported log_format '{}'", other),
+    };
+    if let Err(e) = installed {
+        eprintln!("Tracing subscriber already installed: {}", e);
+    }
+}
+
+/// Runs a route's handler within a span holding the request id and the route name,
+/// so that the protocol step and the Db calls it makes are logged under it.
+#[derive(Clone)]
+struct Traced(Box<dyn Handler>);
+
+#[async_trait]
+impl Handler for Traced {
+    async fn handle<'r>(&self, req: &'r Request<'_>, data: Data<'r>) -> Outcome<'r> {
+        let route = req
+            .route()
+            .and_then(|route| route.name.as_deref())
+            .unwrap_or("unnamed");
+        let span = tracing::info_span!(
+            "route",
+            request_id = %RequestId::of(req).0,
+            route,
+            method = %req.method(),
+            id = tracing::field::Empty,
+        );
+        // Key ids are the first dynamic segment of the MPC routes
+        if let Some(Ok(id)) = req.param::<&str>(0) {
+            span.record("id", &id);
+        }
+        let outcome = self.0.handle(req, data).instrument(span.clone()).await;
+        span.in_scope(|| match &outcome {
+            Outcome::Success(response) => {
+                tracing::info!(status = response.status().code, "Request handled")
+            }
+            Outcome::Failure(status) => tracing::warn!(status = status.code, "Request failed"),
+            Outcome::Forward(_) => tracing::debug!("Request forwarded"),
+        });
+        outcome
+    }
+}
+
+pub fn traced(routes: Vec<Route>) -> Vec<Route> {
+    routes
+        .into_iter()
+        .map(|mut route| {
+            route.handler = Box::new(Traced(route.handler));
+            route
+        })
+        .collect()
+}
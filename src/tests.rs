@@ -2,269 +2,1536 @@
 mod tests {
     use std::collections::HashMap;
     use std::env;
-    use std::time::Instant;
+    use std::sync::Arc;
     use floating_duration::TimeFormat;
     use crate::server;
+    use crate::server::ConfigError;
+    use crate::client::{ClientError, GothamClient, HttpTransport, Method, Reply, Transport};
+    use futures::executor::block_on;
+    use crate::api_error::{classified, ApiError, ErrorCode, Lookups};
+    use crate::audit::{read_export, verify_chain, AuditError, AuditEvent, AuditLog, AuditRecord};
+    use crate::backup::{read_archive, restore, BackupError, BackupInfo, BackupKey};
+    use crate::auth::{Auth, AuthError, PASSTHROUGH_CUSTOMER_ID};
+    use rocket::http::Header;
+    use crate::encryption::{MasterKeyError, MasterKeys};
+    use crate::error::StorageError;
+    use crate::public_gotham::{idify, Config, KeyLocks, PublicGotham, DB};
+    use crate::redis_store::{cluster_primaries, parse_redis_key, redis_key};
+    use redis::ConnectionAddr;
+    use crate::rocksdb_store::RocksDbStore;
+    use crate::keys::{decode_key, encode_key};
+    use crate::offline::{ExportError, OfflineStore};
+    use crate::recovery::{generate_recovery_key, recover_private_key, RecoveryError};
+    use crate::wallet::{public_key_hex, verify_signature, Wallet, WalletError};
+    use crate::expiry::{unix_now, SessionTtl};
+    use crate::shares::SharePolicy;
+    use crate::policy::{PolicyEngine, SignAttempt, SigningPolicy};
+    use crate::metadata::{KeyPage, KeyStatus, KeySummary};
+    use gotham_engine::traits::Db;
+    use gotham_engine::types::{DatabaseError, DbIndex, EcdsaStruct};
     use rocket::{http::ContentType, http::{Status}, local::blocking::Client};
-    use two_party_ecdsa::{BigInt, party_one, party_two};
-    use two_party_ecdsa::curv::cryptographic_primitives::twoparty::dh_key_exchange_variant_with_pok_comm::{Party1FirstMessage, Party1SecondMessage};
-    use two_party_ecdsa::kms::ecdsa::two_party::{MasterKey2, party1};
-    use two_party_ecdsa::kms::chain_code::two_party::party2::ChainCode2;
+    use two_party_ecdsa::{BigInt, party_one};
+    use two_party_ecdsa::kms::ecdsa::two_party::MasterKey2;
     use two_party_ecdsa::kms::ecdsa;
     use gotham_engine::types::SignSecondMsgRequest;
     use two_party_ecdsa::party_one::Converter;
+    use two_party_ecdsa::curv::elliptic::curves::traits::{ECPoint, ECScalar};
+    use two_party_ecdsa::curv::{FE, GE};
 
     fn key_gen(client: &Client) -> (String, MasterKey2) {
-        let response = client
-            .post("/ecdsa/keygen/first")
-            .header(ContentType::JSON)
-            .dispatch();
-        assert_eq!(response.status(), Status::Ok);
-        let res_body = response.into_string().unwrap();
+        block_on(GothamClient::new(client).key_gen()).expect("keygen")
+    }
 
-        let (id, kg_party_one_first_message): (String, party_one::KeyGenFirstMsg) =
-            serde_json::from_str(&res_body).unwrap();
+    fn sign(
+        client: &Client,
+        id: String,
+        master_key_2: MasterKey2,
+        message: BigInt,
+    ) -> party_one::SignatureRecid {
+        let (x_pos, y_pos) = (BigInt::from(0u32), BigInt::from(21u32));
+        block_on(GothamClient::new(client).sign(&id, &master_key_2, &message, &x_pos, &y_pos)).expect("signing")
+    }
 
+    /// A store of a test's own under a unique name, alphanumeric as the `db_name` setting takes
+    /// it, removed with its audit log and the test's other files once dropped. Bound before the
+    /// server or store using it, so that it is dropped last.
+    struct TestDb {
+        name: String,
+        path: String,
+    }
 
-        let (kg_party_two_first_message, kg_ec_key_pair_party2) = MasterKey2::key_gen_first_message();
+    impl TestDb {
+        fn new(prefix: &str) -> Self {
+            let name = format!("{}{}", prefix, uuid::Uuid::new_v4().to_simple());
+            TestDb {
+                path: format!("./{}", name),
+                name,
+            }
+        }
+
+        fn name(&self) -> &str {
+            &self.name
+        }
+
+        fn path(&self) -> &str {
+            &self.path
+        }
+
+        fn audit_path(&self) -> String {
+            format!("{}_audit", self.path)
+        }
+
+        /// Where the test keeps other files, such as wallets and backups.
+        fn file(&self, name: &str) -> std::path::PathBuf {
+            let dir = std::path::PathBuf::from(format!("{}_files", self.path));
+            std::fs::create_dir_all(&dir).unwrap();
+            dir.join(name)
+        }
+    }
 
-        /*************** END: FIRST MESSAGE ***************/
+    impl Drop for TestDb {
+        fn drop(&mut self) {
+            for dir in [self.path.clone(), self.audit_path(), format!("{}_files", self.path)] {
+                let _ = std::fs::remove_dir_all(dir);
+            }
+        }
+    }
 
-        /*************** START: SECOND MESSAGE ***************/
-        let body = serde_json::to_string(&kg_party_two_first_message.d_log_proof).unwrap();
-        let response = client
-            .post(format!("/ecdsa/keygen/{}/second", id))
-            .body(body)
-            .header(ContentType::JSON)
-            .dispatch();
-        assert_eq!(response.status(), Status::Ok);
+    #[test]
+    fn key_gen_and_sign() {
+        // Passthrough mode
+        env::set_var("region", "");
+        env::set_var("pool_id", "");
+        env::set_var("issuer", "");
+        env::set_var("audience", "");
+        // env::set_var("ELASTICACHE_URL", "127.0.0.1");
 
-        let res_body = response.into_string().unwrap();
-        let kg_party_one_second_message: party1::KeyGenParty1Message2 =
-            serde_json::from_str(&res_body).unwrap();
+        let db = TestDb::new("KeyGenAndSign");
+        let settings = HashMap::<String, String>::from([
+            ("db".to_string(), "local".to_string()),
+            ("db_name".to_string(), db.name().to_string()),
+        ]);
+        let server = server::get_server(settings).unwrap();
+        let client = Client::tracked(server).expect("valid rocket instance");
+        let (id,master_key_2) = key_gen(&client);
+
+        let message = BigInt::from(1234u32);
 
+        let signature: party_one::SignatureRecid =
+            sign(&client, id.clone(), master_key_2, message.clone());
 
-        let key_gen_second_message = MasterKey2::key_gen_second_message(
-            &kg_party_one_first_message,
-            &kg_party_one_second_message,
+        println!(
+            "s = (r: {}, s: {}, recid: {})",
+            signature.r.to_hex(),
+            signature.s.to_hex(),
+            signature.recid
         );
-        assert!(key_gen_second_message.is_ok());
+        //test v2 sign interface with session id enabled
+    }
+
+    #[test]
+    fn key_gen_and_sign_encrypted() {
+        // Passthrough mode
+        env::set_var("region", "");
+        env::set_var("pool_id", "");
+        env::set_var("issuer", "");
+        env::set_var("audience", "");
+
+        let db = TestDb::new("KeyGenAndSignEncrypted");
+        let settings = HashMap::<String, String>::from([
+            ("db".to_string(), "local".to_string()),
+            ("db_name".to_string(), db.name().to_string()),
+            ("master_key".to_string(), format!("k1:{}", "11".repeat(32))),
+        ]);
+        let server = server::get_server(settings).unwrap();
+        let client = Client::tracked(server).expect("valid rocket instance");
+        let (id, master_key_2) = key_gen(&client);
+
+        let message = BigInt::from(1234u32);
+
+        let _signature: party_one::SignatureRecid =
+            sign(&client, id.clone(), master_key_2, message.clone());
+    }
+
+    #[test]
+    fn master_key_rotation() {
+        let plaintext = r#"{"Alpha":{"value":"1"}}"#;
+        let (table, key) = ("Party1MasterKey", idify("customer".to_string(), "id".to_string()));
+
+        let old_keys = MasterKeys::parse(&format!("k1:{}", "11".repeat(32)), None).unwrap();
+        let sealed = old_keys.seal(table, &key, plaintext).unwrap();
+        assert!(!sealed.contains(plaintext));
+        assert_eq!(old_keys.open(table, &key, &sealed).unwrap(), (plaintext.to_string(), false));
+
+        // A sealed record only opens under the table and key it was sealed for
+        let other_key = idify("other".to_string(), "id".to_string());
+        assert!(matches!(old_keys.open(table, &other_key, &sealed), Err(StorageError::Decryption)));
+        assert!(matches!(old_keys.open("RotatedMasterKey", &key, &sealed), Err(StorageError::Decryption)));
+
+        // Legacy plaintext records are refused, unless migrating, and then flagged for migration
+        assert!(matches!(old_keys.open(table, &key, plaintext), Err(StorageError::Unencrypted)));
+        let migrating_keys = MasterKeys::parse(&format!("k1:{}", "11".repeat(32)), None)
+            .unwrap()
+            .accepting_plaintext(true);
+        assert_eq!(migrating_keys.open(table, &key, plaintext).unwrap(), (plaintext.to_string(), true));
+
+        let rotated_keys = MasterKeys::parse(
+            &format!("k1:{},k2:{}", "11".repeat(32), "22".repeat(32)),
+            None,
+        )
+        .unwrap();
+        assert_eq!(rotated_keys.open(table, &key, &sealed).unwrap(), (plaintext.to_string(), true));
+        let resealed = rotated_keys.seal(table, &key, plaintext).unwrap();
+        assert!(resealed.contains(":k2:"));
+        assert_eq!(rotated_keys.open(table, &key, &resealed).unwrap(), (plaintext.to_string(), false));
+
+        // Bad keyrings are reported, never quoting the keys
+        assert!(matches!(MasterKeys::parse("k1", None), Err(MasterKeyError::Malformed)));
+        assert!(matches!(MasterKeys::parse("k1:zz", None), Err(MasterKeyError::NotHex(_))));
+        assert!(matches!(MasterKeys::parse("k1:1111", None), Err(MasterKeyError::WrongLength(_))));
+        let unknown = MasterKeys::parse(&format!("k1:{}", "11".repeat(32)), Some("k2"));
+        assert!(matches!(unknown, Err(MasterKeyError::UnknownActiveKey(_))));
+    }
 
+    /// Opens a fresh local store with `value` written raw under the Party1MasterKey record of `index`.
+    fn store_with_raw_record(db: &TestDb, index: &DbIndex, value: &[u8], master_keys: Option<MasterKeys>) -> PublicGotham {
+        let rocksdb_client = RocksDbStore::open(db.path()).unwrap();
+        let identifier = idify(index.customer_id.clone(), index.id.clone());
+        rocksdb_client
+            .put(&EcdsaStruct::Party1MasterKey.to_string(), identifier, value)
+            .unwrap();
+
+        PublicGotham::new(Config {
+            db: DB::Local(rocksdb_client),
+            master_keys,
+            share_policy: SharePolicy::default(),
+            session_ttl: SessionTtl::default(),
+            audit_log: AuditLog::open(db.audit_path()).unwrap(),
+            recovery_key: None,
+        })
+    }
 
-        let (party_two_second_message, party_two_paillier, party_two_pdl_chal) =
-            key_gen_second_message.unwrap();
+    #[tokio::test]
+    async fn corrupt_records_return_errors() {
+        let index = DbIndex {
+            customer_id: "customer".to_string(),
+            id: "corrupt".to_string(),
+        };
 
-        /*************** END: SECOND MESSAGE ***************/
+        let utf8_db = TestDb::new("CorruptUtf8");
+        let gotham = store_with_raw_record(&utf8_db, &index, &[0xff, 0xfe, 0xfd], None);
+        let read = gotham.get(&index, &EcdsaStruct::Party1MasterKey).await;
+        assert!(matches!(read, Err(DatabaseError::SerializationError(_))));
+
+        let json_db = TestDb::new("CorruptJson");
+        let gotham = store_with_raw_record(&json_db, &index, b"{\"Party1Private\": 42", None);
+        let read = gotham.get(&index, &EcdsaStruct::Party1MasterKey).await;
+        assert!(matches!(read, Err(DatabaseError::SerializationError(_))));
+
+        let master_keys = MasterKeys::parse(&format!("k1:{}", "11".repeat(32)), None).unwrap();
+        let mut sealed = master_keys
+            .seal(
+                &EcdsaStruct::Party1MasterKey.to_string(),
+                &idify(index.customer_id.clone(), index.id.clone()),
+                r#"{"Alpha":{"value":"1"}}"#,
+            )
+            .unwrap();
+        let last = sealed.pop().unwrap();
+        sealed.push(if last == '0' { '1' } else { '0' });
+        let ciphertext_db = TestDb::new("CorruptCiphertext");
+        let gotham = store_with_raw_record(&ciphertext_db, &index, sealed.as_bytes(), Some(master_keys));
+        let read = gotham.get(&index, &EcdsaStruct::Party1MasterKey).await;
+        assert!(matches!(read, Err(DatabaseError::EncryptionError(_))));
+
+        let no_master_key_db = TestDb::new("CorruptNoMasterKey");
+        let gotham = store_with_raw_record(&no_master_key_db, &index, sealed.as_bytes(), None);
+        let read = gotham.get(&index, &EcdsaStruct::Party1MasterKey).await;
+        assert!(matches!(read, Err(DatabaseError::EncryptionError(_))));
+
+        let invalid = DbIndex {
+            customer_id: "customer".to_string(),
+            id: "not_an_id".to_string(),
+        };
+        let read = gotham.get(&invalid, &EcdsaStruct::Party1MasterKey).await;
+        assert!(matches!(read, Err(DatabaseError::InvalidKeyError(_))));
 
-        /*************** START: THIRD MESSAGE ***************/
-        let body = serde_json::to_string(&party_two_second_message.pdl_first_message).unwrap();
+        let missing = DbIndex {
+            customer_id: "customer".to_string(),
+            id: "missing".to_string(),
+        };
+        assert!(gotham.get(&missing, &EcdsaStruct::Party1MasterKey).await.unwrap().is_none());
+    }
+
+    fn store_with_share_policy(db: &TestDb, share_policy: SharePolicy) -> PublicGotham {
+        PublicGotham::new(Config {
+            db: DB::Local(RocksDbStore::open(db.path()).unwrap()),
+            master_keys: None,
+            share_policy,
+            session_ttl: SessionTtl::default(),
+            audit_log: AuditLog::open(db.audit_path()).unwrap(),
+            recovery_key: None,
+        })
+    }
+
+    fn complete_keygen(gotham: &PublicGotham, customer_id: &str, id: &str) {
+        let index = DbIndex {
+            customer_id: customer_id.to_string(),
+            id: id.to_string(),
+        };
+        gotham.record_completed_keygen(&index).unwrap();
+    }
+
+    #[tokio::test]
+    async fn share_policy_reject() {
+        let db = TestDb::new("SharePolicyReject");
+        let gotham = store_with_share_policy(&db, SharePolicy::Reject);
+        assert!(!gotham.has_active_share("alice").await.unwrap());
+
+        complete_keygen(&gotham, "alice", "key1");
+        assert!(gotham.has_active_share("alice").await.unwrap());
+        assert!(!gotham.has_active_share("bob").await.unwrap());
+        assert_eq!(gotham.active_shares("alice").unwrap(), vec!["key1".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn share_policy_allow_multiple() {
+        let db = TestDb::new("SharePolicyAllowMultiple");
+        let gotham = store_with_share_policy(&db, SharePolicy::AllowMultiple);
+
+        complete_keygen(&gotham, "alice", "key1");
+        assert!(!gotham.has_active_share("alice").await.unwrap());
+        complete_keygen(&gotham, "alice", "key2");
+        assert!(!gotham.has_active_share("alice").await.unwrap());
+        assert_eq!(
+            gotham.active_shares("alice").unwrap(),
+            vec!["key1".to_string(), "key2".to_string()]
+        );
+    }
+
+    #[tokio::test]
+    async fn share_policy_replace() {
+        let db = TestDb::new("SharePolicyReplace");
+        let gotham = store_with_share_policy(&db, SharePolicy::Replace);
+
+        complete_keygen(&gotham, "alice", "key1");
+        assert!(!gotham.has_active_share("alice").await.unwrap());
+        complete_keygen(&gotham, "alice", "key2");
+        assert!(!gotham.has_active_share("alice").await.unwrap());
+        assert_eq!(gotham.active_shares("alice").unwrap(), vec!["key2".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn steps_on_different_keys_do_not_wait_on_each_other() {
+        let db = TestDb::new("KeyLocks");
+        let gotham = Arc::new(store_with_share_policy(&db, SharePolicy::AllowMultiple));
+        let locks = KeyLocks::new(gotham);
+
+        let key1 = locks.of("alice", "key1");
+        let _step = key1.lock().await;
+        assert!(locks.of("alice", "key1").try_lock().is_err());
+        assert!(locks.of("alice", "key2").try_lock().is_ok());
+        assert!(locks.of("bob", "key1").try_lock().is_ok());
+        assert!(locks.unshared().try_lock().is_ok());
+    }
+
+    const TEST_JWKS: &str = r#"{"keys":[{"kty":"oct","kid":"test","k":"c2VjcmV0LWtleS1mb3ItdGVzdHM="}]}"#;
+
+    fn auth_settings(db_name: &str) -> HashMap<String, String> {
+        let jwks_file = env::temp_dir().join("gotham_test_jwks.json");
+        std::fs::write(&jwks_file, TEST_JWKS).unwrap();
+        HashMap::<String, String>::from([
+            ("db".to_string(), "local".to_string()),
+            ("db_name".to_string(), db_name.to_string()),
+            ("issuer".to_string(), "https://issuer.test".to_string()),
+            ("audience".to_string(), "gotham".to_string()),
+            ("jwks_file".to_string(), jwks_file.to_str().unwrap().to_string()),
+            ("jwt_algorithms".to_string(), "HS256".to_string()),
+        ])
+    }
+
+    fn bearer_token(sub: &str, secret: &[u8]) -> String {
+        let mut header = jsonwebtoken::Header::new(jsonwebtoken::Algorithm::HS256);
+        header.kid = Some("test".to_string());
+        let claims = serde_json::json!({
+            "sub": sub,
+            "iss": "https://issuer.test",
+            "aud": "gotham",
+            "exp": chrono::Utc::now().timestamp() + 3600,
+        });
+        let token = jsonwebtoken::encode(&header, &claims, &jsonwebtoken::EncodingKey::from_secret(secret)).unwrap();
+        format!("Bearer {}", token)
+    }
+
+    #[test]
+    fn auth_derives_customer_id_from_token() {
+        let auth = block_on(Auth::load(&auth_settings("AuthCustomerId"))).unwrap();
+        assert!(!auth.is_passthrough());
+        assert_eq!(auth.authenticate(Some(&bearer_token("alice", b"secret-key-for-tests"))).unwrap(), "alice");
+        assert!(auth.authenticate(Some(&bearer_token("alice", b"wrong-secret"))).is_err());
+        assert!(auth.authenticate(None).is_err());
+
+        // The token header does not choose the algorithm
+        let mut rs256_only = auth_settings("AuthCustomerId");
+        rs256_only.remove("jwt_algorithms");
+        let auth = block_on(Auth::load(&rs256_only)).unwrap();
+        assert!(matches!(
+            auth.authenticate(Some(&bearer_token("alice", b"secret-key-for-tests"))),
+            Err(AuthError::DisallowedAlgorithm(_))
+        ));
+
+        let mut missing_jwks = auth_settings("AuthCustomerId");
+        missing_jwks.insert("jwks_file".to_string(), "./no_such_jwks.json".to_string());
+        assert!(matches!(block_on(Auth::load(&missing_jwks)), Err(AuthError::Jwks(_))));
+
+        let passthrough = block_on(Auth::load(&HashMap::new())).unwrap();
+        assert!(passthrough.is_passthrough());
+        assert_eq!(passthrough.authenticate(None).unwrap(), PASSTHROUGH_CUSTOMER_ID);
+        // An unverified token chooses no customer
+        let unverified = bearer_token("alice", b"wrong-secret");
+        assert_eq!(passthrough.authenticate(Some(&unverified)).unwrap(), PASSTHROUGH_CUSTOMER_ID);
+    }
+
+    #[test]
+    fn mpc_routes_require_valid_token() {
+        let db = TestDb::new("AuthRoutes");
+        let server = server::get_server(auth_settings(db.name())).unwrap();
+        let client = Client::tracked(server).expect("valid rocket instance");
 
         let response = client
-            .post(format!("/ecdsa/keygen/{}/third", id))
-            .body(body)
+            .post("/ecdsa/keygen/first")
+            .header(ContentType::JSON)
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+
+        let response = client
+            .post("/ecdsa/keygen/first")
             .header(ContentType::JSON)
+            .header(Header::new("Authorization", bearer_token("alice", b"wrong-secret")))
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+
+        let response = client
+            .post("/ecdsa/keygen/first")
+            .header(ContentType::JSON)
+            .header(Header::new("Authorization", bearer_token("alice", b"secret-key-for-tests")))
             .dispatch();
         assert_eq!(response.status(), Status::Ok);
+    }
 
-        let res_body = response.into_string().unwrap();
-        let party_one_third_message: party_one::PDLFirstMessage =
-            serde_json::from_str(&res_body).unwrap();
+    /// The in-process server, sending a customer's bearer token.
+    struct AsCustomer<'c>(&'c Client, String);
+
+    #[rocket::async_trait(?Send)]
+    impl Transport for AsCustomer<'_> {
+        async fn send(&self, method: Method, path: &str, body: Option<String>) -> Result<Reply, ClientError> {
+            let mut request = self
+                .0
+                .req(method.into(), path.to_string())
+                .header(ContentType::JSON)
+                .header(Header::new("Authorization", self.1.clone()));
+            if let Some(body) = body {
+                request = request.body(body);
+            }
+            let response = request.dispatch();
+            Ok(Reply {
+                status: response.status().code,
+                body: response.into_string().unwrap_or_default(),
+            })
+        }
+    }
 
-        let start = Instant::now();
+    #[test]
+    fn keys_belong_to_the_customer_of_the_token() {
+        let db = TestDb::new("KeyOwnership");
+        let server = server::get_server(auth_settings(db.name())).unwrap();
+        let client = Client::tracked(server).expect("valid rocket instance");
+        let alice = GothamClient::new(AsCustomer(&client, bearer_token("alice", b"secret-key-for-tests")));
+        let bob = GothamClient::new(AsCustomer(&client, bearer_token("bob", b"secret-key-for-tests")));
+
+        let (id, master_key_2) = block_on(alice.key_gen()).unwrap();
+        let page: KeyPage = block_on(alice.get("/ecdsa/keys")).unwrap();
+        assert_eq!(page.keys.iter().map(|key| key.id.as_str()).collect::<Vec<_>>(), vec![id.as_str()]);
+        let page: KeyPage = block_on(bob.get("/ecdsa/keys")).unwrap();
+        assert!(page.keys.is_empty());
+
+        let (message, x_pos, y_pos) = (BigInt::from(1234u32), BigInt::from(0u32), BigInt::from(21u32));
+        match block_on(bob.sign(&id, &master_key_2, &message, &x_pos, &y_pos)) {
+            Err(ClientError::Api { status, code, .. }) => assert_eq!((status, code.as_str()), (404, "unknown_id")),
+            other => panic!("bob signed with alice's key: {:?}", other.is_ok()),
+        }
+        let signature = block_on(alice.sign(&id, &master_key_2, &message, &x_pos, &y_pos)).unwrap();
+        let child_public_key = master_key_2.get_child(vec![x_pos, y_pos]).public.q;
+        assert!(verify_signature(&signature, &child_public_key, &message));
+    }
 
-        let pdl_decom_party2 = MasterKey2::key_gen_third_message(&party_two_pdl_chal);
+    #[test]
+    fn flat_layout_is_split_into_column_families() {
+        let db = TestDb::new("FlatLayoutMigration");
+        let path = db.path();
+        {
+            let flat = rocksdb::DB::open_default(path).unwrap();
+            flat.put("customer_key1_EcdsaParty1MasterKey", "master").unwrap();
+            flat.put("customer_key1_EcdsaEphEcKeyPair", "ephemeral").unwrap();
+        }
+
+        let store = RocksDbStore::open(path).unwrap();
+        assert_eq!(
+            store.get("EcdsaParty1MasterKey", "customer_key1".to_string()).unwrap(),
+            Some(b"master".to_vec())
+        );
+        assert_eq!(
+            store.get("EcdsaEphEcKeyPair", "customer_key1".to_string()).unwrap(),
+            Some(b"ephemeral".to_vec())
+        );
+        let mut keys = store.keys().unwrap();
+        keys.sort();
+        assert_eq!(
+            keys,
+            vec![
+                ("EcdsaEphEcKeyPair".to_string(), "customer_key1".to_string()),
+                ("EcdsaParty1MasterKey".to_string(), "customer_key1".to_string()),
+            ]
+        );
+        drop(store);
+
+        // Only the first open migrates
+        {
+            let families = rocksdb::DB::list_cf(&rocksdb::Options::default(), path).unwrap();
+            let flat = rocksdb::DB::open_cf(&rocksdb::Options::default(), path, families).unwrap();
+            flat.put("customer_key2_EcdsaParty1MasterKey", "master").unwrap();
+        }
+        let store = RocksDbStore::open(path).unwrap();
+        assert_eq!(store.get("EcdsaParty1MasterKey", "customer_key2".to_string()).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn record_keys_do_not_collide() {
+        assert_ne!(
+            idify("a_b".to_string(), "c".to_string()),
+            idify("a".to_string(), "b_c".to_string())
+        );
+        assert_eq!(encode_key(&["a_b", "c"]), "3:a_b1:c");
+
+        let db = TestDb::new("InvalidIds");
+
+        let gotham = store_with_share_policy(&db, SharePolicy::default());
+        let invalid_id = DbIndex {
+            customer_id: "customer".to_string(),
+            id: "b_c".to_string(),
+        };
+        assert!(gotham.get(&invalid_id, &EcdsaStruct::Party1MasterKey).await.is_err());
+        let invalid_customer = DbIndex {
+            customer_id: "".to_string(),
+            id: "key1".to_string(),
+        };
+        assert!(gotham.get(&invalid_customer, &EcdsaStruct::Party1MasterKey).await.is_err());
+    }
 
-        /*************** END: THIRD MESSAGE ***************/
+    #[test]
+    fn legacy_keys_are_read_and_migrated() {
+        let db = TestDb::new("LegacyKeys");
+        let path = db.path();
+        let store = RocksDbStore::open(path).unwrap();
+        store
+            .put("ShareRegistry", "alice_x".to_string(), r#"{"active":["key1"],"replaced":[]}"#)
+            .unwrap();
+        let gotham = PublicGotham::new(Config {
+            db: DB::Local(store),
+            master_keys: None,
+            share_policy: SharePolicy::default(),
+            session_ttl: SessionTtl::default(),
+            audit_log: AuditLog::open(db.audit_path()).unwrap(),
+            recovery_key: None,
+        });
+        // "alice_x" is a different customer than "alice" and must not be picked up
+        assert!(gotham.active_shares("alice").unwrap().is_empty());
+        assert_eq!(gotham.active_shares("alice_x").unwrap(), vec!["key1".to_string()]);
+        drop(gotham);
+
+        let store = RocksDbStore::open(path).unwrap();
+        assert!(store.get("ShareRegistry", "alice_x".to_string()).unwrap().is_none());
+        assert!(store.get("ShareRegistry", encode_key(&["alice_x"])).unwrap().is_some());
+    }
 
-        /*************** START: FOURTH MESSAGE ***************/
+    #[test]
+    fn expired_sessions_are_purged() {
+        let db = TestDb::new("ExpiredSessions");
+        let path = db.path();
+        {
+            let flat = rocksdb::DB::open_default(path).unwrap();
+            flat.put("customer_legacy_EcdsaEphEcKeyPair", "unstamped").unwrap();
+        }
+        let store = RocksDbStore::open(path).unwrap();
+        store.put("EcdsaEphEcKeyPair", "customer_key1".to_string(), "ephemeral").unwrap();
+        store.put("EcdsaParty1MasterKey", "customer_key1".to_string(), "master").unwrap();
+
+        let gotham = PublicGotham::new(Config {
+            db: DB::Local(store),
+            master_keys: None,
+            share_policy: SharePolicy::default(),
+            session_ttl: SessionTtl::parse(Some("60"), Some("EcdsaParty1MasterKey=1")).unwrap(),
+            audit_log: AuditLog::open(db.audit_path()).unwrap(),
+            recovery_key: None,
+        });
+        let now = unix_now();
+        // The first sweep stamps the legacy record with its own time
+        assert_eq!(gotham.purge_expired(now + 30).unwrap(), 0);
+        // The session record expires first, master keys never expire
+        assert_eq!(gotham.purge_expired(now + 80).unwrap(), 1);
+        assert_eq!(gotham.purge_expired(now + 200).unwrap(), 1);
+        drop(gotham);
+
+        let store = RocksDbStore::open(path).unwrap();
+        assert!(store.get("EcdsaEphEcKeyPair", "customer_key1".to_string()).unwrap().is_none());
+        assert_eq!(
+            store.get("EcdsaParty1MasterKey", "customer_key1".to_string()).unwrap(),
+            Some(b"master".to_vec())
+        );
 
-        let party_2_pdl_second_message = pdl_decom_party2;
-        let request = party_2_pdl_second_message;
-        let body = serde_json::to_string(&request).unwrap();
+        // Only known session tables expire, whatever else is configured
+        let ttl = SessionTtl::parse(Some("60"), Some("KeyMetadata=1,SomeNewTable=1")).unwrap();
+        assert_eq!(ttl.ttl("EcdsaEphKeyGenFirstMsg"), Some(std::time::Duration::from_secs(60)));
+        assert_eq!(ttl.ttl("KeyMetadata"), None);
+        assert_eq!(ttl.ttl("SomeNewTable"), None);
+    }
 
+    #[test]
+    fn health_endpoints() {
+        let db = TestDb::new("HealthEndpoints");
+        let settings = HashMap::<String, String>::from([
+            ("db".to_string(), "local".to_string()),
+            ("db_name".to_string(), db.name().to_string()),
+            ("master_key".to_string(), format!("k1:{}", "11".repeat(32))),
+        ]);
+        let server = server::get_server(settings).unwrap();
+        let client = Client::tracked(server).expect("valid rocket instance");
 
-        let response = client
-            .post(format!("/ecdsa/keygen/{}/fourth", id))
-            .body(body)
-            .header(ContentType::JSON)
-            .dispatch();
+        let response = client.get("/health/live").dispatch();
         assert_eq!(response.status(), Status::Ok);
+        let live: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(live["status"], "ok");
+        assert_eq!(live["version"], env!("CARGO_PKG_VERSION"));
 
+        let response = client.get("/health/ready").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let ready: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(ready["status"], "ok");
+        assert_eq!(ready["backend"], "local");
+    }
 
-        let res_body = response.into_string().unwrap();
-        let party_one_pdl_second_message: party_one::PDLSecondMessage =
-            serde_json::from_str(&res_body).unwrap();
+    #[test]
+    fn metrics_endpoint() {
+        let db = TestDb::new("MetricsEndpoint");
+        let settings = HashMap::<String, String>::from([
+            ("db".to_string(), "local".to_string()),
+            ("db_name".to_string(), db.name().to_string()),
+        ]);
+        let server = server::get_server(settings).unwrap();
+        let client = Client::tracked(server).expect("valid rocket instance");
+        let (id, master_key_2) = key_gen(&client);
+        sign(&client, id, master_key_2, BigInt::from(1234u32));
 
+        let response = client.get("/metrics").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let exported = response.into_string().unwrap();
+        for route in ["wrap_keygen_first", "wrap_keygen_fourth", "wrap_sign_first", "wrap_sign_second"] {
+            assert!(exported.contains(&format!("gotham_requests_total{{route=\"{}\",status=\"200\"}}", route)));
+        }
+        assert!(exported.contains("gotham_request_duration_seconds_bucket{route=\"wrap_sign_second\""));
+        assert!(exported.contains("gotham_db_duration_seconds_count{operation=\"insert\"}"));
+        assert!(exported.contains("gotham_keys_created_total"));
+    }
 
-        MasterKey2::key_gen_fourth_message(
-            &party_two_pdl_chal,
-            &party_one_third_message,
-            &party_one_pdl_second_message,
-        )
-            .expect("pdl error party1");
+    fn error_of(response: rocket::local::blocking::LocalResponse) -> (Status, serde_json::Value) {
+        let status = response.status();
+        let body: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        (status, body["error"].clone())
+    }
 
-        /*************** START: CHAINCODE FIRST MESSAGE ***************/
+    #[test]
+    fn errors_are_json_envelopes() {
+        let db = TestDb::new("ErrorEnvelopes");
+        let settings = HashMap::<String, String>::from([
+            ("db".to_string(), "local".to_string()),
+            ("db_name".to_string(), db.name().to_string()),
+        ]);
+        let server = server::get_server(settings).unwrap();
+        let client = Client::tracked(server).expect("valid rocket instance");
 
         let response = client
-            .post(format!("/ecdsa/keygen/{}/chaincode/first", id))
+            .get("/no/such/route")
+            .header(Header::new("X-Request-Id", "req-123"))
+            .dispatch();
+        assert_eq!(response.headers().get_one("X-Request-Id"), Some("req-123"));
+        let (status, error) = error_of(response);
+        assert_eq!(status, Status::NotFound);
+        assert_eq!(error["code"], "route_not_found");
+        assert_eq!(error["request_id"], "req-123");
+
+        let (eph_key_gen_first_message_party_two, _, _) = MasterKey2::sign_first_message();
+        let body = serde_json::to_string(&eph_key_gen_first_message_party_two).unwrap();
+        let response = client
+            .post(format!("/ecdsa/sign/{}/first", uuid::Uuid::new_v4()))
+            .body(body)
             .header(ContentType::JSON)
             .dispatch();
-        assert_eq!(response.status(), Status::Ok);
+        let request_id = response.headers().get_one("X-Request-Id").unwrap().to_string();
+        let (status, error) = error_of(response);
+        assert_eq!(status, Status::NotFound);
+        assert_eq!(error["code"], "unknown_id");
+        assert_eq!(error["request_id"], request_id.as_str());
 
+        let (id, master_key_2) = key_gen(&client);
+        let response = client
+            .post(format!("/ecdsa/sign/{}/second", id))
+            .body("{\"message\": 1}")
+            .header(ContentType::JSON)
+            .dispatch();
+        let (status, error) = error_of(response);
+        assert_eq!(status, Status::BadRequest);
+        assert_eq!(error["code"], "malformed_request");
 
-        let res_body = response.into_string().unwrap();
-        let cc_party_one_first_message: Party1FirstMessage = serde_json::from_str(&res_body).unwrap();
-
-        let (cc_party_two_first_message, cc_ec_key_pair2) =
-            ChainCode2::chain_code_first_message();
+        let (status, error) = error_of(sign_second_without_first(&client, &id, &master_key_2));
+        assert_eq!(status, Status::Conflict);
+        assert_eq!(error["code"], "step_out_of_order");
+    }
 
+    /// Sends a second signing message without a first one.
+    fn sign_second_without_first<'c>(
+        client: &'c Client,
+        id: &str,
+        master_key_2: &MasterKey2,
+    ) -> rocket::local::blocking::LocalResponse<'c> {
+        let (_, eph_comm_witness, eph_ec_key_pair_party2) = MasterKey2::sign_first_message();
+        let (sign_party_one_first_message, _) = party_one::EphKeyGenFirstMsg::create();
+        let message = BigInt::from(1234u32);
+        let request = SignSecondMsgRequest {
+            message: message.clone(),
+            party_two_sign_message: master_key_2
+                .get_child(vec![BigInt::from(0u32), BigInt::from(21u32)])
+                .sign_second_message(
+                    &eph_ec_key_pair_party2,
+                    eph_comm_witness,
+                    &sign_party_one_first_message,
+                    &message,
+                ),
+            x_pos_child_key: BigInt::from(0u32),
+            y_pos_child_key: BigInt::from(21u32),
+        };
+        client
+            .post(format!("/ecdsa/sign/{}/second", id))
+            .body(serde_json::to_string(&request).unwrap())
+            .header(ContentType::JSON)
+            .dispatch()
+    }
 
-        /*************** END: CHAINCODE FIRST MESSAGE ***************/
+    async fn failed_step(reads: Vec<Result<Option<()>, StorageError>>) -> ApiError {
+        classified(Some("id"), async {
+            for read in &reads {
+                Lookups::record_read("EcdsaEphEcKeyPair", read);
+            }
+            Err::<(), _>("Step failed".to_string())
+        })
+        .await
+        .unwrap_err()
+    }
 
-        /*************** START: CHAINCODE SECOND MESSAGE ***************/
-        let body = serde_json::to_string(&cc_party_two_first_message.d_log_proof).unwrap();
+    #[tokio::test]
+    async fn failed_steps_are_classified_by_their_lookups() {
+        let error = failed_step(vec![Ok(Some(())), Ok(Some(()))]).await;
+        assert_eq!(error.code, ErrorCode::ProofFailed);
+        assert_eq!(error.status, Status::UnprocessableEntity);
+        assert_eq!(failed_step(vec![Ok(None)]).await.code, ErrorCode::UnknownId);
+        assert_eq!(failed_step(vec![Ok(Some(())), Ok(None)]).await.code, ErrorCode::StepOutOfOrder);
+        let invalid_id = StorageError::InvalidId("b_c".to_string());
+        assert_eq!(failed_step(vec![Ok(Some(())), Err(invalid_id)]).await.code, ErrorCode::InvalidId);
+        assert_eq!(failed_step(vec![]).await.code, ErrorCode::ProtocolError);
+
+        // Lookups outside of a step go nowhere, and steps do not see each other's
+        Lookups::record_read::<()>("EcdsaEphEcKeyPair", &Ok(None));
+        assert_eq!(failed_step(vec![Ok(Some(()))]).await.code, ErrorCode::ProofFailed);
+    }
 
+    #[test]
+    fn storage_errors_do_not_quote_records() {
+        let err = serde_json::from_str::<u32>("\"0123456789abcdef\"").unwrap_err();
+        let err = StorageError::from(err).to_string();
+        assert!(!err.contains("0123456789abcdef"), "{}", err);
+    }
 
+    /// Runs both signing messages at path 0/`y_pos`, returning the status and body of the second.
+    /// The first must succeed whatever the policy, which only applies to the second.
+    fn try_sign(client: &Client, id: &str, master_key_2: &MasterKey2, y_pos: u32) -> (Status, String) {
+        let (eph_key_gen_first_message_party_two, eph_comm_witness, eph_ec_key_pair_party2) =
+            MasterKey2::sign_first_message();
         let response = client
-            .post(format!("/ecdsa/keygen/{}/chaincode/second", id))
-            .body(body)
+            .post(format!("/ecdsa/sign/{}/first", id))
+            .body(serde_json::to_string(&eph_key_gen_first_message_party_two).unwrap())
             .header(ContentType::JSON)
             .dispatch();
         assert_eq!(response.status(), Status::Ok);
+        let sign_party_one_first_message: party_one::EphKeyGenFirstMsg =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
 
-        let res_body = response.into_string().unwrap();
-        let cc_party_one_second_message: Party1SecondMessage = serde_json::from_str(&res_body).unwrap();
-
-        let _cc_party_two_second_message = ChainCode2::chain_code_second_message(
-            &cc_party_one_first_message,
-            &cc_party_one_second_message,
-        );
+        let message = BigInt::from(1234u32);
+        let (x_pos, y_pos) = (BigInt::from(0u32), BigInt::from(y_pos));
+        let request = SignSecondMsgRequest {
+            message: message.clone(),
+            party_two_sign_message: master_key_2.get_child(vec![x_pos.clone(), y_pos.clone()]).sign_second_message(
+                &eph_ec_key_pair_party2,
+                eph_comm_witness,
+                &sign_party_one_first_message,
+                &message,
+            ),
+            x_pos_child_key: x_pos,
+            y_pos_child_key: y_pos,
+        };
+        let response = client
+            .post(format!("/ecdsa/sign/{}/second", id))
+            .body(serde_json::to_string(&request).unwrap())
+            .header(ContentType::JSON)
+            .dispatch();
+        (response.status(), response.into_string().unwrap_or_default())
+    }
 
+    #[test]
+    fn signing_policy_is_enforced() {
+        let db = TestDb::new("SigningPolicy");
+        let settings = HashMap::<String, String>::from([
+            ("db".to_string(), "local".to_string()),
+            ("db_name".to_string(), db.name().to_string()),
+            ("admin_token".to_string(), "admin-secret".to_string()),
+        ]);
+        let server = server::get_server(settings).unwrap();
+        let client = Client::tracked(server).expect("valid rocket instance");
+        let (id, master_key_2) = key_gen(&client);
+        let policy_uri = format!("/admin/policies/{}", PASSTHROUGH_CUSTOMER_ID);
+        let admin = Header::new("X-Admin-Token", "admin-secret");
 
-        /*************** END: CHAINCODE SECOND MESSAGE ***************/
+        let policy = serde_json::json!({"daily_limit": 1, "allowed_paths": [{"x_pos": 0, "y_pos": 21}]});
+        let response = client
+            .put(&policy_uri)
+            .header(ContentType::JSON)
+            .body(policy.to_string())
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        let response = client
+            .put(&policy_uri)
+            .header(ContentType::JSON)
+            .header(admin.clone())
+            .body(policy.to_string())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let response = client.get(&policy_uri).header(admin.clone()).dispatch();
+        let stored: SigningPolicy = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(stored.daily_limit, Some(1));
+
+        let (status, body) = try_sign(&client, &id, &master_key_2, 22);
+        assert_eq!(status, Status::Forbidden);
+        assert!(body.contains("policy_violation") && body.contains("Derivation path"), "{}", body);
+        // Failed signatures do not count against the limit
+        let response = sign_second_without_first(&client, &id, &master_key_2);
+        assert_eq!(response.status(), Status::Conflict);
+        let (status, _) = try_sign(&client, &id, &master_key_2, 21);
+        assert_eq!(status, Status::Ok);
+        let (status, body) = try_sign(&client, &id, &master_key_2, 21);
+        assert_eq!(status, Status::Forbidden);
+        assert!(body.contains("daily limit"), "{}", body);
+
+        let policy = serde_json::json!({"frozen_keys": [id]});
+        let response = client
+            .put(&policy_uri)
+            .header(ContentType::JSON)
+            .header(admin.clone())
+            .body(policy.to_string())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let (status, body) = try_sign(&client, &id, &master_key_2, 21);
+        assert_eq!(status, Status::Forbidden);
+        assert!(body.contains("frozen"), "{}", body);
 
-        let party2_cc = ChainCode2::compute_chain_code(
-            &cc_ec_key_pair2,
-            &cc_party_one_second_message.comm_witness.public_share,
-        )
-            .chain_code;
+        let response = client.delete(&policy_uri).header(admin).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let (status, _) = try_sign(&client, &id, &master_key_2, 21);
+        assert_eq!(status, Status::Ok);
+    }
 
+    fn rotate(client: &Client, id: &str, master_key_2: &MasterKey2) -> MasterKey2 {
+        block_on(GothamClient::new(client).rotate(id, master_key_2)).expect("rotation")
+    }
 
-        /*************** END: CHAINCODE COMPUTE MESSAGE ***************/
+    #[test]
+    fn key_rotation_keeps_the_old_share_until_confirmed() {
+        let db = TestDb::new("KeyRotation");
+        let settings = HashMap::<String, String>::from([
+            ("db".to_string(), "local".to_string()),
+            ("db_name".to_string(), db.name().to_string()),
+        ]);
+        let server = server::get_server(settings).unwrap();
+        let client = Client::tracked(server).expect("valid rocket instance");
+        let (id, master_key_2) = key_gen(&client);
+        let gotham_client = GothamClient::new(&client);
 
+        match block_on(gotham_client.confirm_rotation(&id)) {
+            Err(ClientError::Api { status, .. }) => assert_eq!(status, 409),
+            other => panic!("{:?}", other),
+        }
 
-        let party_two_master_key = MasterKey2::set_master_key(
-            &party2_cc,
-            &kg_ec_key_pair_party2,
-            &kg_party_one_second_message
-                .ecdh_second_message
-                .comm_witness
-                .public_share,
-            &party_two_paillier,
-        );
+        let rotated = rotate(&client, &id, &master_key_2);
+        assert_eq!(rotated.public.q, master_key_2.public.q);
 
-        /*************** END: MASTER KEYS MESSAGE ***************/
+        // Until confirmed, the server keeps signing with the previous share
+        assert_eq!(try_sign(&client, &id, &master_key_2, 21).0, Status::Ok);
+        assert_ne!(try_sign(&client, &id, &rotated, 21).0, Status::Ok);
 
-        (id, party_two_master_key)
+        block_on(gotham_client.confirm_rotation(&id)).unwrap();
+        assert_eq!(try_sign(&client, &id, &rotated, 21).0, Status::Ok);
+        assert_ne!(try_sign(&client, &id, &master_key_2, 21).0, Status::Ok);
     }
 
-    fn sign(
-        client: &Client,
-        id: String,
-        master_key_2: MasterKey2,
-        message: BigInt,
-    ) -> party_one::SignatureRecid {
-        let (eph_key_gen_first_message_party_two, eph_comm_witness, eph_ec_key_pair_party2) =
-            MasterKey2::sign_first_message();
+    #[test]
+    fn public_key_and_chain_code_are_readable() {
+        let db = TestDb::new("PublicKeyRoutes");
+        let settings = HashMap::<String, String>::from([
+            ("db".to_string(), "local".to_string()),
+            ("db_name".to_string(), db.name().to_string()),
+        ]);
+        let server = server::get_server(settings).unwrap();
+        let client = Client::tracked(server).expect("valid rocket instance");
+        let (id, master_key_2) = key_gen(&client);
 
-        let request: party_two::EphKeyGenFirstMsg = eph_key_gen_first_message_party_two;
+        let response = client.get(format!("/ecdsa/keys/{}", id)).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let key: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(key["id"], id.as_str());
+        assert_eq!(
+            key["public_key"],
+            master_key_2.public.q.bytes_compressed_to_big_int().to_hex().as_str()
+        );
+        assert_eq!(key["chain_code"], master_key_2.chain_code.to_hex().as_str());
+        assert!(key["created_at"].is_string());
+
+        let response = client.get(format!("/ecdsa/keys/{}/child?x_pos=0&y_pos=21", id)).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let child: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        let child_key_2 = master_key_2.get_child(vec![BigInt::from(0u32), BigInt::from(21u32)]);
+        assert_eq!(
+            child["public_key"],
+            child_key_2.public.q.bytes_compressed_to_big_int().to_hex().as_str()
+        );
 
-        let body = serde_json::to_string(&request).unwrap();
+        let response = client.get(format!("/ecdsa/keys/{}", uuid::Uuid::new_v4())).dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
 
+    #[test]
+    fn customer_keys_are_listed_by_page() {
+        let db = TestDb::new("ListKeys");
+        let settings = HashMap::<String, String>::from([
+            ("db".to_string(), "local".to_string()),
+            ("db_name".to_string(), db.name().to_string()),
+        ]);
+        let server = server::get_server(settings).unwrap();
+        let client = Client::tracked(server).expect("valid rocket instance");
+        let ids: Vec<String> = (0..3).map(|_| key_gen(&client).0).collect();
 
         let response = client
-            .post(format!("/ecdsa/sign/{}/first", id))
-            .body(body)
+            .put(format!("/ecdsa/keys/{}/labels", ids[1]))
             .header(ContentType::JSON)
+            .body(r#"{"purpose": "cold storage"}"#)
             .dispatch();
         assert_eq!(response.status(), Status::Ok);
 
+        // The database may hold keys of earlier runs, walk all pages
+        let mut listed = Vec::new();
+        let mut uri = "/ecdsa/keys?limit=2".to_string();
+        loop {
+            let response = client.get(&uri).dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            let page: KeyPage = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+            assert!(page.keys.len() <= 2);
+            listed.extend(page.keys);
+            match page.next {
+                Some(next) => uri = format!("/ecdsa/keys?limit=2&after={}", next),
+                None => break,
+            }
+        }
+        let listed: Vec<&KeySummary> = listed.iter().filter(|key| ids.contains(&key.id)).collect();
+        assert_eq!(
+            listed.iter().map(|key| &key.id).collect::<Vec<_>>(),
+            ids.iter().collect::<Vec<_>>()
+        );
+        assert!(listed.iter().all(|key| key.status == KeyStatus::Active && key.created_at.is_some()));
+        assert_eq!(listed[1].labels.get("purpose").map(String::as_str), Some("cold storage"));
+        assert!(listed[0].labels.is_empty());
 
-        let res_body = response.into_string().unwrap();
-        let sign_party_one_first_message: party_one::EphKeyGenFirstMsg =
-            serde_json::from_str(&res_body).unwrap();
+        let (status, error) = error_of(client.get("/ecdsa/keys?after=no-such-key").dispatch());
+        assert_eq!(status, Status::BadRequest);
+        assert_eq!(error["code"], "bad_request");
+    }
 
-        let x_pos = BigInt::from(0u32);
-        let y_pos = BigInt::from(21u32);
+    #[test]
+    fn deactivated_keys_refuse_to_sign_and_can_be_deleted() {
+        let db = TestDb::new("KeyLifecycle");
+        let settings = HashMap::<String, String>::from([
+            ("db".to_string(), "local".to_string()),
+            ("db_name".to_string(), db.name().to_string()),
+        ]);
+        let server = server::get_server(settings).unwrap();
+        let client = Client::tracked(server).expect("valid rocket instance");
+        let (id, master_key_2) = key_gen(&client);
+        assert_eq!(try_sign(&client, &id, &master_key_2, 21).0, Status::Ok);
 
-        let child_party_two_master_key = master_key_2.get_child(vec![x_pos.clone(), y_pos.clone()]);
+        // Only deactivated keys may be deleted
+        let response = client.delete(format!("/ecdsa/keys/{}", id)).dispatch();
+        assert_eq!(error_of(response).1["code"], "step_out_of_order");
 
-        let start = Instant::now();
+        let response = client.post(format!("/ecdsa/keys/{}/deactivate", id)).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let summary: KeySummary = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(summary.status, KeyStatus::Deactivated);
+
+        let (status, body) = try_sign(&client, &id, &master_key_2, 21);
+        assert_eq!(status, Status::Forbidden);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["error"]["code"], "key_deactivated");
+        let response = client.post(format!("/ecdsa/rotate/{}/first", id)).header(ContentType::JSON).dispatch();
+        assert_eq!(error_of(response).1["code"], "key_deactivated");
+        let response = client.put(format!("/ecdsa/keys/{}/recovery", id)).header(ContentType::JSON).body("{}").dispatch();
+        assert_eq!(error_of(response).1["code"], "key_deactivated");
+
+        let response = client.delete(format!("/ecdsa/keys/{}", id)).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let response = client.get(format!("/ecdsa/keys/{}", id)).dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        let response = client.get("/ecdsa/keys?limit=500").dispatch();
+        let page: KeyPage = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert!(page.keys.iter().all(|key| key.id != id));
+    }
 
-        let party_two_sign_message = child_party_two_master_key.sign_second_message(
-            &eph_ec_key_pair_party2,
-            eph_comm_witness.clone(),
-            &sign_party_one_first_message,
-            &message,
-        );
+    #[test]
+    fn replaced_keys_refuse_to_sign_and_rotate() {
+        let db = TestDb::new("ReplacedKeys");
+        let settings = HashMap::<String, String>::from([
+            ("db".to_string(), "local".to_string()),
+            ("db_name".to_string(), db.name().to_string()),
+            ("share_policy".to_string(), "replace".to_string()),
+        ]);
+        let server = server::get_server(settings).unwrap();
+        let client = Client::tracked(server).expect("valid rocket instance");
+        let (old_id, old_master_key_2) = key_gen(&client);
+        let (new_id, new_master_key_2) = key_gen(&client);
 
+        let (status, body) = try_sign(&client, &old_id, &old_master_key_2, 21);
+        assert_eq!(status, Status::Forbidden);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["error"]["code"], "key_replaced");
+        let response = client.post(format!("/ecdsa/rotate/{}/first", old_id)).header(ContentType::JSON).dispatch();
+        assert_eq!(error_of(response).1["code"], "key_replaced");
 
+        assert_eq!(try_sign(&client, &new_id, &new_master_key_2, 21).0, Status::Ok);
+    }
 
-        let request: SignSecondMsgRequest = SignSecondMsgRequest {
-            message,
-            party_two_sign_message,
-            x_pos_child_key: x_pos,
-            y_pos_child_key: y_pos,
-        };
+    #[test]
+    fn operations_are_recorded_in_a_hash_chained_audit_log() {
+        let db = TestDb::new("AuditLog");
+        let settings = HashMap::<String, String>::from([
+            ("db".to_string(), "local".to_string()),
+            ("db_name".to_string(), db.name().to_string()),
+            ("admin_token".to_string(), "admin-secret".to_string()),
+        ]);
+        let server = server::get_server(settings).unwrap();
+        let client = Client::tracked(server).expect("valid rocket instance");
+        let (id, master_key_2) = key_gen(&client);
+        let response = sign_second_without_first(&client, &id, &master_key_2);
+        assert_eq!(response.status(), Status::Conflict);
+        let signature = sign(&client, id.clone(), master_key_2, BigInt::from(1234u32));
+
+        // The log outlives test runs, walk it to its end
+        let mut records: Vec<AuditRecord> = Vec::new();
+        loop {
+            let from = records.last().map_or(0, |record| record.seq + 1);
+            let response = client
+                .get(format!("/admin/audit?from={}&limit=100", from))
+                .header(Header::new("X-Admin-Token", "admin-secret"))
+                .dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            let export = response.into_string().unwrap();
+            let page: Vec<AuditRecord> = read_export(export.as_bytes()).map(Result::unwrap).collect();
+            if page.is_empty() {
+                break;
+            }
+            records.extend(page);
+        }
+        let summary = verify_chain(records.iter().cloned().map(Ok)).unwrap();
+        assert_eq!(summary.first_seq, Some(0));
+        assert_eq!(summary.head_hash, records.last().unwrap().hash);
+
+        let of_key: Vec<&AuditRecord> = records
+            .iter()
+            .filter(|record| record.event.id.as_deref() == Some(id.as_str()))
+            .collect();
+        let actions: Vec<&str> = of_key.iter().map(|record| record.event.action.as_str()).collect();
+        assert!(actions.starts_with(&["wrap_keygen_first", "wrap_keygen_second"]));
+        assert!(actions.contains(&"wrap_chain_code_second_message"));
+        assert!(actions.ends_with(&["sign", "sign_first", "sign"]));
+        let failed = of_key[of_key.len() - 3];
+        assert_eq!(failed.event.details["error"], "step_out_of_order");
+        assert_eq!(failed.event.details["status"], 409);
+        let signed = of_key.last().unwrap();
+        assert_eq!(signed.event.action, "sign");
+        assert_eq!(signed.event.details["message"], BigInt::from(1234u32).to_hex().as_str());
+        assert_eq!(signed.event.details["signature"]["r"], signature.r.to_hex().as_str());
+
+        let mut tampered = records.clone();
+        let last = tampered.len() - 1;
+        tampered[last].event.details["message"] = serde_json::json!("00");
+        assert!(matches!(
+            verify_chain(tampered.into_iter().map(Ok)),
+            Err(AuditError::Tampered(_))
+        ));
+        let mut truncated = records.clone();
+        truncated.remove(1);
+        assert!(matches!(
+            verify_chain(truncated.into_iter().map(Ok)),
+            Err(AuditError::Gap { .. })
+        ));
+    }
 
-        let body = serde_json::to_string(&request).unwrap();
+    #[test]
+    fn audit_events_of_the_key_store_move_to_the_audit_log() {
+        let db = TestDb::new("LegacyAuditEvents");
+        let path = db.path();
+        let store = RocksDbStore::open(path).unwrap();
+        let events = [
+            AuditEvent::new("admin", "delete_key", "alice", Some("key2"), serde_json::json!({})),
+            AuditEvent::new("alice", "deactivate_key", "alice", Some("key1"), serde_json::json!({})),
+        ];
+        for (n, event) in events.iter().enumerate() {
+            let identifier = encode_key(&[&format!("{:020}", n), &uuid::Uuid::new_v4().to_string()]);
+            store.put("AuditLog", identifier, serde_json::to_string(event).unwrap()).unwrap();
+        }
+
+        let gotham = PublicGotham::new(Config {
+            db: DB::Local(store),
+            master_keys: None,
+            share_policy: SharePolicy::default(),
+            session_ttl: SessionTtl::default(),
+            audit_log: AuditLog::open(db.audit_path()).unwrap(),
+            recovery_key: None,
+        });
+        assert_eq!(gotham.migrate_legacy_audit_events().unwrap(), 2);
+        assert_eq!(gotham.migrate_legacy_audit_events().unwrap(), 0);
+        let records = gotham.audit_records(0, 10).unwrap();
+        // Oldest first
+        assert_eq!(records.iter().map(|record| &record.event).collect::<Vec<_>>(), vec![&events[0], &events[1]]);
+        drop(gotham);
+
+        let store = RocksDbStore::open(path).unwrap();
+        assert!(store.keys().unwrap().iter().all(|(table, _)| table != "AuditLog"));
+    }
 
+    #[test]
+    fn backups_restore_into_a_working_store() {
+        let db = TestDb::new("BackupSource");
+        let backup_key = "42".repeat(32);
+        let settings = HashMap::<String, String>::from([
+            ("db".to_string(), "local".to_string()),
+            ("db_name".to_string(), db.name().to_string()),
+            ("admin_token".to_string(), "admin-secret".to_string()),
+            ("backup_key".to_string(), backup_key.clone()),
+            ("backup_dir".to_string(), db.file("archives").to_str().unwrap().to_string()),
+        ]);
+        let server = server::get_server(settings).unwrap();
+        let client = Client::tracked(server).expect("valid rocket instance");
+        let (id, master_key_2) = key_gen(&client);
 
+        let response = client.post("/admin/backups").dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
         let response = client
-            .post(format!("/ecdsa/sign/{}/second", id))
-            .body(body)
-            .header(ContentType::JSON)
+            .post("/admin/backups")
+            .header(Header::new("X-Admin-Token", "admin-secret"))
             .dispatch();
         assert_eq!(response.status(), Status::Ok);
+        let info: BackupInfo = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        let archive = std::path::Path::new(&info.archive);
+
+        let key = BackupKey::from_hex(&backup_key).unwrap();
+        let (db_dir, audit_dir) = (db.file("restored"), db.file("restored_audit"));
+        let (db_dir, audit_dir) = (db_dir.as_path(), audit_dir.as_path());
+        restore(archive, &key, db_dir, audit_dir).unwrap();
+        assert!(matches!(
+            restore(archive, &key, db_dir, audit_dir),
+            Err(BackupError::TargetExists(_))
+        ));
+        let restored = PublicGotham::new(Config {
+            db: DB::Local(RocksDbStore::open(db_dir).unwrap()),
+            master_keys: None,
+            share_policy: SharePolicy::default(),
+            session_ttl: SessionTtl::default(),
+            audit_log: AuditLog::open(audit_dir).unwrap(),
+            recovery_key: None,
+        });
+        let index = DbIndex {
+            customer_id: PASSTHROUGH_CUSTOMER_ID.to_string(),
+            id,
+        };
+        let master_key_1 = restored.party_one_master_key(&index).unwrap().unwrap();
+        assert_eq!(master_key_1.public.q, master_key_2.public.q);
+
+        let wrong_key = BackupKey::from_hex(&"24".repeat(32)).unwrap();
+        assert!(matches!(read_archive(archive, &wrong_key), Err(BackupError::Decryption)));
+        let mut tampered = std::fs::read(archive).unwrap();
+        *tampered.last_mut().unwrap() ^= 1;
+        let tampered_archive = archive.with_extension("tampered");
+        std::fs::write(&tampered_archive, tampered).unwrap();
+        assert!(matches!(read_archive(&tampered_archive, &key), Err(BackupError::Decryption)));
+        // Neither the manifest nor the end of the contents can go unnoticed
+        let mut tampered = std::fs::read(archive).unwrap();
+        tampered[30] ^= 1;
+        std::fs::write(&tampered_archive, tampered).unwrap();
+        assert!(matches!(read_archive(&tampered_archive, &key), Err(BackupError::Decryption)));
+        let mut truncated = std::fs::read(archive).unwrap();
+        truncated.truncate(truncated.len() - 1);
+        std::fs::write(&tampered_archive, truncated).unwrap();
+        assert!(matches!(read_archive(&tampered_archive, &key), Err(BackupError::Decryption)));
+    }
+
+    #[test]
+    fn backups_are_never_scheduled_back_to_back() {
+        let db = TestDb::new("BackupInterval");
+        let settings = HashMap::<String, String>::from([
+            ("db".to_string(), "local".to_string()),
+            ("db_name".to_string(), db.name().to_string()),
+            ("backup_key".to_string(), "42".repeat(32)),
+            ("backup_interval_secs".to_string(), "0".to_string()),
+        ]);
+        let refused = server::get_server(settings).unwrap_err();
+        assert_eq!(refused.to_string(), "backup_interval_secs must be a positive number of seconds");
+    }
+
+    #[test]
+    fn bad_settings_are_reported_rather_than_panicking() {
+        let settings = HashMap::from([("db".to_string(), "mongodb".to_string())]);
+        assert!(matches!(server::get_config(&settings), Err(ConfigError::UnsupportedBackend(_))));
+        let settings = HashMap::from([("db_name".to_string(), "../db".to_string())]);
+        assert!(matches!(server::get_config(&settings), Err(ConfigError::IllegalDbName)));
+        let db = TestDb::new("BadSettings");
+        let settings = HashMap::from([
+            ("db_name".to_string(), db.name().to_string()),
+            ("master_key".to_string(), "k1:1111".to_string()),
+        ]);
+        assert!(matches!(server::get_config(&settings), Err(ConfigError::MasterKeys(_))));
+        let settings = HashMap::from([
+            ("db_name".to_string(), db.name().to_string()),
+            ("recovery_public_key".to_string(), "02".to_string()),
+        ]);
+        assert!(matches!(server::get_config(&settings), Err(ConfigError::RecoveryKey(_))));
+    }
 
+    #[test]
+    fn offline_store_lists_and_verifies_records() {
+        let db = TestDb::new("OfflineStore");
+        let gotham = store_with_share_policy(&db, SharePolicy::AllowMultiple);
+        complete_keygen(&gotham, "alice", "key1");
+        complete_keygen(&gotham, "alice", "key2");
+        complete_keygen(&gotham, "bob_x", "key3");
+        drop(gotham);
+        let store = RocksDbStore::open(db.path()).unwrap();
+        store
+            .put("KeyMetadata", idify("bob_x".to_string(), "key3".to_string()), "{not json")
+            .unwrap();
+        drop(store);
+
+        assert_eq!(
+            decode_key(&idify("bob_x".to_string(), "key3".to_string())),
+            Some(vec!["bob_x".to_string(), "key3".to_string()])
+        );
+        let offline = OfflineStore::open_read_only(db.path(), None).unwrap();
+        let customers = offline.customers().unwrap();
+        assert_eq!(customers["alice"].iter().collect::<Vec<_>>(), vec!["key1", "key2"]);
+        assert_eq!(customers["bob_x"].iter().collect::<Vec<_>>(), vec!["key3"]);
+
+        let records = offline.records("alice", "key1").unwrap();
+        assert!(records.iter().any(|record| record.table == "KeyMetadata" && record.sealed_with.is_none()));
+        assert!(offline.records("alice", "key9").unwrap().is_empty());
+
+        let (checked, problems) = offline.verify(Some("alice"), None).unwrap();
+        assert!(checked >= 4 && problems.is_empty());
+        let (_, problems) = offline.verify(None, None).unwrap();
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].table, "KeyMetadata");
+        assert!(!problems[0].error.contains("not json"));
+
+        // Without a master key party one's share is stored in the clear
+        drop(offline);
+        let store = RocksDbStore::open(db.path()).unwrap();
+        store
+            .put("Party1MasterKey", idify("alice".to_string(), "key2".to_string()), "{}")
+            .unwrap();
+        drop(store);
+        let offline = OfflineStore::open_read_only(db.path(), None).unwrap();
+        match offline.export("alice", "key2", false) {
+            Err(ExportError::PlaintextSecrets(tables)) => {
+                assert!(tables.contains(&"Party1MasterKey".to_string()));
+                assert!(!tables.contains(&"KeyMetadata".to_string()));
+            }
+            other => panic!("exported plaintext secrets: {:?}", other.map(|export| export.is_some())),
+        }
+        let export = offline.export("alice", "key2", true).unwrap().unwrap();
+        assert!(export.records.contains_key("KeyMetadata"));
+        assert!(export.records.contains_key("Party1MasterKey"));
+        assert!(offline.export("alice", "key9", false).unwrap().is_none());
+    }
 
+    #[test]
+    fn wallet_files_are_encrypted_and_signatures_verify() {
+        let db = TestDb::new("Wallet");
+        let settings = HashMap::<String, String>::from([
+            ("db".to_string(), "local".to_string()),
+            ("db_name".to_string(), db.name().to_string()),
+        ]);
+        let client = Client::tracked(server::get_server(settings).unwrap()).expect("valid rocket instance");
+        let (id, master_key_2) = key_gen(&client);
+
+        let path = db.file("Wallet.wallet");
+        let path = path.as_path();
+        let wallet = Wallet::new("http://127.0.0.1:8000", id.clone(), master_key_2.clone());
+        wallet.create(path, "correct horse").unwrap();
+        assert!(matches!(wallet.create(path, "correct horse"), Err(WalletError::Exists(_))));
+        assert!(!String::from_utf8_lossy(&std::fs::read(path).unwrap()).contains(&id));
+        assert!(matches!(Wallet::open(path, "wrong horse"), Err(WalletError::Decryption)));
+        let opened = Wallet::open(path, "correct horse").unwrap();
+        assert_eq!(opened.id, id);
+        assert_eq!(opened.master_key.public.q, master_key_2.public.q);
 
-        let res_body = response.into_string().unwrap();
-        let signature_recid: party_one::SignatureRecid = serde_json::from_str(&res_body).unwrap();
+        let message = BigInt::from(1234u32);
+        let signature = sign(&client, id, opened.master_key.clone(), message.clone());
+        assert!(verify_signature(&signature, &opened.child_public_key(0, 21), &message));
+        assert!(!verify_signature(&signature, &opened.child_public_key(0, 22), &message));
+        assert!(!verify_signature(&signature, &opened.child_public_key(0, 21), &BigInt::from(1235u32)));
+        assert_eq!(public_key_hex(&opened.child_public_key(0, 21)).len(), 66);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(std::fs::metadata(path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+    }
 
-        signature_recid
+    #[tokio::test(flavor = "multi_thread")]
+    async fn wallet_keygen_and_sign_over_http() {
+        let db = TestDb::new("WalletOverHttp");
+        let settings = HashMap::<String, String>::from([
+            ("db".to_string(), "local".to_string()),
+            ("db_name".to_string(), db.name().to_string()),
+        ]);
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let server = server::get_server(settings).unwrap();
+        let figment = server.figment().clone().merge(("address", "127.0.0.1")).merge(("port", port));
+        let (launched, liftoff) = tokio::sync::oneshot::channel();
+        let server = server
+            .configure(figment)
+            .attach(rocket::fairing::AdHoc::on_liftoff("Test liftoff", |_| {
+                Box::pin(async move {
+                    let _ = launched.send(());
+                })
+            }));
+        let running = tokio::spawn(server.launch());
+        liftoff.await.expect("server launched");
+
+        let server_url = format!("http://127.0.0.1:{}", port);
+        let client = GothamClient::new(HttpTransport::new(&server_url, None));
+        let (id, master_key_2) = client.key_gen().await.unwrap();
+        let path = db.file("WalletOverHttp.wallet");
+        let path = path.as_path();
+        Wallet::new(&server_url, id, master_key_2).create(path, "correct horse").unwrap();
+
+        let wallet = Wallet::open(path, "correct horse").unwrap();
+        let message = BigInt::from(1234u32);
+        let signature = client
+            .sign(&wallet.id, &wallet.master_key, &message, &BigInt::from(0u32), &BigInt::from(21u32))
+            .await
+            .unwrap();
+        assert!(verify_signature(&signature, &wallet.child_public_key(0, 21), &message));
+        match client.sign("unknown", &wallet.master_key, &message, &BigInt::from(0u32), &BigInt::from(21u32)).await {
+            Err(ClientError::Api { status, code, .. }) => assert_eq!((status, code.as_str()), (404, "unknown_id")),
+            other => panic!("signed with an unknown key: {:?}", other.is_ok()),
+        }
+        running.abort();
     }
 
+    #[test]
+    fn escrowed_share_recovers_the_private_key() {
+        let (server_secret, server_recovery_key) = generate_recovery_key();
+        let db = TestDb::new("RecoveryEscrow");
+        let settings = HashMap::<String, String>::from([
+            ("db".to_string(), "local".to_string()),
+            ("db_name".to_string(), db.name().to_string()),
+            ("admin_token".to_string(), "admin-secret".to_string()),
+            ("recovery_public_key".to_string(), public_key_hex(&server_recovery_key)),
+        ]);
+        let client = Client::tracked(server::get_server(settings).unwrap()).expect("valid rocket instance");
+        let gotham_client = GothamClient::new(&client);
+        let (id, master_key_2) = key_gen(&client);
+
+        // Escrowed at keygen under the configured key
+        let escrow = block_on(gotham_client.recovery_escrow(&id, &master_key_2)).unwrap();
+        assert_eq!(escrow.recovery_public_key, server_recovery_key);
+        assert!(matches!(escrow.verify(&master_key_2.public.q), Err(RecoveryError::WrongShare)));
+        let x1 = escrow.decrypt(&server_secret).unwrap();
+        let private_key = recover_private_key(&x1, &master_key_2, 0, 21).unwrap();
+        let child_public_key = master_key_2.get_child(vec![BigInt::from(0u32), BigInt::from(21u32)]).public.q;
+        assert_eq!(GE::generator() * &private_key, child_public_key);
+
+        let (customer_secret, customer_recovery_key) = generate_recovery_key();
+        assert!(matches!(escrow.decrypt(&customer_secret), Err(RecoveryError::WrongRecoveryKey)));
+        let mut tampered = escrow.clone();
+        tampered.chunks.swap(0, 1);
+        assert!(matches!(tampered.verify(&master_key_2.public.p1), Err(RecoveryError::InvalidProof)));
+        // A chunk out of its range would not decrypt, it cannot be proven in range
+        let mut tampered = escrow.clone();
+        tampered.chunks[3].bits[15].c2 = tampered.chunks[3].bits[15].c2 + GE::generator();
+        assert!(matches!(tampered.verify(&master_key_2.public.p1), Err(RecoveryError::OutOfRange(3))));
+        let mut tampered = escrow.clone();
+        let out_of_range: FE = ECScalar::from(&(BigInt::from(1u32) << 16));
+        tampered.chunks[3].c2 = tampered.chunks[3].c2 + GE::generator() * &out_of_range;
+        assert!(matches!(tampered.verify(&master_key_2.public.p1), Err(RecoveryError::OutOfRange(3))));
+
+        // A customer chosen key replaces it, and is kept across rotations
+        block_on(gotham_client.escrow_share(&id, &master_key_2, &public_key_hex(&customer_recovery_key))).unwrap();
+        let rotated = rotate(&client, &id, &master_key_2);
+        block_on(gotham_client.confirm_rotation(&id)).unwrap();
+        assert_ne!(rotated.public.p1, master_key_2.public.p1);
+        let escrow = block_on(gotham_client.recovery_escrow(&id, &rotated)).unwrap();
+        assert_eq!(escrow.recovery_public_key, customer_recovery_key);
+        let x1 = escrow.decrypt(&customer_secret).unwrap();
+        assert!(recover_private_key(&x1, &rotated, 0, 21).is_ok());
+        assert!(matches!(
+            recover_private_key(&x1, &master_key_2, 0, 21),
+            Err(RecoveryError::KeyMismatch)
+        ));
+
+        match block_on(gotham_client.escrow_share(&id, &rotated, "02abcd")) {
+            Err(ClientError::Api { status, .. }) => assert_eq!(status, 400),
+            other => panic!("{:?}", other),
+        }
+
+        // Once chosen, only an administrator replaces it
+        let (_, other_recovery_key) = generate_recovery_key();
+        match block_on(gotham_client.escrow_share(&id, &rotated, &public_key_hex(&other_recovery_key))) {
+            Err(ClientError::Api { status, code, .. }) => assert_eq!((status, code.as_str()), (403, "forbidden")),
+            other => panic!("{:?}", other),
+        }
+        let escrow = block_on(gotham_client.recovery_escrow(&id, &rotated)).unwrap();
+        assert_eq!(escrow.recovery_public_key, customer_recovery_key);
+        let response = client
+            .put(format!("/admin/keys/{}/{}/recovery", PASSTHROUGH_CUSTOMER_ID, id))
+            .header(ContentType::JSON)
+            .header(Header::new("X-Admin-Token", "admin-secret"))
+            .body(serde_json::json!({ "recovery_public_key": public_key_hex(&other_recovery_key) }).to_string())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let escrow = block_on(gotham_client.recovery_escrow(&id, &rotated)).unwrap();
+        assert_eq!(escrow.recovery_public_key, other_recovery_key);
+    }
 
+    #[test]
+    fn time_windows_wrap_past_midnight() {
+        let policy: SigningPolicy = serde_json::from_value(serde_json::json!({
+            "time_windows": [{"weekdays": ["Fri"], "start": "22:00:00", "end": "02:00:00"}]
+        }))
+        .unwrap();
+        let (zero, engine) = (BigInt::from(0u32), PolicyEngine::default());
+        let at = |time: &str| SignAttempt {
+            customer_id: "alice",
+            id: "key1",
+            message: &zero,
+            x_pos: &zero,
+            y_pos: &zero,
+            now: chrono::DateTime::parse_from_rfc3339(time).unwrap().with_timezone(&chrono::Utc),
+            signed_today: 0,
+        };
+        // 2024-03-01 is a Friday
+        assert!(engine.evaluate(&policy, &at("2024-03-01T23:00:00Z")).is_ok());
+        assert!(engine.evaluate(&policy, &at("2024-03-02T01:00:00Z")).is_ok());
+        assert!(engine.evaluate(&policy, &at("2024-03-02T03:00:00Z")).is_err());
+        assert!(engine.evaluate(&policy, &at("2024-03-01T01:00:00Z")).is_err());
+    }
 
+    /// Requires a locally started redis-server, e.g. `redis-server --port 6379`.
+    /// Run with `cargo test -- --ignored`, set REDIS_URL to use a different instance.
     #[test]
-    fn key_gen_and_sign() {
+    #[ignore]
+    fn key_gen_and_sign_redis() {
         // Passthrough mode
         env::set_var("region", "");
         env::set_var("pool_id", "");
         env::set_var("issuer", "");
         env::set_var("audience", "");
-        // env::set_var("ELASTICACHE_URL", "127.0.0.1");
 
+        let redis_url = env::var("REDIS_URL").unwrap_or("redis://127.0.0.1:6379".to_string());
         let settings = HashMap::<String, String>::from([
-            ("db".to_string(), "local".to_string()),
-            ("db_name".to_string(), "KeyGenAndSign".to_string()),
+            ("db".to_string(), "redis".to_string()),
+            ("redis_url".to_string(), redis_url),
         ]);
-        let server = server::get_server(settings);
+        let server = server::get_server(settings).unwrap();
         let client = Client::tracked(server).expect("valid rocket instance");
-        let (id,master_key_2) = key_gen(&client);
+        let (id, master_key_2) = key_gen(&client);
 
         let message = BigInt::from(1234u32);
 
-        let signature: party_one::SignatureRecid =
+        let _signature: party_one::SignatureRecid =
             sign(&client, id.clone(), master_key_2, message.clone());
+    }
 
-        println!(
-            "s = (r: {}, s: {}, recid: {})",
-            signature.r.to_hex(),
-            signature.s.to_hex(),
-            signature.recid
+    #[test]
+    fn redis_keys_hash_tag_the_customer() {
+        let customer_key = redis_key("Party1MasterKey", &idify("cust{o}mer".to_string(), "id-1".to_string()));
+        assert_eq!(customer_key, "{637573747b6f7d6d6572}10:cust{o}mer4:id-1_Party1MasterKey");
+        let registry_key = redis_key("ShareRegistry", &encode_key(&["cust{o}mer"]));
+        assert_eq!(registry_key, "{637573747b6f7d6d6572}10:cust{o}mer_ShareRegistry");
+        // A closing brace in the customer id does not cut the hash tag short
+        let braced_key = redis_key("ShareRegistry", &encode_key(&["a}b"]));
+        assert!(braced_key.starts_with("{617d62}"));
+        // Legacy keys are not length prefixed and keep their untagged name
+        assert_eq!(redis_key("Party1MasterKey", "customer_id-1"), "customer_id-1_Party1MasterKey");
+
+        for (table, key) in [
+            ("Party1MasterKey", idify("cust{o}mer".to_string(), "id-1".to_string())),
+            ("ShareRegistry", encode_key(&["cust_omer}"])),
+            ("Party1MasterKey", "{customer_id-1".to_string()),
+        ] {
+            assert_eq!(
+                parse_redis_key(&redis_key(table, &key)),
+                Some((table.to_string(), key))
+            );
+        }
+    }
+
+    #[test]
+    fn redis_cluster_keys_are_scanned_on_every_primary() {
+        let nodes = "\
+07c37dfe 10.0.0.2:30004@31004 slave e7d1eecc 0 1426238317239 4 connected
+67ed2db8 10.0.0.1:30002@31002,node-2 master - 0 1426238316232 2 connected 5461-10922
+292f8b36 10.0.0.1:30003@31003 master,fail - 1426238313201 1426238312000 3 disconnected 10923-16383
+e7d1eecc 10.0.0.1:30001@31001 myself,master - 0 0 1 connected 0-5460
+";
+        let plain = ConnectionAddr::Tcp("10.0.0.1".to_string(), 30001);
+        assert_eq!(
+            cluster_primaries(nodes, &plain),
+            vec![
+                ConnectionAddr::Tcp("10.0.0.1".to_string(), 30002),
+                ConnectionAddr::Tcp("10.0.0.1".to_string(), 30001),
+            ]
         );
-        //test v2 sign interface with session id enabled
+        let tls = ConnectionAddr::TcpTls {
+            host: "10.0.0.1".to_string(),
+            port: 30001,
+            insecure: false,
+        };
+        assert!(matches!(
+            cluster_primaries(nodes, &tls).as_slice(),
+            [ConnectionAddr::TcpTls { port: 30002, .. }, ConnectionAddr::TcpTls { port: 30001, .. }]
+        ));
     }
 }
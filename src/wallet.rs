@@ -0,0 +1,161 @@
+//!Passphrase encrypted wallet files of party two, as kept by `gotham_wallet`
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::string::String;
+
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use thiserror::Error;
+
+use two_party_ecdsa::curv::elliptic::curves::traits::ECPoint;
+use two_party_ecdsa::curv::GE;
+use two_party_ecdsa::kms::ecdsa::two_party::MasterKey2;
+use two_party_ecdsa::party_one::Converter;
+use two_party_ecdsa::{party_one, BigInt};
+
+/// Layout of the wallet file. Bumped on any incompatible change.
+pub const WALLET_FORMAT_VERSION: u16 = 1;
+
+/// A wallet file is `MAGIC | format version (u16 BE) | salt | nonce | AES-256-GCM ciphertext`,
+/// everything before the nonce being authenticated as associated data. The key is derived
+/// from the passphrase and salt with PBKDF2-HMAC-SHA256.
+const MAGIC: &[u8; 8] = b"GOTHAMWL";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const PBKDF2_ROUNDS: u32 = 600_000;
+
+#[derive(Debug, Error)]
+pub enum WalletError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+    #[error("Not a wallet file")]
+    NotAWallet,
+    #[error("Unsupported wallet format version {0}")]
+    UnsupportedFormat(u16),
+    #[error("Encryption failed")]
+    Encryption,
+    #[error("Wallet failed authentication, wrong passphrase or corrupted file")]
+    Decryption,
+    #[error("Wallet {0} already exists")]
+    Exists(String),
+}
+
+/// Party two's share of a key, with the server holding party one's.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Wallet {
+    pub server_url: String,
+    pub id: String,
+    pub created_at: DateTime<Utc>,
+    pub master_key: MasterKey2,
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> Aes256Gcm {
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), salt, PBKDF2_ROUNDS, &mut key);
+    Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key))
+}
+
+impl Wallet {
+    pub fn new(server_url: &str, id: String, master_key: MasterKey2) -> Self {
+        Wallet {
+            server_url: server_url.to_string(),
+            id,
+            created_at: Utc::now(),
+            master_key,
+        }
+    }
+
+    /// Writes a new wallet file, refusing to overwrite an existing one. The file is only
+    /// readable by its owner, and synced before it is renamed into place.
+    pub fn create(&self, path: &Path, passphrase: &str) -> Result<(), WalletError> {
+        if path.exists() {
+            return Err(WalletError::Exists(path.display().to_string()));
+        }
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let header = [&MAGIC[..], &WALLET_FORMAT_VERSION.to_be_bytes(), &salt].concat();
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = derive_key(passphrase, &salt)
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: &serde_json::to_vec(self)?,
+                    aad: &header,
+                },
+            )
+            .map_err(|_| WalletError::Encryption)?;
+
+        let partial = path.with_extension("partial");
+        // Left over by an interrupted create, possibly with wider permissions
+        if partial.exists() {
+            fs::remove_file(&partial)?;
+        }
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&partial)?;
+        file.write_all(&[&header[..], &nonce, &ciphertext].concat())?;
+        file.sync_all()?;
+        fs::rename(&partial, path)?;
+        // So that the rename itself survives a crash
+        #[cfg(unix)]
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    pub fn open(path: &Path, passphrase: &str) -> Result<Self, WalletError> {
+        let raw = fs::read(path)?;
+        let header_len = MAGIC.len() + 2 + SALT_LEN;
+        if raw.len() < header_len + NONCE_LEN || &raw[..MAGIC.len()] != MAGIC {
+            return Err(WalletError::NotAWallet);
+        }
+        let format_version = u16::from_be_bytes([raw[MAGIC.len()], raw[MAGIC.len() + 1]]);
+        if format_version != WALLET_FORMAT_VERSION {
+            return Err(WalletError::UnsupportedFormat(format_version));
+        }
+        let (header, rest) = raw.split_at(header_len);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        let plaintext = derive_key(passphrase, &header[MAGIC.len() + 2..])
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: header,
+                },
+            )
+            .map_err(|_| WalletError::Decryption)?;
+        Ok(serde_json::from_slice(&plaintext)?)
+    }
+
+    pub fn child_public_key(&self, x_pos: u32, y_pos: u32) -> GE {
+        self.master_key
+            .get_child(vec![BigInt::from(x_pos), BigInt::from(y_pos)])
+            .public
+            .q
+    }
+}
+
+/// The compressed SEC1 encoding of a public key, hex encoded.
+pub fn public_key_hex(public_key: &GE) -> String {
+    format!("{:0>66}", public_key.bytes_compressed_to_big_int().to_hex())
+}
+
+/// Whether `signature` is a valid signature of `message` under `public_key`.
+pub fn verify_signature(signature: &party_one::SignatureRecid, public_key: &GE, message: &BigInt) -> bool {
+    let signature = party_one::Signature {
+        r: signature.r.clone(),
+        s: signature.s.clone(),
+    };
+    party_one::verify(&signature, public_key, message).is_ok()
+}